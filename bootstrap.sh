@@ -7,7 +7,7 @@ set -eu
 cd "$(dirname "$0")"
 
 tmux new-session \; \
-    send-keys "cargo watch -s \"clear && cargo check && cargo test && cargo run\"" C-m \; \
+    send-keys "cargo watch -s \"clear && cargo check && cargo test && cargo run -- migrate up && cargo run\"" C-m \; \
     split-window -h \; \
     send-keys "(cd src/frontend; CARGO_TARGET_DIR=../../target cargo web start)" C-m \; \
     select-pane -t 0 \; \
@@ -0,0 +1,23 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Embed the files in `migrations/` into the binary, so that deployments
+/// don't need to ship the directory. Names are parsed by `migrate::migrations`.
+fn main() {
+    let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("migrations");
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let mut files: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "sql"))
+        .collect();
+    files.sort();
+
+    let entries: String = files.iter()
+        .map(|path| format!("    ({:?}, include_str!({:?})),\n",
+                            path.file_name().unwrap().to_str().unwrap(), path))
+        .collect();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("migrations.rs");
+    fs::write(out, format!("&[\n{}]\n", entries)).unwrap();
+}
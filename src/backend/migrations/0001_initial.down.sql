@@ -0,0 +1,3 @@
+DROP TABLE message;
+DROP TABLE thread;
+DROP TABLE account;
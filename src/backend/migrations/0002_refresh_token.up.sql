@@ -0,0 +1,20 @@
+CREATE TABLE refresh_token
+(
+    id SERIAL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES account (id),
+    token_hash TEXT NOT NULL UNIQUE,
+    family TEXT NOT NULL,
+    access_jti TEXT NOT NULL,
+    access_expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX refresh_token_family_idx ON refresh_token (family);
+
+CREATE TABLE revoked_token
+(
+    jti TEXT PRIMARY KEY,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,12 @@
+DROP INDEX message_thread_id_idx;
+DROP INDEX thread_title_idx;
+DROP INDEX thread_created_at_idx;
+DROP INDEX thread_updated_at_idx;
+
+ALTER TABLE message
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at;
+
+ALTER TABLE thread
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at;
@@ -0,0 +1,17 @@
+ALTER TABLE thread
+    ADD COLUMN created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+
+ALTER TABLE message
+    ADD COLUMN created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+
+-- A thread's updated_at tracks its latest activity, so new messages bump it
+UPDATE thread t SET updated_at = m.latest
+FROM (SELECT thread_id, max(created_at) AS latest FROM message GROUP BY thread_id) m
+WHERE t.id = m.thread_id;
+
+CREATE INDEX thread_updated_at_idx ON thread (updated_at, id);
+CREATE INDEX thread_created_at_idx ON thread (created_at, id);
+CREATE INDEX thread_title_idx ON thread (title, id);
+CREATE INDEX message_thread_id_idx ON message (thread_id, id);
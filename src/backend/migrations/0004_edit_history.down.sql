@@ -0,0 +1,11 @@
+-- Edits and deletions can't be undone: the latest content stays, and soft
+-- deleted rows become visible again
+DROP TABLE message_revision;
+
+ALTER TABLE message
+    DROP COLUMN edited_at,
+    DROP COLUMN deleted_at;
+
+ALTER TABLE thread
+    DROP COLUMN edited_at,
+    DROP COLUMN deleted_at;
@@ -0,0 +1,22 @@
+ALTER TABLE thread
+    ADD COLUMN edited_at TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+ALTER TABLE message
+    ADD COLUMN edited_at TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+CREATE TABLE message_revision
+(
+    id SERIAL PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    thread_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    replaced_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    replaced_by INTEGER NOT NULL REFERENCES account (id),
+
+    FOREIGN KEY (message_id, thread_id) REFERENCES message (id, thread_id)
+);
+
+CREATE INDEX message_revision_message_idx ON message_revision (message_id, thread_id);
@@ -0,0 +1,7 @@
+ALTER TABLE thread
+    DROP COLUMN locked;
+
+ALTER TABLE account
+    DROP COLUMN role,
+    DROP COLUMN banned,
+    DROP COLUMN ban_reason;
@@ -0,0 +1,7 @@
+ALTER TABLE account
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'moderator', 'admin')),
+    ADD COLUMN banned BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN ban_reason TEXT;
+
+ALTER TABLE thread
+    ADD COLUMN locked BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+DROP TRIGGER message_notify ON message;
+DROP TRIGGER thread_notify ON thread;
+DROP FUNCTION notify_forum_event();
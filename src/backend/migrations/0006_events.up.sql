@@ -0,0 +1,33 @@
+-- Publish every change to threads and messages on the forum_events channel.
+-- The payload only identifies the row, as notifications are limited to 8000
+-- bytes; listeners load the rows themselves.
+CREATE FUNCTION notify_forum_event() RETURNS trigger AS $$
+DECLARE
+    kind TEXT;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        kind := 'created';
+    ELSIF NEW.deleted_at IS NOT NULL AND OLD.deleted_at IS NULL THEN
+        kind := 'deleted';
+    ELSIF NEW.deleted_at IS NOT NULL THEN
+        RETURN NULL;
+    ELSE
+        kind := 'updated';
+    END IF;
+
+    IF TG_TABLE_NAME = 'thread' THEN
+        PERFORM pg_notify('forum_events', json_build_object(
+            'table', 'thread', 'kind', kind, 'thread_id', NEW.id)::text);
+    ELSE
+        PERFORM pg_notify('forum_events', json_build_object(
+            'table', 'message', 'kind', kind, 'thread_id', NEW.thread_id, 'message_id', NEW.id)::text);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER thread_notify AFTER INSERT OR UPDATE ON thread
+    FOR EACH ROW EXECUTE PROCEDURE notify_forum_event();
+
+CREATE TRIGGER message_notify AFTER INSERT OR UPDATE ON message
+    FOR EACH ROW EXECUTE PROCEDURE notify_forum_event();
@@ -0,0 +1,8 @@
+DROP INDEX message_search_idx;
+DROP INDEX thread_search_idx;
+
+ALTER TABLE message
+    DROP COLUMN search;
+
+ALTER TABLE thread
+    DROP COLUMN search;
@@ -0,0 +1,8 @@
+ALTER TABLE thread
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('english', title)) STORED;
+
+ALTER TABLE message
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('english', content)) STORED;
+
+CREATE INDEX thread_search_idx ON thread USING GIN (search);
+CREATE INDEX message_search_idx ON message USING GIN (search);
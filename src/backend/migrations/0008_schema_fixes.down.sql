@@ -0,0 +1,44 @@
+DROP INDEX refresh_token_account_id_idx;
+DROP INDEX message_revision_replaced_by_idx;
+DROP INDEX message_creator_idx;
+DROP INDEX thread_creator_idx;
+
+ALTER TABLE refresh_token
+    DROP CONSTRAINT refresh_token_account_id_fkey,
+    ADD CONSTRAINT refresh_token_account_id_fkey
+        FOREIGN KEY (account_id) REFERENCES account (id);
+
+ALTER TABLE message_revision
+    DROP CONSTRAINT message_revision_message_id_thread_id_fkey,
+    DROP CONSTRAINT message_revision_replaced_by_fkey,
+    ADD CONSTRAINT message_revision_replaced_by_fkey
+        FOREIGN KEY (replaced_by) REFERENCES account (id);
+
+ALTER TABLE message
+    DROP CONSTRAINT message_thread_id_fkey,
+    DROP CONSTRAINT message_creator_fkey,
+    ADD CONSTRAINT message_thread_id_fkey
+        FOREIGN KEY (thread_id) REFERENCES thread (id),
+    ADD CONSTRAINT message_creator_fkey
+        FOREIGN KEY (creator) REFERENCES account (id);
+
+ALTER TABLE thread
+    DROP CONSTRAINT thread_creator_fkey,
+    ADD CONSTRAINT thread_creator_fkey
+        FOREIGN KEY (creator) REFERENCES account (id);
+
+ALTER TABLE message
+    DROP CONSTRAINT message_id_thread_id_key,
+    DROP CONSTRAINT message_pkey,
+    ADD PRIMARY KEY (id, thread_id);
+ALTER TABLE message_revision
+    ADD CONSTRAINT message_revision_message_id_thread_id_fkey
+        FOREIGN KEY (message_id, thread_id) REFERENCES message (id, thread_id);
+
+CREATE SEQUENCE thread_creator_seq OWNED BY thread.creator;
+CREATE SEQUENCE message_thread_id_seq OWNED BY message.thread_id;
+CREATE SEQUENCE message_creator_seq OWNED BY message.creator;
+ALTER TABLE thread ALTER COLUMN creator SET DEFAULT nextval('thread_creator_seq');
+ALTER TABLE message
+    ALTER COLUMN thread_id SET DEFAULT nextval('message_thread_id_seq'),
+    ALTER COLUMN creator SET DEFAULT nextval('message_creator_seq');
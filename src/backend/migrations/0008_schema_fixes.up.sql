@@ -0,0 +1,51 @@
+-- The initial schema declared these references SERIAL, which gave each of
+-- them a sequence and a default that inserted made up ids when omitted
+ALTER TABLE thread ALTER COLUMN creator DROP DEFAULT;
+ALTER TABLE message
+    ALTER COLUMN thread_id DROP DEFAULT,
+    ALTER COLUMN creator DROP DEFAULT;
+DROP SEQUENCE thread_creator_seq;
+DROP SEQUENCE message_thread_id_seq;
+DROP SEQUENCE message_creator_seq;
+
+-- Message ids are unique on their own. (id, thread_id) stays unique so that
+-- revisions can't point to a message in another thread.
+ALTER TABLE message_revision DROP CONSTRAINT message_revision_message_id_thread_id_fkey;
+ALTER TABLE message
+    DROP CONSTRAINT message_pkey,
+    ADD PRIMARY KEY (id),
+    ADD CONSTRAINT message_id_thread_id_key UNIQUE (id, thread_id);
+
+-- Deleting an account or a thread takes everything belonging to it along.
+-- The API only soft deletes, this is for cleaning up by hand.
+ALTER TABLE thread
+    DROP CONSTRAINT thread_creator_fkey,
+    ADD CONSTRAINT thread_creator_fkey
+        FOREIGN KEY (creator) REFERENCES account (id) ON DELETE CASCADE;
+
+ALTER TABLE message
+    DROP CONSTRAINT message_thread_id_fkey,
+    DROP CONSTRAINT message_creator_fkey,
+    ADD CONSTRAINT message_thread_id_fkey
+        FOREIGN KEY (thread_id) REFERENCES thread (id) ON DELETE CASCADE,
+    ADD CONSTRAINT message_creator_fkey
+        FOREIGN KEY (creator) REFERENCES account (id) ON DELETE CASCADE;
+
+ALTER TABLE message_revision
+    DROP CONSTRAINT message_revision_replaced_by_fkey,
+    ADD CONSTRAINT message_revision_message_id_thread_id_fkey
+        FOREIGN KEY (message_id, thread_id) REFERENCES message (id, thread_id) ON DELETE CASCADE,
+    ADD CONSTRAINT message_revision_replaced_by_fkey
+        FOREIGN KEY (replaced_by) REFERENCES account (id) ON DELETE CASCADE;
+
+ALTER TABLE refresh_token
+    DROP CONSTRAINT refresh_token_account_id_fkey,
+    ADD CONSTRAINT refresh_token_account_id_fkey
+        FOREIGN KEY (account_id) REFERENCES account (id) ON DELETE CASCADE;
+
+-- Postgres doesn't index the referencing side of foreign keys, and cascades
+-- scan it. message (thread_id, id) is covered by message_thread_id_idx.
+CREATE INDEX thread_creator_idx ON thread (creator);
+CREATE INDEX message_creator_idx ON message (creator);
+CREATE INDEX message_revision_replaced_by_idx ON message_revision (replaced_by);
+CREATE INDEX refresh_token_account_id_idx ON refresh_token (account_id);
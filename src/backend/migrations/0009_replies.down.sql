@@ -0,0 +1,6 @@
+DROP INDEX message_parent_id_idx;
+
+ALTER TABLE message
+    DROP CONSTRAINT message_parent_id_thread_id_fkey,
+    DROP COLUMN depth,
+    DROP COLUMN parent_id;
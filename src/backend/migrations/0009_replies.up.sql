@@ -0,0 +1,9 @@
+-- A reply points to the message it answers, which has to be in the same
+-- thread. depth is 0 for messages that don't answer anything.
+ALTER TABLE message
+    ADD COLUMN parent_id INTEGER,
+    ADD COLUMN depth INTEGER NOT NULL DEFAULT 0,
+    ADD CONSTRAINT message_parent_id_thread_id_fkey
+        FOREIGN KEY (parent_id, thread_id) REFERENCES message (id, thread_id) ON DELETE CASCADE;
+
+CREATE INDEX message_parent_id_idx ON message (parent_id);
@@ -0,0 +1,3 @@
+-- Message content rendered from Markdown to sanitized HTML. Messages posted
+-- before this are NULL and get rendered when they're read.
+ALTER TABLE message ADD COLUMN content_html TEXT;
@@ -0,0 +1,18 @@
+-- Files are uploaded before the message they're attached to is posted, so
+-- message_id stays NULL until then. The content itself is in the blob store
+-- under blob_key, along with the thumbnail of images.
+CREATE TABLE attachment
+(
+    id SERIAL PRIMARY KEY,
+    uploader INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    message_id INTEGER REFERENCES message (id) ON DELETE CASCADE,
+    filename TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    blob_key TEXT NOT NULL UNIQUE,
+    thumbnail_key TEXT UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX attachment_uploader_idx ON attachment (uploader);
+CREATE INDEX attachment_message_id_idx ON attachment (message_id);
@@ -0,0 +1,17 @@
+-- Shared state of the rate limiter when `rate_limits.store` is "postgres",
+-- so that every instance of the backend sees the same limits. Keys name the
+-- route and the client, like "login:ip:192.0.2.1" or "create_message:account:4".
+CREATE TABLE rate_limit_bucket
+(
+    key TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Consecutive failed logins per username, whether the account exists or not
+CREATE TABLE login_failure
+(
+    username TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
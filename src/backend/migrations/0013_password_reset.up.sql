@@ -0,0 +1,12 @@
+-- Single-use password reset links. Only the hash of the token is stored,
+-- like with refresh tokens.
+CREATE TABLE password_reset
+(
+    id SERIAL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX password_reset_account_id_idx ON password_reset (account_id);
@@ -0,0 +1,8 @@
+DROP TABLE email_verification;
+DROP INDEX account_email_key;
+-- Give renamed accounts their address back as username, lowercased. Those
+-- that shared an address with an older account keep their new name.
+UPDATE account SET username = email
+WHERE email IS NOT NULL AND username = left(split_part(email, '@', 1), 50) || '-' || id;
+ALTER TABLE account DROP COLUMN email_verified;
+ALTER TABLE account DROP COLUMN email;
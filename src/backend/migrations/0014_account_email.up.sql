@@ -0,0 +1,33 @@
+-- Usernames are display names from now on, and accounts log in with their
+-- email address, stored lowercased.
+ALTER TABLE account ADD COLUMN email TEXT;
+ALTER TABLE account ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- The login form has always asked for an email address as the username,
+-- so take those over, once per address
+UPDATE account SET email = lower(username)
+WHERE id IN (SELECT min(id) FROM account
+             WHERE username ~ '^[^@[:space:]]+@[^@[:space:]]+\.[^@[:space:]]+$'
+             GROUP BY lower(username));
+-- Usernames are shown to everyone, so those addresses must not stay in them.
+-- Rename such accounts to the local part of the address and their id, which
+-- is unique. Accounts sharing an address with an older one are renamed too:
+-- they can't log in anyway, as the address now belongs to the older one.
+UPDATE account SET username = left(split_part(username, '@', 1), 50) || '-' || id
+WHERE username ~ '^[^@[:space:]]+@[^@[:space:]]+\.[^@[:space:]]+$';
+-- Existing accounts have been posting all along
+UPDATE account SET email_verified = TRUE;
+
+CREATE UNIQUE INDEX account_email_key ON account (email);
+
+-- Single-use verification links, stored like password resets
+CREATE TABLE email_verification
+(
+    id SERIAL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX email_verification_account_id_idx ON email_verification (account_id);
@@ -0,0 +1,5 @@
+DROP TABLE mfa_challenge;
+DROP TABLE recovery_code;
+ALTER TABLE account DROP COLUMN totp_last_step;
+ALTER TABLE account DROP COLUMN totp_enabled;
+ALTER TABLE account DROP COLUMN totp_secret;
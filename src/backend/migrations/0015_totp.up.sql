@@ -0,0 +1,27 @@
+-- Optional TOTP two-factor authentication. The secret is set when
+-- enrollment starts and only counts once a code from it has been confirmed.
+ALTER TABLE account ADD COLUMN totp_secret BYTEA;
+ALTER TABLE account ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+-- Time step of the latest accepted code, so that every code works once
+ALTER TABLE account ADD COLUMN totp_last_step BIGINT;
+
+-- Single-use codes for when the authenticator is lost, stored hashed
+CREATE TABLE recovery_code
+(
+    id SERIAL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    UNIQUE (account_id, code_hash)
+);
+
+-- Logins that got the password right and still need a code
+CREATE TABLE mfa_challenge
+(
+    id SERIAL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX mfa_challenge_account_id_idx ON mfa_challenge (account_id);
@@ -0,0 +1 @@
+DROP INDEX attachment_unattached_idx;
@@ -0,0 +1,3 @@
+-- Uploads that are never attached to a message get deleted after a while,
+-- see unattached_upload_lifetime
+CREATE INDEX attachment_unattached_idx ON attachment (created_at) WHERE message_id IS NULL;
@@ -0,0 +1,324 @@
+use chrono::{Duration, Utc};
+use futures::Future;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+use hyper::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, HeaderValue, X_CONTENT_TYPE_OPTIONS};
+use hyper::{Body, HeaderMap, StatusCode};
+use multipart::server::Multipart;
+use image::ImageDecoder;
+use serde::Deserialize;
+use std::io::{Cursor, Read};
+use types::{Attachment, ErrorCode, FieldError};
+
+use crate::auth_middleware::AuthenticatedAccount;
+use crate::blob_store::{self, Blobs};
+use crate::config::Config;
+use crate::db::{DbError, NewAttachment, StoredAttachment};
+use crate::handler_utils::{HttpResult, blocking_response, json_response, read_body, respond};
+use crate::router::{self, S};
+use crate::store::Store;
+
+/// Room for the multipart boundary and part headers around the file
+const MULTIPART_OVERHEAD: usize = 16 * 1024;
+/// Thumbnails fit in a square this many pixels wide
+const THUMBNAIL_SIZE: u32 = 256;
+const MAX_FILENAME_LENGTH: usize = 255;
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct AttachmentId {
+    id: i32,
+}
+
+/// The type of `data` judging by its content, if it's one that may be
+/// uploaded. Whatever the client claims the type to be is ignored.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+    ];
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return Some(content_type);
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    // Anything else has to be text, which is never served as anything else
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.contains('\0') => Some("text/plain; charset=utf-8"),
+        _ => None,
+    }
+}
+
+/// The width and height an image declares in its header, read without
+/// decoding the image
+fn dimensions(content_type: &str, data: &[u8]) -> Option<(u64, u64)> {
+    match content_type {
+        "image/png" => Some(image::png::PNGDecoder::new(Cursor::new(data)).ok()?.dimensions()),
+        "image/jpeg" => Some(image::jpeg::JPEGDecoder::new(Cursor::new(data)).ok()?.dimensions()),
+        "image/gif" => Some(image::gif::Decoder::new(Cursor::new(data)).ok()?.dimensions()),
+        "image/webp" => webp_dimensions(data),
+        _ => None,
+    }
+}
+
+/// The WebP decoder decodes the whole frame up front, so its headers are read
+/// by hand
+fn webp_dimensions(data: &[u8]) -> Option<(u64, u64)> {
+    let chunk = data.get(12..16)?;
+    let header = data.get(20..30)?;
+    let u16_at = |i: usize| u64::from(u16::from_le_bytes([header[i], header[i + 1]]));
+    let u24_at = |i: usize| u64::from(header[i]) | u64::from(header[i + 1]) << 8 | u64::from(header[i + 2]) << 16;
+    match chunk {
+        // Lossy: a frame tag, then a start code, then 14 bit sizes
+        b"VP8 " if header[3..6] == [0x9d, 0x01, 0x2a] => Some((u16_at(6) & 0x3fff, u16_at(8) & 0x3fff)),
+        // Lossless: a signature, then 14 bit sizes minus one
+        b"VP8L" if header[0] == 0x2f => {
+            let bits = u64::from(u32::from_le_bytes([header[1], header[2], header[3], header[4]]));
+            Some(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1))
+        }
+        // Extended: flags, then 24 bit canvas sizes minus one
+        b"VP8X" => Some((u24_at(4) + 1, u24_at(7) + 1)),
+        _ => None,
+    }
+}
+
+/// A PNG preview of an image, if it can be decoded
+fn thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(data).ok()?;
+    let mut png = Vec::new();
+    image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).write_to(&mut png, image::ImageOutputFormat::PNG).ok()?;
+    Some(png)
+}
+
+/// The name of the uploaded file without any directories, which some
+/// browsers include
+fn clean_filename(filename: &str) -> String {
+    let name: String = filename.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// `Content-Disposition` with the filename both as is, for the clients that
+/// understand RFC 5987, and as ASCII for the rest
+fn content_disposition(inline: bool, filename: &str) -> String {
+    let ascii: String = filename.chars()
+        .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+        .collect();
+    let encoded: String = filename.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    let kind = if inline { "inline" } else { "attachment" };
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", kind, ascii, encoded)
+}
+
+struct Upload {
+    filename: String,
+    data: Vec<u8>,
+}
+
+fn malformed<E: std::fmt::Display>(e: E) -> HttpResult {
+    HttpResult::new(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, format!("Malformed multipart body: {}", e))
+}
+
+/// The field named `file` of a multipart/form-data body
+fn parse_upload(headers: &HeaderMap, body: &[u8]) -> Result<Upload, HttpResult> {
+    let boundary = headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .filter(|m| m.type_() == mime::MULTIPART && m.subtype() == mime::FORM_DATA)
+        .and_then(|m| m.get_param(mime::BOUNDARY).map(|boundary| boundary.as_str().to_string()))
+        .ok_or_else(|| HttpResult::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, ErrorCode::UnsupportedMediaType,
+                                       "Upload files as multipart/form-data"))?;
+    let mut multipart = Multipart::with_body(body, boundary);
+    while let Some(mut field) = multipart.read_entry().map_err(malformed)? {
+        if &*field.headers.name == "file" {
+            let filename = clean_filename(&field.headers.filename.clone().unwrap_or_default());
+            let mut data = Vec::new();
+            field.data.read_to_end(&mut data).map_err(malformed)?;
+            return Ok(Upload { filename, data });
+        }
+    }
+    Err(HttpResult::validation(vec![FieldError { field: "file".to_string(), message: "is missing".to_string() }]))
+}
+
+/// Check an upload and store it along with its thumbnail
+fn save_upload(store: &Store, blobs: &Blobs, config: &Config, uploader: i32, upload: Upload)
+    -> Result<Attachment, HttpResult> {
+    if upload.data.len() as u64 > config.max_attachment_size {
+        return Err(HttpResult::new(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge,
+                                   format!("Attachments are limited to {} bytes", config.max_attachment_size)));
+    }
+    if upload.data.is_empty() {
+        return Err(HttpResult::validation(vec![
+            FieldError { field: "file".to_string(), message: "must not be empty".to_string() }]));
+    }
+    let content_type = sniff(&upload.data).ok_or_else(|| HttpResult::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE, ErrorCode::UnsupportedMediaType,
+        "Only images (PNG, JPEG, GIF and WebP), PDFs and plain text can be attached"))?;
+    // A small file can declare a huge image, so the size is checked before
+    // decoding. Browsers would choke on those images too.
+    let dimensions = dimensions(content_type, &upload.data);
+    if let Some((width, height)) = dimensions {
+        if width.saturating_mul(height) > config.max_image_pixels {
+            return Err(HttpResult::validation(vec![FieldError {
+                field: "file".to_string(),
+                message: format!("must not have more than {} pixels", config.max_image_pixels),
+            }]));
+        }
+    }
+    let thumbnail = if dimensions.is_some() { thumbnail(&upload.data) } else { None };
+
+    let blob_key = blob_store::new_key();
+    blobs.put(&blob_key, &upload.data)?;
+    let thumbnail_key = match thumbnail {
+        Some(png) => {
+            let key = blob_store::new_key();
+            blobs.put(&key, &png)?;
+            Some(key)
+        }
+        None => None,
+    };
+    store.create_attachment(&NewAttachment {
+        uploader,
+        filename: &upload.filename,
+        content_type,
+        size: upload.data.len() as i32,
+        blob_key: &blob_key,
+        thumbnail_key: thumbnail_key.as_ref().map(|key| key.as_str()),
+    }).map_err(|e| {
+        // Nothing refers to them
+        let _ = blobs.delete(&blob_key);
+        if let Some(key) = &thumbnail_key {
+            let _ = blobs.delete(key);
+        }
+        HttpResult::from(e)
+    })
+}
+
+/// Delete the uploads that have waited too long to be attached. Uploading is
+/// the only way to add them, so it's also where they're cleaned up.
+fn delete_unattached(store: &Store, blobs: &Blobs, config: &Config) -> Result<(), DbError> {
+    let created_before = Utc::now() - Duration::seconds(config.unattached_upload_lifetime as i64);
+    for key in store.delete_unattached(created_before)? {
+        let _ = blobs.delete(&key);
+    }
+    Ok(())
+}
+
+/// `POST /attachment`: upload a file, in the `file` field of a
+/// multipart/form-data body. It stays unattached, visible only to the
+/// uploader, until a message is posted with its id in `attachment_ids`.
+pub fn upload(mut state: State, store: Store) -> Box<HandlerFuture> {
+    let s = S::borrow_from(&state).clone();
+    let limit = s.config.max_attachment_size as usize + MULTIPART_OVERHEAD;
+    let f = read_body(&mut state, limit).then(move |body| blocking_response(state, move |state| {
+        let result = body.and_then(|body| {
+            let upload = parse_upload(HeaderMap::borrow_from(&state), &body)?;
+            let account = AuthenticatedAccount::borrow_from(&state);
+            router::require_verified(&*store, &s.config, account.id)?;
+            delete_unattached(&store, &s.blobs, &s.config)?;
+            let attachment = save_upload(&store, &s.blobs, &s.config, account.id, upload)?;
+            json_response(&state, StatusCode::CREATED, &attachment)
+        });
+        respond(state, result)
+    }));
+    Box::new(f)
+}
+
+/// The attachment of the request, if the account may see it: attachments
+/// of messages are visible to everyone signed in, uploads that aren't
+/// attached yet only to the uploader
+fn visible_attachment(state: &State, store: &Store) -> Result<StoredAttachment, HttpResult> {
+    let id = AttachmentId::borrow_from(state).id;
+    let account = AuthenticatedAccount::borrow_from(state);
+    let stored = store.get_attachment(id)?;
+    if stored.message_id.is_none() && stored.uploader != account.id {
+        return Err(DbError::NotFound.into());
+    }
+    Ok(stored)
+}
+
+fn file_response(state: &State, content_type: &str, disposition: Option<String>, data: Vec<u8>)
+    -> Result<hyper::Response<Body>, HttpResult> {
+    let content_type = content_type.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let mut response = create_response(state, StatusCode::OK, content_type, data);
+    let headers = response.headers_mut();
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    // Blobs never change
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("private, max-age=31536000, immutable"));
+    if let Some(disposition) = disposition {
+        headers.insert(CONTENT_DISPOSITION, HeaderValue::from_str(&disposition)
+            .map_err(|_| HttpResult::from(StatusCode::INTERNAL_SERVER_ERROR))?);
+    }
+    Ok(response)
+}
+
+/// `GET /attachment/:id`: the file as it was uploaded. Only images are
+/// shown inline, everything else is downloaded.
+pub fn download(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let blobs = S::borrow_from(&state).blobs.clone();
+    let result = visible_attachment(&state, &store).and_then(|stored| {
+        let attachment = stored.attachment;
+        let inline = attachment.content_type.starts_with("image/");
+        let data = blobs.get(&stored.blob_key)?;
+        file_response(&state, &attachment.content_type,
+                      Some(content_disposition(inline, &attachment.filename)), data)
+    });
+    respond(state, result)
+}
+
+/// `GET /attachment/:id/thumbnail`: a PNG preview, for images only
+pub fn download_thumbnail(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let blobs = S::borrow_from(&state).blobs.clone();
+    let result = visible_attachment(&state, &store).and_then(|stored| {
+        let key = stored.thumbnail_key.ok_or(DbError::NotFound)?;
+        file_response(&state, "image/png", None, blobs.get(&key)?)
+    });
+    respond(state, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_the_allowed_types() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"%PDF-1.4"), Some("application/pdf"));
+        assert_eq!(sniff("Hyvää päivää".as_bytes()), Some("text/plain; charset=utf-8"));
+        assert_eq!(sniff(b"MZ\x90\0\x03\0\0\0"), None);
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01\0"), None);
+    }
+
+    #[test]
+    fn webp_sizes_are_read_from_the_header() {
+        let lossy = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0\x30\x01\0\x9d\x01\x2a\x80\x02\xe0\x01";
+        assert_eq!(webp_dimensions(lossy), Some((640, 480)));
+        let extended = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0\xff\xff\0\xff\xff\0";
+        assert_eq!(webp_dimensions(extended), Some((65536, 65536)));
+        assert_eq!(webp_dimensions(b"RIFF\0\0\0\0WEBPVP8 "), None);
+    }
+
+    #[test]
+    fn filenames_are_cleaned() {
+        assert_eq!(clean_filename("C:\\Users\\alice\\cat.png"), "cat.png");
+        assert_eq!(clean_filename("../../etc/passwd"), "passwd");
+        assert_eq!(clean_filename(".."), "attachment");
+        assert_eq!(content_disposition(false, "kissa \"1\".png"),
+                   "attachment; filename=\"kissa _1_.png\"; filename*=UTF-8''kissa%20%221%22.png");
+    }
+}
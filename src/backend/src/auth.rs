@@ -1,30 +1,343 @@
 use frank_jwt::{Algorithm, ValidationOptions, encode, decode};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::EcKey;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_threadpool::BlockingError;
+use uuid::Uuid;
 
-const SECRET_KEY: &str = "foobar1234";
+use crate::config::{Config, ConfigError, KeyAlgorithm};
+use crate::db::DbError;
+use crate::store::ForumStore;
 
-pub fn sign(mut payload: Value) -> Result<String, Box<dyn std::error::Error>> {
+#[derive(Debug)]
+pub enum AuthError {
+    Jwt(frank_jwt::Error),
+    Revoked,
+    /// The token is validly signed but lacks the claims we need
+    Malformed,
+    /// The token was signed with a key we don't know (anymore)
+    UnknownKey,
+    /// The revocation list couldn't be checked
+    Db(DbError),
+}
+
+impl From<frank_jwt::Error> for AuthError {
+    fn from(e: frank_jwt::Error) -> Self {
+        AuthError::Jwt(e)
+    }
+}
+
+impl From<DbError> for AuthError {
+    fn from(e: DbError) -> Self {
+        AuthError::Db(e)
+    }
+}
+
+impl From<BlockingError> for AuthError {
+    fn from(e: BlockingError) -> Self {
+        AuthError::Db(DbError::from(e))
+    }
+}
+
+#[derive(Debug)]
+struct SigningKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: String,
+}
+
+#[derive(Debug)]
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: String,
+    jwk: Option<Value>,
+}
+
+/// The keys tokens are signed and verified with. Without any `jwt_keys`
+/// configured this is the HS256 `jwt_secret`, which is neither tagged with a
+/// `kid` nor published.
+#[derive(Debug)]
+pub struct KeySet {
+    signing: SigningKey,
+    verification: Vec<VerificationKey>,
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn padded(bytes: Vec<u8>, len: usize) -> Vec<u8> {
+    let mut result = vec![0; len.saturating_sub(bytes.len())];
+    result.extend(bytes);
+    result
+}
+
+fn jwk(kid: &str, algorithm: KeyAlgorithm, pem: &str) -> Result<Value, openssl::error::ErrorStack> {
+    match algorithm {
+        KeyAlgorithm::RS256 => {
+            let rsa = Rsa::public_key_from_pem(pem.as_bytes())?;
+            Ok(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": base64url(&rsa.n().to_vec()),
+                "e": base64url(&rsa.e().to_vec()),
+            }))
+        }
+        KeyAlgorithm::ES256 => {
+            let ec = EcKey::public_key_from_pem(pem.as_bytes())?;
+            let mut ctx = BigNumContext::new()?;
+            let mut x = BigNum::new()?;
+            let mut y = BigNum::new()?;
+            ec.public_key().affine_coordinates_gfp(ec.group(), &mut x, &mut y, &mut ctx)?;
+            Ok(json!({
+                "kty": "EC",
+                "use": "sig",
+                "alg": "ES256",
+                "crv": "P-256",
+                "kid": kid,
+                "x": base64url(&padded(x.to_vec(), 32)),
+                "y": base64url(&padded(y.to_vec(), 32)),
+            }))
+        }
+    }
+}
+
+fn jwt_algorithm(algorithm: KeyAlgorithm) -> Algorithm {
+    match algorithm {
+        KeyAlgorithm::RS256 => Algorithm::RS256,
+        KeyAlgorithm::ES256 => Algorithm::ES256,
+    }
+}
+
+fn check_curve(algorithm: KeyAlgorithm, pem: &str) -> Result<(), String> {
+    if algorithm == KeyAlgorithm::ES256 {
+        let ec = EcKey::public_key_from_pem(pem.as_bytes()).map_err(|e| e.to_string())?;
+        if ec.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+            return Err("ES256 keys must use the P-256 curve".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Make sure the signing key parses and belongs to its public key, so a bad
+/// key fails at startup rather than at the first login
+fn check_key_pair(private_pem: &str, public_pem: &str) -> Result<(), String> {
+    let private_key = PKey::private_key_from_pem(private_pem.as_bytes()).map_err(|e| e.to_string())?;
+    let public_key = PKey::public_key_from_pem(public_pem.as_bytes()).map_err(|e| e.to_string())?;
+    if !private_key.public_eq(&public_key) {
+        return Err("the private key doesn't match the public key".to_string());
+    }
+    Ok(())
+}
+
+impl KeySet {
+    pub fn load(config: &Config) -> Result<KeySet, ConfigError> {
+        let signing_kid = match &config.jwt_signing_key {
+            Some(kid) => kid,
+            None => return Ok(KeySet::hmac(&config.jwt_secret)),
+        };
+
+        let read = |path: &std::path::PathBuf| fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.clone(), e));
+
+        let mut verification = Vec::new();
+        let mut signing = None;
+        for key in &config.jwt_keys {
+            let public_key = read(&key.public_key)?;
+            check_curve(key.algorithm, &public_key)
+                .map_err(|e| ConfigError::Invalid("jwt_keys", format!("key {:?}: {}", key.kid, e)))?;
+            let jwk = jwk(&key.kid, key.algorithm, &public_key)
+                .map_err(|e| ConfigError::Invalid("jwt_keys", format!("key {:?}: {}", key.kid, e)))?;
+            if &key.kid == signing_kid {
+                // Validated to be present by Config::validate
+                let private_key = read(key.private_key.as_ref().unwrap())?;
+                check_key_pair(&private_key, &public_key)
+                    .map_err(|e| ConfigError::Invalid("jwt_keys", format!("key {:?}: {}", key.kid, e)))?;
+                signing = Some(SigningKey {
+                    kid: Some(key.kid.clone()),
+                    algorithm: jwt_algorithm(key.algorithm),
+                    key: private_key,
+                });
+            }
+            verification.push(VerificationKey {
+                kid: Some(key.kid.clone()),
+                algorithm: jwt_algorithm(key.algorithm),
+                key: public_key,
+                jwk: Some(jwk),
+            });
+        }
+
+        let signing = signing.ok_or_else(|| ConfigError::Invalid(
+            "jwt_signing_key", format!("no key with kid {:?} in jwt_keys", signing_kid)))?;
+        Ok(KeySet { signing, verification })
+    }
+
+    fn hmac(secret: &str) -> KeySet {
+        KeySet {
+            signing: SigningKey { kid: None, algorithm: Algorithm::HS256, key: secret.to_string() },
+            verification: vec![VerificationKey {
+                kid: None, algorithm: Algorithm::HS256, key: secret.to_string(), jwk: None,
+            }],
+        }
+    }
+
+    /// The public keys as a JSON Web Key Set
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<&Value> = self.verification.iter().filter_map(|k| k.jwk.as_ref()).collect();
+        json!({ "keys": keys })
+    }
+}
+
+pub fn sign(config: &Config, keys: &KeySet, mut payload: Value) -> Result<String, Box<dyn std::error::Error>> {
     let body = payload.as_object_mut().unwrap();
 
     let utc = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    body.insert("exp".to_string(), From::from(utc + 60 * 60 * 1));
+    body.insert("exp".to_string(), From::from(utc + config.token_lifetime));
+
+    let header = match &keys.signing.kid {
+        Some(kid) => json!({"kid": kid}),
+        None => json!({}),
+    };
+    Ok(encode(header, &keys.signing.key, &payload, keys.signing.algorithm)?)
+}
+
+/// Read the (unverified) header of a token to find out which key it claims
+/// to be signed with.
+fn token_kid(s: &str) -> Result<Option<String>, AuthError> {
+    let header = s.split('.').next().ok_or(AuthError::Malformed)?;
+    let header = base64::decode_config(header, base64::URL_SAFE_NO_PAD).map_err(|_| AuthError::Malformed)?;
+    let header: Value = serde_json::from_slice(&header).map_err(|_| AuthError::Malformed)?;
+    Ok(header["kid"].as_str().map(|kid| kid.to_string()))
+}
+
+/// Check the signature and expiry of a token without consulting the
+/// revocation list.
+pub fn verify_signature(keys: &KeySet, s: &str) -> Result<(Value, Value), AuthError> {
+    let kid = token_kid(s)?;
+    // The algorithm comes from our key, never from the token header
+    let key = keys.verification.iter()
+        .find(|key| key.kid == kid)
+        .ok_or(AuthError::UnknownKey)?;
+    Ok(decode(s, &key.key, key.algorithm, &ValidationOptions::default())?)
+}
+
+pub fn unsign<St: ForumStore + ?Sized>(store: &St, keys: &KeySet, s: &str) -> Result<(Value, Value), AuthError> {
+    let (header, payload) = verify_signature(keys, s)?;
+    let jti = payload["jti"].as_str().ok_or(AuthError::Malformed)?;
+    if store.is_token_revoked(jti)? {
+        return Err(AuthError::Revoked);
+    }
+    Ok((header, payload))
+}
+
+pub fn new_jti() -> String {
+    Uuid::new_v4().to_string()
+}
 
-    Ok(encode(json!({}), &SECRET_KEY, &payload, Algorithm::HS256).unwrap())
+/// A random token for refresh tokens and password resets
+pub fn new_opaque_token() -> String {
+    format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple())
 }
 
-pub fn unsign(s: &str) -> Result<(Value, Value), frank_jwt::Error> {
-    decode(s, &SECRET_KEY, Algorithm::HS256, &ValidationOptions::default())
+/// Opaque tokens are only stored hashed, so a database leak doesn't hand out
+/// working sessions or password resets.
+pub fn hash_opaque_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn sign_and_unsign() -> Result<(), Box<dyn std::error::Error>> {
+        let config = crate::config::Config::default();
+        let keys = KeySet::load(&config)?;
         let obj = json!({"user": 1234});
-        let crypted = crate::auth::sign(obj)?;
-        let decoded = crate::auth::unsign(&crypted)?;
+        let crypted = crate::auth::sign(&config, &keys, obj)?;
+        let decoded = crate::auth::verify_signature(&keys, &crypted).unwrap();
         assert_eq!(decoded.1.as_object().unwrap()["user"].as_i64().unwrap(), 1234);
+        assert!(keys.jwks()["keys"].as_array().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rs256_with_kid() -> Result<(), Box<dyn std::error::Error>> {
+        let config = crate::config::Config::default();
+        let rsa = Rsa::generate(2048)?;
+        let private_key = String::from_utf8(rsa.private_key_to_pem()?)?;
+        let public_key = String::from_utf8(rsa.public_key_to_pem()?)?;
+        let keys = KeySet {
+            signing: SigningKey { kid: Some("k1".to_string()), algorithm: Algorithm::RS256, key: private_key },
+            verification: vec![VerificationKey {
+                kid: Some("k1".to_string()),
+                algorithm: Algorithm::RS256,
+                jwk: Some(jwk("k1", KeyAlgorithm::RS256, &public_key)?),
+                key: public_key,
+            }],
+        };
+
+        let crypted = crate::auth::sign(&config, &keys, json!({"user": 1234}))?;
+        assert_eq!(token_kid(&crypted).unwrap(), Some("k1".to_string()));
+        assert!(crate::auth::verify_signature(&keys, &crypted).is_ok());
+
+        let hmac = KeySet::hmac(&config.jwt_secret);
+        assert!(crate::auth::verify_signature(&hmac, &crypted).is_err());
+
+        let jwks = keys.jwks();
+        assert_eq!(jwks["keys"][0]["kid"], "k1");
+        assert_eq!(jwks["keys"][0]["kty"], "RSA");
+        assert_eq!(jwks["keys"][0]["e"], "AQAB");
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_private_keys_fail_to_load() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("fstack-keys-{}", new_jti()));
+        fs::create_dir_all(&dir)?;
+        let rsa = Rsa::generate(2048)?;
+        let other = Rsa::generate(2048)?;
+        fs::write(dir.join("public.pem"), rsa.public_key_to_pem()?)?;
+        fs::write(dir.join("private.pem"), rsa.private_key_to_pem()?)?;
+        fs::write(dir.join("other.pem"), other.private_key_to_pem()?)?;
+        fs::write(dir.join("garbage.pem"), "not a key")?;
+
+        let mut config = crate::config::Config::default();
+        config.jwt_signing_key = Some("k1".to_string());
+        let with_private_key = |config: &mut crate::config::Config, name: &str| {
+            config.jwt_keys = vec![crate::config::JwtKey {
+                kid: "k1".to_string(),
+                algorithm: KeyAlgorithm::RS256,
+                public_key: dir.join("public.pem"),
+                private_key: Some(dir.join(name)),
+            }];
+        };
+
+        with_private_key(&mut config, "private.pem");
+        let keys = KeySet::load(&config)?;
+        assert!(crate::auth::sign(&config, &keys, json!({"user": 1234})).is_ok());
+        with_private_key(&mut config, "other.pem");
+        assert!(KeySet::load(&config).is_err());
+        with_private_key(&mut config, "garbage.pem");
+        assert!(KeySet::load(&config).is_err());
+
+        fs::remove_dir_all(&dir)?;
         Ok(())
     }
+
+    #[test]
+    fn refresh_token_hash_is_stable() {
+        let token = crate::auth::new_opaque_token();
+        assert_eq!(crate::auth::hash_opaque_token(&token), crate::auth::hash_opaque_token(&token));
+        assert_ne!(crate::auth::hash_opaque_token(&token), token);
+    }
 }
@@ -0,0 +1,178 @@
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{future, Future};
+use gotham::handler::HandlerFuture;
+use gotham::middleware::Middleware;
+use gotham::state::{FromState, State};
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{HeaderMap, StatusCode};
+use std::convert::TryInto;
+use types::{ApiError, ErrorCode, Role};
+
+use crate::auth::{self, AuthError};
+use crate::db::blocking;
+use crate::handler_utils::{HttpResult, error_response};
+use crate::router::S;
+
+/// The account the request was made with, put into `State` by
+/// `AuthMiddleware` when a valid token was presented.
+#[derive(Clone, Debug, StateData)]
+pub struct AuthenticatedAccount {
+    pub id: i32,
+    pub role: Role,
+    pub jti: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl AuthenticatedAccount {
+    /// Whether the account has `role` or a more privileged one
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+
+    pub fn require_role(&self, role: Role) -> Result<(), HttpResult> {
+        if self.has_role(role) {
+            Ok(())
+        } else {
+            Err(HttpResult::from(StatusCode::FORBIDDEN))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthPolicy {
+    /// Reject requests without a valid token
+    Required,
+    /// Authenticate the request if a token was sent
+    Optional,
+    /// Like `Required`, but the account must also have at least this role
+    Role(Role),
+}
+
+#[derive(Clone, NewMiddleware)]
+pub struct AuthMiddleware {
+    policy: AuthPolicy,
+}
+
+impl AuthMiddleware {
+    pub fn new(policy: AuthPolicy) -> Self {
+        AuthMiddleware { policy }
+    }
+}
+
+/// Read the token from `Authorization: Bearer <token>`, falling back to the
+/// legacy `token` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(AUTHORIZATION) {
+        let value = value.to_str().ok()?.trim();
+        let (scheme, token) = value.split_at(value.find(' ')?);
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return Some(token.trim());
+        }
+        return None;
+    }
+    headers.get("token")?.to_str().ok()
+}
+
+/// Verify `token`, checking the revocation list off the event loop
+pub fn authenticate(state: &State, token: String) -> impl Future<Item = AuthenticatedAccount, Error = AuthError> {
+    let s = S::borrow_from(state);
+    let (store, keys) = (s.store.clone(), s.keys.clone());
+    blocking(move || account_from_claims(auth::unsign(&*store, &keys, &token)?.1))
+}
+
+fn account_from_claims(claims: serde_json::Value) -> Result<AuthenticatedAccount, AuthError> {
+    let id = claims["sub"].as_i64().and_then(|id| id.try_into().ok());
+    let jti = claims["jti"].as_str();
+    let exp = claims["exp"].as_i64();
+    match (id, jti, exp) {
+        (Some(id), Some(jti), Some(exp)) => Ok(AuthenticatedAccount {
+            id,
+            role: claims["role"].as_str().and_then(Role::parse).unwrap_or(Role::User),
+            jti: jti.to_string(),
+            expires_at: Utc.timestamp(exp, 0),
+        }),
+        _ => Err(AuthError::Malformed),
+    }
+}
+
+fn reject(state: State, status: StatusCode, error: Option<&str>) -> Box<HandlerFuture> {
+    let api_error = match (status, error) {
+        (StatusCode::FORBIDDEN, _) =>
+            ApiError::new(ErrorCode::Forbidden, "You are not allowed to do that"),
+        (_, Some(_)) =>
+            ApiError::new(ErrorCode::Unauthorized, "Invalid or expired token"),
+        (_, None) =>
+            ApiError::new(ErrorCode::Unauthorized, "Authentication required"),
+    };
+    let mut response = error_response(&state, status, api_error);
+    if status == StatusCode::UNAUTHORIZED {
+        let challenge = match error {
+            Some(error) => format!("Bearer realm=\"fstack\", error=\"{}\"", error),
+            None => "Bearer realm=\"fstack\"".to_string(),
+        };
+        response.headers_mut().insert(WWW_AUTHENTICATE, challenge.parse().unwrap());
+    }
+    Box::new(future::ok((state, response)))
+}
+
+impl Middleware for AuthMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
+    where Chain: FnOnce(State) -> Box<HandlerFuture> + Send + 'static {
+        let token = match bearer_token(HeaderMap::borrow_from(&state)) {
+            Some(token) => token.to_string(),
+            None if self.policy == AuthPolicy::Optional => return chain(state),
+            None => return reject(state, StatusCode::UNAUTHORIZED, None),
+        };
+
+        let policy = self.policy;
+        let authenticated = authenticate(&state, token).then(move |account| -> Box<HandlerFuture> {
+            let account = match account {
+                Ok(account) => account,
+                Err(AuthError::Db(e)) => {
+                    let response = HttpResult::from(e).into_response(&state);
+                    return Box::new(future::ok((state, response)));
+                }
+                Err(_) => return reject(state, StatusCode::UNAUTHORIZED, Some("invalid_token")),
+            };
+
+            if let AuthPolicy::Role(role) = policy {
+                if !account.has_role(role) {
+                    return reject(state, StatusCode::FORBIDDEN, None);
+                }
+            }
+
+            state.put(account);
+            chain(state)
+        });
+        Box::new(authenticated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_include_lesser_roles() {
+        let account = |role| AuthenticatedAccount { id: 1, role, jti: "jti".to_string(), expires_at: Utc::now() };
+        assert!(account(Role::Admin).has_role(Role::Moderator));
+        assert!(account(Role::Moderator).has_role(Role::User));
+        assert!(!account(Role::Moderator).has_role(Role::Admin));
+        assert!(account(Role::User).require_role(Role::Moderator).is_err());
+    }
+
+    #[test]
+    fn reads_bearer_and_legacy_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        headers.insert("token", "legacy".parse().unwrap());
+        assert_eq!(bearer_token(&headers), Some("legacy"));
+
+        headers.insert(AUTHORIZATION, "Bearer abc.def".parse().unwrap());
+        assert_eq!(bearer_token(&headers), Some("abc.def"));
+
+        headers.insert(AUTHORIZATION, "Basic Zm9vOmJhcg==".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+    }
+}
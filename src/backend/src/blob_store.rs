@@ -0,0 +1,109 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Where the content of attachments is kept. Keys are generated by the
+/// caller with `new_key` and never reused.
+pub trait BlobStore: Send + Sync + fmt::Debug {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    /// `io::ErrorKind::NotFound` if there's nothing under `key`
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+pub type Blobs = Arc<dyn BlobStore>;
+
+pub fn new_key() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+/// Blobs as files in a directory
+#[derive(Debug)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    /// Use `root`, creating it if it doesn't exist
+    pub fn new(root: &Path) -> io::Result<LocalBlobStore> {
+        fs::create_dir_all(root)?;
+        Ok(LocalBlobStore { root: root.to_path_buf() })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        // Keys come from `new_key`, this only keeps them from escaping `root`
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid blob key {:?}", key)));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        // Written next to the final file and renamed, so readers never see
+        // a partial blob
+        let partial = path.with_extension("partial");
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&partial, &path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Blobs in memory, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryBlobStore {
+    blobs: std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl BlobStore for MemoryBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.blobs.lock().unwrap().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.blobs.lock().unwrap().get(key).cloned().ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.blobs.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_store_round_trips() {
+        let root = std::env::temp_dir().join(format!("fstack-blobs-{}", new_key()));
+        let store = LocalBlobStore::new(&root).unwrap();
+        let key = new_key();
+        store.put(&key, b"hello").unwrap();
+        assert_eq!(store.get(&key).unwrap(), b"hello");
+        store.delete(&key).unwrap();
+        assert_eq!(store.get(&key).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(store.get("../etc/passwd").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,99 +1,1042 @@
+use chrono::{DateTime, SecondsFormat, Utc};
 use postgres::GenericConnection;
-use types::{Account, Thread, Message};
+use postgres::rows::Row;
+use postgres::types::ToSql;
+use types::{Account, Attachment, Message, MessageRevision, Page, Role, SearchResult, SnippetPart, Thread, ThreadSort};
 
-pub use crate::db_traits::{DBConnectionPool, Connection, Transaction};
-use crate::db_traits::{IntoGenericConnection as IGC, get_db_connection};
+pub use crate::db_traits::{Connection, Database, DbError, blocking, get_db_connection};
+use crate::db_traits::IntoGenericConnection as IGC;
+use crate::markdown;
 
-lazy_static! {
-    pub(crate) static ref DB_CONNECTION: DBConnectionPool = get_db_connection().unwrap();
+/// Create an account with an unverified, already normalized, `email`
+pub fn create_account<T: IGC>(db: T, username: &str, email: &str, password: &str) -> Result<i32, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("INSERT INTO account (username, email, password, last_logged_in) \
+               VALUES ($1, $2, $3, $4) \
+               RETURNING id", &[&username, &email, &password, &chrono::Utc::now()])?
+        .into_iter()
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)
+}
+
+pub struct Credentials {
+    pub id: i32,
+    pub username: String,
+    pub password: String,
+    pub banned: bool,
+    /// Accounts from before addresses were asked for may not have one
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// Whether logins need a TOTP code after the password
+    pub totp_enabled: bool,
+}
+
+const CREDENTIALS_COLUMNS: &str = "id, username, password, banned, email, email_verified, totp_enabled";
+
+fn credentials(row: &Row) -> Credentials {
+    Credentials {
+        id: row.get(0),
+        username: row.get(1),
+        password: row.get(2),
+        banned: row.get(3),
+        email: row.get(4),
+        email_verified: row.get(5),
+        totp_enabled: row.get(6),
+    }
 }
 
-pub fn create_account<T: IGC>(db: T, username: &str, password: &str) -> Option<i32> {
+pub fn get_credentials<T: IGC>(db: T, username: &str) -> Result<Credentials, DbError> {
     let conn = db.into_generic_connection();
-    conn.query("INSERT INTO account (username, password, last_logged_in) \
-               VALUES ($1, $2, $3) \
-               RETURNING id", &[&username, &password, &chrono::Utc::now()]).ok()?
+    conn.query(&format!("SELECT {} FROM account WHERE username=$1", CREDENTIALS_COLUMNS), &[&username])?
         .into_iter()
         .next()
-        .map(|row| row.get(0))
+        .map(|row| credentials(&row))
+        .ok_or(DbError::NotFound)
 }
 
-pub fn get_password<T: IGC>(db: T, username: &str) -> Option<(i32, String)> {
+/// Credentials of the account with the normalized address `email`
+pub fn get_credentials_by_email<T: IGC>(db: T, email: &str) -> Result<Credentials, DbError> {
     let conn = db.into_generic_connection();
-    conn.query("SELECT id, password FROM account WHERE username=$1",
-               &[&username]).unwrap()
+    conn.query(&format!("SELECT {} FROM account WHERE email=$1", CREDENTIALS_COLUMNS), &[&email])?
         .into_iter()
         .next()
-        .map(|row| (row.get(0), row.get(1)))
+        .map(|row| credentials(&row))
+        .ok_or(DbError::NotFound)
 }
 
-pub fn update_last_logged_in<T: IGC>(db: T, username: &str) {
+pub fn update_last_logged_in<T: IGC>(db: T, username: &str) -> Result<(), DbError> {
     let conn = db.into_generic_connection();
     conn.query("UPDATE account SET last_logged_in=$2 WHERE username=$1",
-               &[&username, &chrono::Utc::now()]).unwrap();
+               &[&username, &chrono::Utc::now()])?;
+    Ok(())
 }
 
-pub fn get_account<T: IGC>(db: T, id: i32) -> Option<Account> {
+pub fn get_account<T: IGC>(db: T, id: i32) -> Result<Account, DbError> {
     let conn = db.into_generic_connection();
-    conn.query("SELECT id, username FROM account WHERE id=$1", &[&id]).unwrap()
+    conn.query("SELECT id, username, role, banned FROM account WHERE id=$1", &[&id])?
         .into_iter()
-        .map(|row| Account { id: row.get(0), username: row.get(1) })
+        .map(|row| Account {
+            id: row.get(0),
+            username: row.get(1),
+            role: role_from_column(row.get(2)),
+            banned: row.get(3),
+        })
         .next()
+        .ok_or(DbError::NotFound)
+}
+
+/// The role column is constrained to valid roles, but fall back to the least
+/// privileged one just in case
+fn role_from_column(role: String) -> Role {
+    Role::parse(&role).unwrap_or(Role::User)
 }
 
-pub fn create_thread<T: IGC>(db: T, account_id: i32, title: &str) {
+pub fn get_role<T: IGC>(db: T, id: i32) -> Result<Role, DbError> {
     let conn = db.into_generic_connection();
-    conn.query("INSERT INTO thread (title, creator) VALUES ($1, $2)", &[&title, &account_id]).unwrap();
+    conn.query("SELECT role FROM account WHERE id=$1", &[&id])?
+        .into_iter()
+        .next()
+        .map(|row| role_from_column(row.get(0)))
+        .ok_or(DbError::NotFound)
 }
 
-pub fn get_threads<T: IGC>(db: T) -> Vec<Thread> {
+pub fn set_role<T: IGC>(db: T, id: i32, role: Role) -> Result<(), DbError> {
     let conn = db.into_generic_connection();
-    conn.query("SELECT t.id, a.username, t.title \
-               FROM thread t \
-               LEFT JOIN account a ON t.creator = a.id", &[])
-        .unwrap()
+    found(conn.execute("UPDATE account SET role=$2 WHERE id=$1", &[&id, &role.as_str()])?)
+}
+
+pub fn set_banned<T: IGC>(db: T, id: i32, banned: bool, reason: Option<&str>) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE account SET banned=$2, ban_reason=$3 WHERE id=$1",
+                       &[&id, &banned, &reason])?)
+}
+
+pub fn set_password<T: IGC>(db: T, id: i32, password: &str) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE account SET password=$2 WHERE id=$1", &[&id, &password])?)
+}
+
+pub fn create_password_reset<T: IGC>(db: T, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+    -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.execute("INSERT INTO password_reset (account_id, token_hash, expires_at) VALUES ($1, $2, $3)",
+                 &[&account_id, &token_hash, &expires_at])?;
+    Ok(())
+}
+
+/// Use up the password reset with `token_hash`, along with every other one
+/// of its account, and return the account
+pub fn use_password_reset<T: IGC>(db: T, token_hash: &str) -> Result<i32, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("DELETE FROM password_reset WHERE account_id = \
+                (SELECT account_id FROM password_reset WHERE token_hash=$1 AND expires_at > $2) \
+                RETURNING account_id", &[&token_hash, &Utc::now()])?
         .into_iter()
-        .map(|row| Thread {
-            id: row.get(0),
-            creator: row.get(1),
-            title: row.get(2),
-            messages: None,
-            latest_message: None,
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)
+}
+
+#[derive(Clone, Debug)]
+pub struct AccountEmail {
+    pub email: Option<String>,
+    pub verified: bool,
+}
+
+pub fn get_email<T: IGC>(db: T, id: i32) -> Result<AccountEmail, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("SELECT email, email_verified FROM account WHERE id=$1", &[&id])?
+        .into_iter()
+        .next()
+        .map(|row| AccountEmail { email: row.get(0), verified: row.get(1) })
+        .ok_or(DbError::NotFound)
+}
+
+pub fn create_email_verification<T: IGC>(db: T, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+    -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.execute("INSERT INTO email_verification (account_id, token_hash, expires_at) VALUES ($1, $2, $3)",
+                 &[&account_id, &token_hash, &expires_at])?;
+    Ok(())
+}
+
+/// Use up the verification with `token_hash`, along with every other one
+/// of its account, mark the address of the account verified and return it
+pub fn verify_email<T: IGC>(db: T, token_hash: &str) -> Result<i32, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("WITH used AS (DELETE FROM email_verification WHERE account_id = \
+                    (SELECT account_id FROM email_verification WHERE token_hash=$1 AND expires_at > $2) \
+                    RETURNING account_id) \
+                UPDATE account SET email_verified=TRUE WHERE id IN (SELECT account_id FROM used) \
+                RETURNING id", &[&token_hash, &Utc::now()])?
+        .into_iter()
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Totp {
+    /// Set once enrollment starts
+    pub secret: Option<Vec<u8>>,
+    /// Set once a code of the secret has been confirmed
+    pub enabled: bool,
+    pub last_step: Option<i64>,
+    pub recovery_codes_left: i64,
+}
+
+pub fn get_totp<T: IGC>(db: T, account_id: i32) -> Result<Totp, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("SELECT totp_secret, totp_enabled, totp_last_step, \
+                (SELECT count(*) FROM recovery_code WHERE account_id=account.id) \
+                FROM account WHERE id=$1", &[&account_id])?
+        .into_iter()
+        .next()
+        .map(|row| Totp {
+            secret: row.get(0),
+            enabled: row.get(1),
+            last_step: row.get(2),
+            recovery_codes_left: row.get(3),
         })
-        .collect()
-}
-
-pub fn get_thread<T: IGC>(db: T, id: i32) -> Option<Thread> {
-    let conn = db.into_generic_connection();
-    let result = conn.query(
-        "SELECT t.id, a1.username, title, m.id, a2.username, m.content \
-         FROM thread t \
-         LEFT JOIN message m ON m.thread_id = t.id \
-         LEFT JOIN account a1 ON t.creator = a1.id \
-         LEFT JOIN account a2 ON m.creator = a2.id \
-         WHERE t.id=$1", &[&id])
-        .unwrap();
-
-    let thread_row = result.iter().next()?;
-
-    Some(Thread {
-        id: thread_row.get(0), creator: thread_row.get(1),
-        title: thread_row.get(2),
-        latest_message: None,
-        messages: Some(result
-                       .into_iter()
-                       .filter(|row| row.get::<usize, Option<i32>>(3).is_some())
-                       .map(|row| Message {
-                           id: row.get(3),
-                           creator: row.get(4),
-                           content: row.get(5),
-                       })
-                       .collect()),
+        .ok_or(DbError::NotFound)
+}
+
+/// Start enrolling with `secret`, or turn two-factor authentication off
+/// with `None`. Either way it's disabled until `enable_totp`.
+pub fn set_totp_secret<T: IGC>(db: T, account_id: i32, secret: Option<&[u8]>) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE account SET totp_secret=$2, totp_enabled=FALSE, totp_last_step=NULL WHERE id=$1",
+                       &[&account_id, &secret])?)
+}
+
+pub fn enable_totp<T: IGC>(db: T, account_id: i32) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE account SET totp_enabled=TRUE WHERE id=$1 AND totp_secret IS NOT NULL",
+                       &[&account_id])?)
+}
+
+/// Record that the code of `step` was used. `NotFound` if it, or a later
+/// one, already was.
+pub fn use_totp_step<T: IGC>(db: T, account_id: i32, step: i64) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE account SET totp_last_step=$2 \
+                        WHERE id=$1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+                       &[&account_id, &step])?)
+}
+
+/// Replace the recovery codes of an account
+pub fn set_recovery_codes<T: IGC>(db: T, account_id: i32, code_hashes: &[String]) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.execute("DELETE FROM recovery_code WHERE account_id=$1", &[&account_id])?;
+    for code_hash in code_hashes {
+        conn.execute("INSERT INTO recovery_code (account_id, code_hash) VALUES ($1, $2)",
+                     &[&account_id, code_hash])?;
+    }
+    Ok(())
+}
+
+pub fn use_recovery_code<T: IGC>(db: T, account_id: i32, code_hash: &str) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("DELETE FROM recovery_code WHERE account_id=$1 AND code_hash=$2",
+                       &[&account_id, &code_hash])?)
+}
+
+pub fn create_mfa_challenge<T: IGC>(db: T, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+    -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.execute("INSERT INTO mfa_challenge (account_id, token_hash, expires_at) VALUES ($1, $2, $3)",
+                 &[&account_id, &token_hash, &expires_at])?;
+    Ok(())
+}
+
+/// Count an attempt at answering the challenge with `token_hash`, and
+/// return its account. `NotFound` if it has expired or run out of attempts.
+pub fn attempt_mfa_challenge<T: IGC>(db: T, token_hash: &str, max_attempts: i32) -> Result<i32, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("UPDATE mfa_challenge SET attempts=attempts + 1 \
+                WHERE token_hash=$1 AND expires_at > $2 AND attempts < $3 \
+                RETURNING account_id", &[&token_hash, &Utc::now(), &max_attempts])?
+        .into_iter()
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)
+}
+
+pub fn delete_mfa_challenges<T: IGC>(db: T, account_id: i32) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.execute("DELETE FROM mfa_challenge WHERE account_id=$1", &[&account_id])?;
+    Ok(())
+}
+
+pub fn create_thread<T: IGC>(db: T, account_id: i32, title: &str) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("INSERT INTO thread (title, creator) VALUES ($1, $2)", &[&title, &account_id])?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cursor<K = i32> {
+    Start,
+    /// The page following the item with this key
+    After(K),
+    /// The page preceding the item with this key
+    Before(K),
+}
+
+impl<K> Cursor<K> {
+    pub(crate) fn key(&self) -> Option<&K> {
+        match self {
+            Cursor::Start => None,
+            Cursor::After(key) | Cursor::Before(key) => Some(key),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PageRequest<K = i32> {
+    pub cursor: Cursor<K>,
+    pub limit: i64,
+}
+
+/// Position of a thread in a listing sorted by `ThreadSort`: the value of the
+/// sort column, with the id as a tie breaker.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum ThreadKey {
+    Time(DateTime<Utc>, i32),
+    Title(String, i32),
+}
+
+impl ThreadKey {
+    pub(crate) fn of(thread: &Thread, sort: ThreadSort) -> ThreadKey {
+        match sort {
+            ThreadSort::RecentActivity => ThreadKey::Time(thread.updated_at, thread.id),
+            ThreadSort::Created => ThreadKey::Time(thread.created_at, thread.id),
+            ThreadSort::Title => ThreadKey::Title(thread.title.clone(), thread.id),
+        }
+    }
+
+    /// Encode the key as an opaque, URL safe cursor
+    pub fn to_cursor(&self) -> String {
+        let key = match self {
+            ThreadKey::Time(time, id) => format!("{},{}", id, time.to_rfc3339_opts(SecondsFormat::Micros, true)),
+            ThreadKey::Title(title, id) => format!("{},{}", id, title),
+        };
+        base64::encode_config(key.as_bytes(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decode a cursor from `to_cursor`. Cursors are only valid for the sort
+    /// order they were created with.
+    pub fn from_cursor(sort: ThreadSort, cursor: &str) -> Option<ThreadKey> {
+        let key = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let key = String::from_utf8(key).ok()?;
+        let mut parts = key.splitn(2, ',');
+        let id = parts.next()?.parse().ok()?;
+        let value = parts.next()?;
+        match sort {
+            ThreadSort::RecentActivity | ThreadSort::Created => DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|time| ThreadKey::Time(time.with_timezone(&Utc), id)),
+            ThreadSort::Title => Some(ThreadKey::Title(value.to_string(), id)),
+        }
+    }
+
+    fn push_params<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        match self {
+            ThreadKey::Time(time, id) => params.extend(&[time as &dyn ToSql, id]),
+            ThreadKey::Title(title, id) => params.extend(&[title as &dyn ToSql, id]),
+        }
+    }
+}
+
+/// The WHERE condition and ORDER BY clause to fetch a page starting from
+/// `cursor`, for a list displayed in `columns` order (descending if
+/// `descending`). The cursor's values are bound starting from `$param`. Pages
+/// before the cursor are fetched in reverse and flipped by `into_page`.
+fn keyset<K>(cursor: &Cursor<K>, columns: &[&str], descending: bool, param: usize) -> (String, String) {
+    let backwards = match cursor {
+        Cursor::Before(_) => !descending,
+        Cursor::Start | Cursor::After(_) => descending,
+    };
+    let (cmp, order) = if backwards { ("<", "DESC") } else { (">", "ASC") };
+    let condition = match (cursor, columns) {
+        (Cursor::Start, _) => "TRUE".to_string(),
+        (_, [column]) => format!("{} {} ${}", column, cmp, param),
+        _ => {
+            let params: Vec<String> = (param..param + columns.len()).map(|i| format!("${}", i)).collect();
+            format!("({}) {} ({})", columns.join(", "), cmp, params.join(", "))
+        }
+    };
+    let order_by: Vec<String> = columns.iter().map(|column| format!("{} {}", column, order)).collect();
+    (condition, order_by.join(", "))
+}
+
+/// Build a page out of `rows`, which were fetched with `keyset` using a
+/// limit one larger than requested to find out whether there's more.
+pub(crate) fn into_page<T, K, F: Fn(&T) -> String>(mut rows: Vec<T>, page: &PageRequest<K>, cursor: F) -> Page<T> {
+    let has_more = rows.len() as i64 > page.limit;
+    rows.truncate(page.limit as usize);
+    let (has_next, has_prev) = match page.cursor {
+        Cursor::Start => (has_more, false),
+        Cursor::After(_) => (has_more, true),
+        Cursor::Before(_) => {
+            rows.reverse();
+            (true, has_more)
+        }
+    };
+    Page {
+        next: rows.last().filter(|_| has_next).map(|row| cursor(row)),
+        prev: rows.first().filter(|_| has_prev).map(|row| cursor(row)),
+        items: rows,
+    }
+}
+
+/// Columns of a message, as read by `message_from_row`
+const MESSAGE_COLUMNS: &str = "m.id, a.username, m.creator, m.content, m.created_at, m.updated_at, m.edited_at, \
+                               m.parent_id, m.depth, m.content_html, \
+                               (SELECT coalesce(json_agg(json_build_object( \
+                                    'id', f.id, 'filename', f.filename, 'content_type', f.content_type, \
+                                    'size', f.size, 'thumbnail', f.thumbnail_key IS NOT NULL) ORDER BY f.id), '[]') \
+                                FROM attachment f WHERE f.message_id = m.id)";
+
+/// Threads with their message count and latest message, ignoring anything
+/// deleted. Expects the caller to add the rest of the WHERE clause.
+fn thread_query() -> String {
+    format!("SELECT t.id, a.username, t.creator, t.title, t.created_at, t.updated_at, \
+                    (SELECT count(*) FROM message WHERE thread_id = t.id AND deleted_at IS NULL), \
+                    t.locked, lm.* \
+             FROM thread t \
+             LEFT JOIN account a ON t.creator = a.id \
+             LEFT JOIN LATERAL ( \
+                 SELECT {} \
+                 FROM message m \
+                 LEFT JOIN account a ON m.creator = a.id \
+                 WHERE m.thread_id = t.id AND m.deleted_at IS NULL \
+                 ORDER BY m.id DESC \
+                 LIMIT 1 \
+             ) lm ON TRUE \
+             WHERE t.deleted_at IS NULL", MESSAGE_COLUMNS)
+}
+
+/// A message from `MESSAGE_COLUMNS` starting at `offset`
+fn message_from_row(row: &Row, offset: usize) -> Message {
+    let content: String = row.get(offset + 3);
+    Message {
+        id: row.get(offset),
+        creator: row.get(offset + 1),
+        creator_id: row.get(offset + 2),
+        // Messages posted before migration 0010 weren't rendered
+        content_html: row.get::<_, Option<String>>(offset + 9).unwrap_or_else(|| markdown::render(&content)),
+        content,
+        created_at: row.get(offset + 4),
+        updated_at: row.get(offset + 5),
+        edited_at: row.get(offset + 6),
+        parent_id: row.get(offset + 7),
+        depth: row.get(offset + 8),
+        attachments: serde_json::from_value(row.get(offset + 10)).unwrap_or_default(),
+    }
+}
+
+fn thread_from_row(row: &Row) -> Thread {
+    let latest_id: Option<i32> = row.get(8);
+    Thread {
+        id: row.get(0),
+        creator: row.get(1),
+        creator_id: row.get(2),
+        title: row.get(3),
+        created_at: row.get(4),
+        updated_at: row.get(5),
+        message_count: row.get(6),
+        locked: row.get(7),
+        messages: None,
+        latest_message: latest_id.map(|_| message_from_row(row, 8)),
+    }
+}
+
+pub fn get_threads<T: IGC>(db: T, sort: ThreadSort, page: &PageRequest<ThreadKey>) -> Result<Page<Thread>, DbError> {
+    let conn = db.into_generic_connection();
+    let (column, descending) = match sort {
+        ThreadSort::RecentActivity => ("t.updated_at", true),
+        ThreadSort::Created => ("t.created_at", true),
+        ThreadSort::Title => ("t.title", false),
+    };
+    let (condition, order_by) = keyset(&page.cursor, &[column, "t.id"], descending, 2);
+    let query = format!("{} AND {} ORDER BY {} LIMIT $1", thread_query(), condition, order_by);
+    let limit = page.limit + 1;
+    let mut params: Vec<&dyn ToSql> = vec![&limit];
+    if let Some(key) = page.cursor.key() {
+        key.push_params(&mut params);
+    }
+    let threads = conn.query(&query, &params)?
+        .iter()
+        .map(|row| thread_from_row(&row))
+        .collect();
+    Ok(into_page(threads, page, |thread| ThreadKey::of(thread, sort).to_cursor()))
+}
+
+pub fn get_thread<T: IGC>(db: T, id: i32, page: &PageRequest) -> Result<Thread, DbError> {
+    let conn = db.into_generic_connection();
+    let mut thread = query_thread(conn, id)?;
+
+    let (condition, order_by) = keyset(&page.cursor, &["m.id"], false, 3);
+    let query = format!("SELECT {} \
+                         FROM message m \
+                         LEFT JOIN account a ON m.creator = a.id \
+                         WHERE m.thread_id=$1 AND m.deleted_at IS NULL AND {} \
+                         ORDER BY {} \
+                         LIMIT $2", MESSAGE_COLUMNS, condition, order_by);
+    let limit = page.limit + 1;
+    let rows = match page.cursor.key() {
+        Some(cursor) => conn.query(&query, &[&id, &limit, cursor])?,
+        None => conn.query(&query, &[&id, &limit])?,
+    };
+    let messages = rows
+        .iter()
+        .map(|row| message_from_row(&row, 0))
+        .collect();
+    thread.messages = Some(into_page(messages, page, |message: &Message| message.id.to_string()));
+    Ok(thread)
+}
+
+fn query_thread<C: GenericConnection>(conn: &C, id: i32) -> Result<Thread, DbError> {
+    conn.query(&format!("{} AND t.id=$1", thread_query()), &[&id])?
+        .iter()
+        .map(|row| thread_from_row(&row))
+        .next()
+        .ok_or(DbError::NotFound)
+}
+
+/// Threads created after the thread `id`, oldest first
+pub fn get_threads_created_after<T: IGC>(db: T, id: i32, limit: i64) -> Result<Vec<Thread>, DbError> {
+    let conn = db.into_generic_connection();
+    Ok(conn.query(&format!("{} AND t.id > $1 ORDER BY t.id LIMIT $2", thread_query()), &[&id, &limit])?
+        .iter()
+        .map(|row| thread_from_row(&row))
+        .collect())
+}
+
+/// A thread without its messages
+pub fn get_thread_summary<T: IGC>(db: T, id: i32) -> Result<Thread, DbError> {
+    query_thread(db.into_generic_connection(), id)
+}
+
+/// Put around the matched words by `ts_headline`, and split out again by
+/// `snippet_parts`. Control characters don't occur in normal text, unlike
+/// any markup we could pick.
+const HIGHLIGHT_START: char = '\u{2}';
+const HIGHLIGHT_STOP: char = '\u{3}';
+
+fn snippet_parts(headline: &str) -> Vec<SnippetPart> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    for c in headline.chars() {
+        let highlighted = match c {
+            HIGHLIGHT_START => false,
+            HIGHLIGHT_STOP => true,
+            c => {
+                text.push(c);
+                continue;
+            }
+        };
+        if !text.is_empty() {
+            parts.push(SnippetPart { text: std::mem::replace(&mut text, String::new()), highlighted });
+        }
+    }
+    if !text.is_empty() {
+        parts.push(SnippetPart { text, highlighted: false });
+    }
+    parts
+}
+
+/// Thread titles and messages matching a web search style query (`"quoted
+/// phrases"`, `or` and `-excluded` words), best matches first
+pub fn search<T: IGC>(db: T, query: &str, limit: i64) -> Result<Vec<SearchResult>, DbError> {
+    let conn = db.into_generic_connection();
+    let options = format!("StartSel={}, StopSel={}, MaxFragments=2", HIGHLIGHT_START, HIGHLIGHT_STOP);
+    // Snippets are only built for the hits returned, as ts_headline is slow
+    Ok(conn.query(
+        "WITH q AS (SELECT websearch_to_tsquery('english', $1) AS query), \
+         hits AS ( \
+             SELECT t.id AS thread_id, NULL::INTEGER AS message_id, t.title AS text, t.creator, \
+                    t.created_at, ts_rank(t.search, q.query) AS rank \
+             FROM thread t, q \
+             WHERE t.search @@ q.query AND t.deleted_at IS NULL \
+             UNION ALL \
+             SELECT m.thread_id, m.id, m.content, m.creator, m.created_at, ts_rank(m.search, q.query) \
+             FROM message m JOIN thread t ON t.id = m.thread_id, q \
+             WHERE m.search @@ q.query AND m.deleted_at IS NULL AND t.deleted_at IS NULL \
+             ORDER BY rank DESC, created_at DESC \
+             LIMIT $2 \
+         ) \
+         SELECT h.thread_id, t.title, h.message_id, a.username, h.created_at, h.rank, \
+                ts_headline('english', h.text, q.query, $3) \
+         FROM hits h \
+         JOIN thread t ON t.id = h.thread_id \
+         JOIN account a ON a.id = h.creator, q \
+         ORDER BY h.rank DESC, h.created_at DESC",
+        &[&query, &limit, &options])?
+        .iter()
+        .map(|row| SearchResult {
+            thread_id: row.get(0),
+            thread_title: row.get(1),
+            message_id: row.get(2),
+            creator: row.get(3),
+            created_at: row.get(4),
+            rank: row.get(5),
+            snippet: snippet_parts(&row.get::<_, String>(6)),
+        })
+        .collect())
+}
+
+pub fn get_message<T: IGC>(db: T, thread_id: i32, id: i32) -> Result<Message, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query(&format!("SELECT {} \
+                         FROM message m \
+                         LEFT JOIN account a ON m.creator = a.id \
+                         WHERE m.id=$1 AND m.thread_id=$2 AND m.deleted_at IS NULL", MESSAGE_COLUMNS),
+               &[&id, &thread_id])?
+        .iter()
+        .map(|row| message_from_row(&row, 0))
+        .next()
+        .ok_or(DbError::NotFound)
+}
+
+/// Post a message, bumping the thread's latest activity. A reply goes one
+/// level deeper than its parent, which has to be in the same thread.
+pub fn create_message<T: IGC>(db: T, account_id: i32, thread_id: i32, message: &str, message_html: &str,
+                              parent_id: Option<i32>) -> Result<i32, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("WITH p AS ( \
+                   SELECT depth + 1 AS depth FROM message \
+                   WHERE id=$4 AND thread_id=$1 AND deleted_at IS NULL \
+               ), m AS ( \
+                   INSERT INTO message (thread_id, content, content_html, creator, parent_id, depth) \
+                   SELECT $1, $2, $5, $3, $4, COALESCE((SELECT depth FROM p), 0) \
+                   WHERE EXISTS (SELECT 1 FROM thread WHERE id=$1 AND deleted_at IS NULL) \
+                   AND ($4 IS NULL OR EXISTS (SELECT 1 FROM p)) \
+                   RETURNING id, thread_id, created_at \
+               ) \
+               UPDATE thread SET updated_at = m.created_at FROM m WHERE thread.id = m.thread_id \
+               RETURNING m.id",
+               &[&thread_id, &message, &account_id, &parent_id, &message_html])?
+        .into_iter()
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)
+}
+
+/// An attachment along with where its content is kept
+#[derive(Clone, Debug)]
+pub struct StoredAttachment {
+    pub attachment: Attachment,
+    pub uploader: i32,
+    /// `None` until the message it was uploaded for is posted
+    pub message_id: Option<i32>,
+    pub blob_key: String,
+    pub thumbnail_key: Option<String>,
+}
+
+pub struct NewAttachment<'a> {
+    pub uploader: i32,
+    pub filename: &'a str,
+    pub content_type: &'a str,
+    pub size: i32,
+    pub blob_key: &'a str,
+    pub thumbnail_key: Option<&'a str>,
+}
+
+pub fn create_attachment<T: IGC>(db: T, attachment: &NewAttachment) -> Result<Attachment, DbError> {
+    let conn = db.into_generic_connection();
+    let id = conn.query("INSERT INTO attachment (uploader, filename, content_type, size, blob_key, thumbnail_key) \
+                        VALUES ($1, $2, $3, $4, $5, $6) \
+                        RETURNING id",
+                        &[&attachment.uploader, &attachment.filename, &attachment.content_type, &attachment.size,
+                          &attachment.blob_key, &attachment.thumbnail_key])?
+        .into_iter()
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)?;
+    Ok(Attachment {
+        id,
+        filename: attachment.filename.to_string(),
+        content_type: attachment.content_type.to_string(),
+        size: attachment.size,
+        thumbnail: attachment.thumbnail_key.is_some(),
     })
 }
 
-pub fn create_message<T: IGC>(db: T, account_id: i32, thread_id: i32, message: &str) {
+/// An attachment that hasn't been attached yet, or whose message and thread
+/// weren't deleted
+pub fn get_attachment<T: IGC>(db: T, id: i32) -> Result<StoredAttachment, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("SELECT f.id, f.filename, f.content_type, f.size, f.uploader, f.message_id, f.blob_key, \
+                       f.thumbnail_key \
+               FROM attachment f \
+               LEFT JOIN message m ON f.message_id = m.id \
+               LEFT JOIN thread t ON m.thread_id = t.id \
+               WHERE f.id=$1 AND (f.message_id IS NULL OR (m.deleted_at IS NULL AND t.deleted_at IS NULL))",
+               &[&id])?
+        .into_iter()
+        .map(|row| {
+            let thumbnail_key: Option<String> = row.get(7);
+            StoredAttachment {
+                attachment: Attachment {
+                    id: row.get(0),
+                    filename: row.get(1),
+                    content_type: row.get(2),
+                    size: row.get(3),
+                    thumbnail: thumbnail_key.is_some(),
+                },
+                uploader: row.get(4),
+                message_id: row.get(5),
+                blob_key: row.get(6),
+                thumbnail_key,
+            }
+        })
+        .next()
+        .ok_or(DbError::NotFound)
+}
+
+/// Attach uploads of `account_id` to a message. `NotFound` unless every one
+/// of them exists and isn't attached to anything yet.
+pub fn attach<T: IGC>(db: T, account_id: i32, message_id: i32, ids: &[i32]) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    let attached = conn.execute("UPDATE attachment SET message_id=$2 \
+                                 WHERE id = ANY($3) AND uploader=$1 AND message_id IS NULL",
+                                &[&account_id, &message_id, &ids])?;
+    if attached as usize == ids.len() { Ok(()) } else { Err(DbError::NotFound) }
+}
+
+/// Delete the uploads that weren't attached to anything before
+/// `created_before`, returning the blob keys of their content and thumbnails
+pub fn delete_unattached<T: IGC>(db: T, created_before: DateTime<Utc>) -> Result<Vec<String>, DbError> {
+    let conn = db.into_generic_connection();
+    let rows = conn.query("DELETE FROM attachment WHERE message_id IS NULL AND created_at < $1 \
+                           RETURNING blob_key, thumbnail_key",
+                          &[&created_before])?;
+    Ok(rows.iter()
+        .flat_map(|row| {
+            let thumbnail_key: Option<String> = row.get(1);
+            std::iter::once(row.get(0)).chain(thumbnail_key)
+        })
+        .collect())
+}
+
+/// `NotFound` unless exactly one row was affected
+fn found(rows: u64) -> Result<(), DbError> {
+    if rows == 1 { Ok(()) } else { Err(DbError::NotFound) }
+}
+
+/// Who created a thread or message, and whether its thread is locked
+#[derive(Clone, Copy, Debug)]
+pub struct Ownership {
+    pub creator: i32,
+    pub locked: bool,
+}
+
+pub fn get_thread_ownership<T: IGC>(db: T, id: i32) -> Result<Ownership, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("SELECT creator, locked FROM thread WHERE id=$1 AND deleted_at IS NULL", &[&id])?
+        .into_iter()
+        .next()
+        .map(|row| Ownership { creator: row.get(0), locked: row.get(1) })
+        .ok_or(DbError::NotFound)
+}
+
+pub fn set_thread_locked<T: IGC>(db: T, id: i32, locked: bool) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE thread SET locked=$2 WHERE id=$1 AND deleted_at IS NULL", &[&id, &locked])?)
+}
+
+pub fn update_thread<T: IGC>(db: T, id: i32, title: &str) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE thread SET title=$2, edited_at=now() \
+                        WHERE id=$1 AND deleted_at IS NULL", &[&id, &title])?)
+}
+
+/// Soft delete a thread, hiding it and its messages
+pub fn delete_thread<T: IGC>(db: T, id: i32) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE thread SET deleted_at=now() WHERE id=$1 AND deleted_at IS NULL", &[&id])?)
+}
+
+pub fn get_message_ownership<T: IGC>(db: T, thread_id: i32, id: i32) -> Result<Ownership, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("SELECT m.creator, t.locked FROM message m \
+                JOIN thread t ON m.thread_id = t.id \
+                WHERE m.id=$1 AND m.thread_id=$2 AND m.deleted_at IS NULL AND t.deleted_at IS NULL",
+               &[&id, &thread_id])?
+        .into_iter()
+        .next()
+        .map(|row| Ownership { creator: row.get(0), locked: row.get(1) })
+        .ok_or(DbError::NotFound)
+}
+
+/// Replace the content of a message, keeping the current version in
+/// `message_revision`. Should be run in a transaction.
+pub fn update_message<T: IGC>(db: T, account_id: i32, thread_id: i32, id: i32, content: &str, content_html: &str)
+    -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("INSERT INTO message_revision \
+                        (message_id, thread_id, content, created_at, replaced_at, replaced_by) \
+                        SELECT id, thread_id, content, coalesce(edited_at, created_at), now(), $3 \
+                        FROM message WHERE id=$1 AND thread_id=$2 AND deleted_at IS NULL",
+                       &[&id, &thread_id, &account_id])?)?;
+    found(conn.execute("UPDATE message SET content=$3, content_html=$4, edited_at=now(), updated_at=now() \
+                        WHERE id=$1 AND thread_id=$2", &[&id, &thread_id, &content, &content_html])?)
+}
+
+/// Soft delete a message. Its revisions are kept.
+pub fn delete_message<T: IGC>(db: T, thread_id: i32, id: i32) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    found(conn.execute("UPDATE message SET deleted_at=now() \
+                        WHERE id=$1 AND thread_id=$2 AND deleted_at IS NULL", &[&id, &thread_id])?)
+}
+
+/// Prior versions of a message, oldest first
+pub fn get_message_revisions<T: IGC>(db: T, thread_id: i32, id: i32) -> Result<Vec<MessageRevision>, DbError> {
+    let conn = db.into_generic_connection();
+    Ok(conn.query("SELECT r.content, r.created_at, r.replaced_at, a.username \
+                   FROM message_revision r \
+                   LEFT JOIN account a ON r.replaced_by = a.id \
+                   WHERE r.message_id=$1 AND r.thread_id=$2 \
+                   ORDER BY r.id", &[&id, &thread_id])?
+        .into_iter()
+        .map(|row| MessageRevision {
+            content: row.get(0),
+            created_at: row.get(1),
+            replaced_at: row.get(2),
+            replaced_by: row.get(3),
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub account_id: i32,
+    pub family: String,
+    pub expires_at: DateTime<Utc>,
+    pub used: bool,
+    pub revoked: bool,
+}
+
+pub struct NewRefreshToken<'a> {
+    pub account_id: i32,
+    pub token_hash: &'a str,
+    pub family: &'a str,
+    pub access_jti: &'a str,
+    pub access_expires_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub fn create_refresh_token<T: IGC>(db: T, token: &NewRefreshToken) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("INSERT INTO refresh_token \
+               (account_id, token_hash, family, access_jti, access_expires_at, expires_at) \
+               VALUES ($1, $2, $3, $4, $5, $6)",
+               &[&token.account_id, &token.token_hash, &token.family,
+                 &token.access_jti, &token.access_expires_at, &token.expires_at])?;
+    Ok(())
+}
+
+pub fn get_refresh_token<T: IGC>(db: T, token_hash: &str) -> Result<RefreshToken, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("SELECT id, account_id, family, expires_at, used, revoked \
+               FROM refresh_token WHERE token_hash=$1", &[&token_hash])?
+        .into_iter()
+        .map(|row| RefreshToken {
+            id: row.get(0),
+            account_id: row.get(1),
+            family: row.get(2),
+            expires_at: row.get(3),
+            used: row.get(4),
+            revoked: row.get(5),
+        })
+        .next()
+        .ok_or(DbError::NotFound)
+}
+
+/// Mark a refresh token as used, returning false if it already was. Checked
+/// in the same statement so that of two concurrent refreshes only one wins.
+pub fn mark_refresh_token_used<T: IGC>(db: T, id: i32) -> Result<bool, DbError> {
+    let conn = db.into_generic_connection();
+    let marked = conn.execute("UPDATE refresh_token SET used=TRUE WHERE id=$1 AND NOT used", &[&id])?;
+    Ok(marked == 1)
+}
+
+/// Revoke every refresh token of a rotation family, along with the access
+/// tokens that were issued with them.
+pub fn revoke_refresh_token_family<T: IGC>(db: T, family: &str) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("INSERT INTO revoked_token (jti, expires_at) \
+               SELECT access_jti, access_expires_at FROM refresh_token \
+               WHERE family=$1 AND access_expires_at > $2 \
+               ON CONFLICT DO NOTHING", &[&family, &Utc::now()])?;
+    conn.query("UPDATE refresh_token SET revoked=TRUE WHERE family=$1", &[&family])?;
+    Ok(())
+}
+
+/// Revoke every session of an account, e.g. when it gets banned
+pub fn revoke_account_tokens<T: IGC>(db: T, account_id: i32) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("INSERT INTO revoked_token (jti, expires_at) \
+               SELECT access_jti, access_expires_at FROM refresh_token \
+               WHERE account_id=$1 AND access_expires_at > $2 \
+               ON CONFLICT DO NOTHING", &[&account_id, &Utc::now()])?;
+    conn.query("UPDATE refresh_token SET revoked=TRUE WHERE account_id=$1", &[&account_id])?;
+    Ok(())
+}
+
+pub fn revoke_token<T: IGC>(db: T, jti: &str, expires_at: DateTime<Utc>) -> Result<(), DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("DELETE FROM revoked_token WHERE expires_at < $1", &[&Utc::now()])?;
+    conn.query("INSERT INTO revoked_token (jti, expires_at) VALUES ($1, $2) \
+               ON CONFLICT DO NOTHING", &[&jti, &expires_at])?;
+    Ok(())
+}
+
+pub fn is_token_revoked<T: IGC>(db: T, jti: &str) -> Result<bool, DbError> {
+    let conn = db.into_generic_connection();
+    Ok(!conn.query("SELECT 1 FROM revoked_token WHERE jti=$1", &[&jti])?.is_empty())
+}
+
+/// Refill the token bucket `key` and take a token out of it. `None` if there
+/// was one to take, otherwise the tokens left. Buckets start out full. The
+/// time is the database's, so that every instance agrees on it.
+pub fn take_token<T: IGC>(db: T, key: &str, burst: f64, per_second: f64) -> Result<Option<f64>, DbError> {
+    let conn = db.into_generic_connection();
+    let tokens: f64 = conn.query(
+        "INSERT INTO rate_limit_bucket AS b (key, tokens, updated_at) VALUES ($1, $2, now()) \
+         ON CONFLICT (key) DO UPDATE \
+         SET tokens = LEAST($2, b.tokens + GREATEST(EXTRACT(EPOCH FROM now() - b.updated_at)::float8, 0) * $3), \
+             updated_at = now() \
+         RETURNING tokens", &[&key, &burst, &per_second])?
+        .into_iter()
+        .next()
+        .map(|row| row.get(0))
+        .ok_or(DbError::NotFound)?;
+    // Separately and conditionally, so that concurrent requests can't take
+    // the same token
+    let taken = conn.execute("UPDATE rate_limit_bucket SET tokens = tokens - 1 WHERE key = $1 AND tokens >= 1",
+                             &[&key])?;
+    Ok(if taken == 1 { None } else { Some(tokens) })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FailedLogins {
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+}
+
+/// Failed logins in a row as `username`, unless the last one was over
+/// `reset_after` seconds ago
+pub fn get_failed_logins<T: IGC>(db: T, username: &str, reset_after: f64) -> Result<Option<FailedLogins>, DbError> {
+    let conn = db.into_generic_connection();
+    Ok(conn.query("SELECT failures, last_failure_at FROM login_failure \
+                   WHERE username = $1 AND last_failure_at > now() - $2 * INTERVAL '1 second'",
+                  &[&username, &reset_after])?
+        .into_iter()
+        .next()
+        .map(|row| FailedLogins { failures: row.get(0), last_failure_at: row.get(1) }))
+}
+
+/// Count another failed login, starting over if the last one was over
+/// `reset_after` seconds ago
+pub fn record_failed_login<T: IGC>(db: T, username: &str, reset_after: f64) -> Result<FailedLogins, DbError> {
+    let conn = db.into_generic_connection();
+    conn.query("INSERT INTO login_failure AS f (username, failures, last_failure_at) VALUES ($1, 1, now()) \
+                ON CONFLICT (username) DO UPDATE \
+                SET failures = CASE WHEN f.last_failure_at > now() - $2 * INTERVAL '1 second' \
+                                    THEN f.failures + 1 ELSE 1 END, \
+                    last_failure_at = now() \
+                RETURNING failures, last_failure_at", &[&username, &reset_after])?
+        .into_iter()
+        .next()
+        .map(|row| FailedLogins { failures: row.get(0), last_failure_at: row.get(1) })
+        .ok_or(DbError::NotFound)
+}
+
+pub fn clear_failed_logins<T: IGC>(db: T, username: &str) -> Result<(), DbError> {
     let conn = db.into_generic_connection();
-    conn.query("INSERT INTO message (thread_id, content, creator) VALUES ($1, $2, $3)",
-               &[&thread_id, &message, &account_id]).unwrap();
+    conn.execute("DELETE FROM login_failure WHERE username = $1", &[&username])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use futures::Future;
+
+    fn page(cursor: Cursor) -> PageRequest {
+        PageRequest { cursor, limit: 2 }
+    }
+
+    fn id(i: &i32) -> String {
+        i.to_string()
+    }
+
+    #[test]
+    fn first_page() {
+        let page = into_page(vec![9, 8, 7], &page(Cursor::Start), id);
+        assert_eq!(page.items, vec![9, 8]);
+        assert_eq!(page.next, Some("8".to_string()));
+        assert_eq!(page.prev, None);
+    }
+
+    #[test]
+    fn last_page_after_cursor() {
+        let page = into_page(vec![7], &page(Cursor::After(8)), id);
+        assert_eq!(page.items, vec![7]);
+        assert_eq!(page.next, None);
+        assert_eq!(page.prev, Some("7".to_string()));
+    }
+
+    #[test]
+    fn page_before_cursor_is_flipped() {
+        // Fetched in ascending order when the list is displayed descending
+        let page = into_page(vec![8, 9, 10], &page(Cursor::Before(7)), id);
+        assert_eq!(page.items, vec![9, 8]);
+        assert_eq!(page.next, Some("8".to_string()));
+        assert_eq!(page.prev, Some("9".to_string()));
+    }
+
+    #[test]
+    fn keyset_conditions() {
+        let keyset = |cursor, columns: &[&str], descending, param| keyset(&cursor, columns, descending, param);
+        assert_eq!(keyset(Cursor::Start, &["t.id"], true, 2), ("TRUE".to_string(), "t.id DESC".to_string()));
+        assert_eq!(keyset(Cursor::After(5), &["t.id"], true, 2), ("t.id < $2".to_string(), "t.id DESC".to_string()));
+        assert_eq!(keyset(Cursor::Before(5), &["t.id"], true, 2), ("t.id > $2".to_string(), "t.id ASC".to_string()));
+        assert_eq!(keyset(Cursor::After(5), &["m.id"], false, 3), ("m.id > $3".to_string(), "m.id ASC".to_string()));
+        assert_eq!(keyset(Cursor::After(5), &["t.title", "t.id"], false, 2),
+                   ("(t.title, t.id) > ($2, $3)".to_string(), "t.title ASC, t.id ASC".to_string()));
+    }
+
+    #[test]
+    fn splits_highlighted_snippets() {
+        let part = |text: &str, highlighted| SnippetPart { text: text.to_string(), highlighted };
+        assert_eq!(snippet_parts("a \u{2}rust\u{3} forum \u{2}post\u{3}"),
+                   vec![part("a ", false), part("rust", true), part(" forum ", false), part("post", true)]);
+        assert_eq!(snippet_parts(""), vec![]);
+    }
+
+    #[test]
+    fn blocking_fails_off_the_thread_pool() {
+        let result = blocking(|| Ok::<_, DbError>(1)).wait();
+        assert!(match result { Err(DbError::Blocking(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn thread_cursor_roundtrip() {
+        let time = Utc.timestamp(1_570_000_000, 123_456_000);
+        let key = ThreadKey::Time(time, 12);
+        let cursor = key.to_cursor();
+        assert_eq!(ThreadKey::from_cursor(ThreadSort::RecentActivity, &cursor), Some(key));
+
+        let key = ThreadKey::Title("Hello, world?&".to_string(), 3);
+        assert_eq!(ThreadKey::from_cursor(ThreadSort::Title, &key.to_cursor()), Some(key));
+
+        assert_eq!(ThreadKey::from_cursor(ThreadSort::Created, "12"), None);
+    }
 }
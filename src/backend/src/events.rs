@@ -0,0 +1,152 @@
+use fallible_iterator::FallibleIterator;
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use postgres::TlsMode;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use types::Event;
+
+use crate::config::Config;
+use crate::db::{self, Connection, Database, DbError};
+
+/// The channel the `notify_forum_event` trigger publishes on
+const CHANNEL: &str = "forum_events";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Table {
+    Thread,
+    Message,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// The payload of a notification, identifying the changed row
+#[derive(Debug, Deserialize)]
+struct Change {
+    table: Table,
+    kind: Kind,
+    thread_id: i32,
+    message_id: Option<i32>,
+}
+
+/// Fans events out to every connected client
+#[derive(Debug, Default)]
+pub struct Hub {
+    subscribers: Mutex<Vec<UnboundedSender<Arc<Event>>>>,
+}
+
+impl Hub {
+    /// Receive every event from now on. Clients filter out the ones they
+    /// aren't interested in.
+    pub fn subscribe(&self) -> UnboundedReceiver<Arc<Event>> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        let event = Arc::new(event);
+        // Disconnected clients have dropped their receiver
+        self.subscribers.lock().unwrap().retain(|s| s.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// Load the rows a change refers to and turn it into events. Message changes
+/// also produce a `ThreadUpdated`, as they change the thread's latest message.
+fn events(db: &Connection, change: &Change) -> Result<Vec<Event>, DbError> {
+    let thread_id = change.thread_id;
+    let thread_updated = || db::get_thread_summary(db, thread_id).map(|thread| Event::ThreadUpdated { thread });
+    let events = match (&change.table, &change.kind, change.message_id) {
+        (Table::Thread, Kind::Created, _) =>
+            vec![Event::ThreadCreated { thread: db::get_thread_summary(db, thread_id)? }],
+        (Table::Thread, Kind::Updated, _) => vec![thread_updated()?],
+        (Table::Thread, Kind::Deleted, _) => vec![Event::ThreadDeleted { thread_id }],
+        (Table::Message, Kind::Created, Some(id)) => {
+            // The thread's activity bump is published on its own
+            vec![Event::MessageCreated { thread_id, message: db::get_message(db, thread_id, id)? }]
+        }
+        (Table::Message, Kind::Updated, Some(id)) => vec![
+            Event::MessageUpdated { thread_id, message: db::get_message(db, thread_id, id)? },
+            thread_updated()?,
+        ],
+        (Table::Message, Kind::Deleted, Some(message_id)) => vec![
+            Event::MessageDeleted { thread_id, message_id },
+            thread_updated()?,
+        ],
+        (Table::Message, _, None) => vec![],
+    };
+    Ok(events)
+}
+
+fn listen_once(config: &Config, database: &Database, hub: &Hub) -> Result<(), Box<dyn std::error::Error>> {
+    // LISTEN needs a connection of its own, one from the pool would be
+    // handed out to requests while we wait
+    let listener = postgres::Connection::connect(config.database_url.as_str(), TlsMode::None)?;
+    listener.execute(&format!("LISTEN {}", CHANNEL), &[])?;
+    let notifications = listener.notifications();
+    let mut notifications = notifications.blocking_iter();
+    while let Some(notification) = notifications.next()? {
+        let change: Change = match serde_json::from_str(&notification.payload) {
+            Ok(change) => change,
+            Err(e) => {
+                eprintln!("Ignoring malformed notification {:?}: {}", notification.payload, e);
+                continue;
+            }
+        };
+        let db = database.get()?;
+        match events(&db, &change) {
+            Ok(events) => events.into_iter().for_each(|event| hub.publish(event)),
+            // Deleted again before we got to it
+            Err(DbError::NotFound) => {}
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    Ok(())
+}
+
+/// Publish database changes to `hub` from a background thread, reconnecting
+/// whenever the connection is lost. Every backend instance runs its own
+/// listener, so clients see changes made through any of them.
+pub fn spawn_listener(config: Arc<Config>, database: Database, hub: Arc<Hub>) {
+    thread::spawn(move || loop {
+        if let Err(e) = listen_once(&config, &database, &hub) {
+            eprintln!("Event listener failed: {}", e);
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trigger_payloads() {
+        let change: Change = serde_json::from_str(
+            r#"{"table" : "message", "kind" : "deleted", "thread_id" : 3, "message_id" : 7}"#).unwrap();
+        assert_eq!((change.thread_id, change.message_id), (3, Some(7)));
+
+        let change: Change = serde_json::from_str(
+            r#"{"table" : "thread", "kind" : "created", "thread_id" : 3}"#).unwrap();
+        assert_eq!(change.message_id, None);
+    }
+
+    #[test]
+    fn hub_drops_disconnected_subscribers() {
+        let hub = Hub::default();
+        let receiver = hub.subscribe();
+        let _kept = hub.subscribe();
+        drop(receiver);
+        hub.publish(Event::ThreadDeleted { thread_id: 1 });
+        assert_eq!(hub.subscribers.lock().unwrap().len(), 1);
+    }
+}
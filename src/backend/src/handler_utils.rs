@@ -1,32 +1,60 @@
-use futures::Future;
+use futures::{future, Async, Future};
 use futures::stream::Stream;
 use gotham::error::Result as GothamResult;
-use gotham::handler::{IntoHandlerError, HandlerError, NewHandler, Handler, IntoHandlerFuture, HandlerFuture};
+use gotham::handler::{NewHandler, Handler, HandlerFuture, IntoHandlerError};
 use gotham::helpers::http::response::create_response;
-use gotham::state::{FromState, State};
+use gotham::state::{request_id, FromState, State};
+use hyper::header::RETRY_AFTER;
 use hyper::{Body, StatusCode};
 use std::panic::RefUnwindSafe;
 use std::str::from_utf8;
+use types::{ApiError, ErrorCode, FieldError};
 
-use crate::db::{DB_CONNECTION, Connection};
+use crate::auth::AuthError;
+use crate::db::DbError;
+use crate::router::S;
+use crate::store::Store;
+
+/// How `DBHandler` runs a handler. Handlers that answer right away are run
+/// in `blocking_response` as a whole. The ones returning a future, such as
+/// those built with `with_json`, first wait for the request body on the event
+/// loop and wrap only their queries in `blocking_response` themselves.
+pub trait DbResponse: Sized {
+    fn run<F>(f: F, state: State, store: Store) -> Box<HandlerFuture>
+    where F: FnOnce(State, Store) -> Self + Send + 'static;
+}
+
+impl DbResponse for (State, hyper::Response<Body>) {
+    fn run<F>(f: F, state: State, store: Store) -> Box<HandlerFuture>
+    where F: FnOnce(State, Store) -> Self + Send + 'static {
+        blocking_response(state, move |state| f(state, store))
+    }
+}
+
+impl DbResponse for Box<HandlerFuture> {
+    fn run<F>(f: F, state: State, store: Store) -> Box<HandlerFuture>
+    where F: FnOnce(State, Store) -> Self + Send + 'static {
+        f(state, store)
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct DBHandlerI<F, R>
-where F: FnOnce(State, Connection) -> R + Send,
-      R: IntoHandlerFuture {
+where F: FnOnce(State, Store) -> R + Send,
+      R: DbResponse {
     f: F
 }
 
 #[derive(Debug)]
 pub struct DBHandler<F, R>
-where F: FnOnce(State, Connection) -> R + Send,
-      R: IntoHandlerFuture {
+where F: FnOnce(State, Store) -> R + Send,
+      R: DbResponse {
     f: F
 }
 
 impl<F, R> NewHandler for DBHandlerI<F, R>
-where F: FnOnce(State, Connection) -> R + Copy + Send + Sync + RefUnwindSafe,
-      R: IntoHandlerFuture {
+where F: FnOnce(State, Store) -> R + Copy + Send + Sync + RefUnwindSafe + 'static,
+      R: DbResponse {
     type Instance = DBHandler<F, R>;
 
     fn new_handler(&self) -> GothamResult<Self::Instance> {
@@ -36,29 +64,49 @@ where F: FnOnce(State, Connection) -> R + Copy + Send + Sync + RefUnwindSafe,
 
 impl<F, R> Handler for DBHandler<F, R>
 where
-F: FnOnce(State, Connection) -> R + Send,
-R: IntoHandlerFuture {
+F: FnOnce(State, Store) -> R + Send + 'static,
+R: DbResponse {
+    /// Queries block, so they run off the event loop, see `DbResponse`. When
+    /// every connection stays busy for `pool_timeout` the store fails with
+    /// `DbError::Connection`, which handlers answer with 503.
     fn handle(self, state: State) -> Box<HandlerFuture> {
-        let conn = DB_CONNECTION.get().unwrap();
-        (self.f)(state, Connection::new(Box::new(conn))).into_handler_future()
+        let store = S::borrow_from(&state).store.clone();
+        R::run(self.f, state, store)
     }
 }
 
+/// `blocking` for the rest of a handler. The state is handed back if it can't
+/// block, to fail the request with.
+pub fn blocking_response<F>(state: State, f: F) -> Box<HandlerFuture>
+where F: FnOnce(State) -> (State, hyper::Response<Body>) + Send + 'static {
+    let mut rest = Some((state, f));
+    Box::new(future::poll_fn(move || {
+        match tokio_threadpool::blocking(|| {
+            let (state, f) = rest.take().unwrap();
+            f(state)
+        }) {
+            Ok(Async::Ready(done)) => Ok(Async::Ready(done)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                let (state, _) = rest.take().unwrap();
+                Err((state, e.into_handler_error()))
+            }
+        }
+    }))
+}
+
 pub fn r<F, R>(f: F) -> DBHandlerI<F, R>
-where F: FnOnce(State, Connection) -> R + Send,
-      R: IntoHandlerFuture {
+where F: FnOnce(State, Store) -> R + Send,
+      R: DbResponse {
     DBHandlerI { f }
 }
 
 
-fn bad_request<E>(e: E) -> HandlerError
-where
-    E: std::error::Error + Send + 'static,
-{
-    e.into_handler_error().with_status(StatusCode::BAD_REQUEST)
+fn bad_request<E: std::fmt::Display>(e: E) -> HttpResult {
+    HttpResult::new(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e.to_string())
 }
 
-pub fn extract_json<T>(state: &mut State) -> impl Future<Item = T, Error = HandlerError>
+pub fn extract_json<T>(state: &mut State) -> impl Future<Item = T, Error = HttpResult>
 where
     T: serde::de::DeserializeOwned,
 {
@@ -73,84 +121,215 @@ where
         })
 }
 
+/// The whole request body, failing with 413 once it grows past `limit` bytes
+pub fn read_body(state: &mut State, limit: usize) -> impl Future<Item = Vec<u8>, Error = HttpResult> {
+    Body::take_from(state)
+        .map_err(bad_request)
+        .fold(Vec::new(), move |mut body, chunk| {
+            if body.len() + chunk.len() > limit {
+                return Err(HttpResult::new(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge,
+                                           format!("The request body is limited to {} bytes", limit)));
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        })
+}
+
+/// Build the JSON error response for `error`, tagged with the id of the
+/// current request.
+pub fn error_response(state: &State, status: StatusCode, mut error: ApiError) -> hyper::Response<Body> {
+    error.request_id = Some(request_id(state).to_string());
+    let body = serde_json::to_string(&error).unwrap_or_default();
+    let mut response = create_response(state, status, mime::APPLICATION_JSON, body);
+    if let Some(seconds) = error.retry_after {
+        response.headers_mut().insert(RETRY_AFTER, seconds.into());
+    }
+    response
+}
+
+pub fn json_response<T: serde::Serialize>(state: &State, status: StatusCode, value: &T)
+    -> Result<hyper::Response<Body>, HttpResult> {
+    let body = serde_json::to_string(value)?;
+    Ok(create_response(state, status, mime::APPLICATION_JSON, body))
+}
+
+/// Fail with a validation error listing every field that is blank
+pub fn require_fields(fields: &[(&str, &str)]) -> Result<(), HttpResult> {
+    let details: Vec<FieldError> = fields.iter()
+        .filter(|(_, value)| value.trim().is_empty())
+        .map(|(field, _)| FieldError { field: field.to_string(), message: "must not be empty".to_string() })
+        .collect();
+    if details.is_empty() {
+        Ok(())
+    } else {
+        Err(HttpResult::validation(details))
+    }
+}
+
 pub trait IntoHttpError {
     fn into_http_result(self, state: &State) -> Result<hyper::Response<Body>, hyper::Response<Body>>;
 }
 
-pub struct HttpResult(StatusCode, mime::Mime, Body);
+#[derive(Debug)]
+pub struct HttpResult(StatusCode, ApiError);
+
+impl HttpResult {
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> Self {
+        HttpResult(status, ApiError::new(code, message))
+    }
+
+    /// A 422 listing the fields that failed validation
+    pub fn validation(details: Vec<FieldError>) -> Self {
+        let mut error = ApiError::new(ErrorCode::ValidationFailed, "Validation failed");
+        error.details = details;
+        HttpResult(StatusCode::UNPROCESSABLE_ENTITY, error)
+    }
+
+    /// A 429, to be retried after `retry_after` seconds
+    pub fn too_many_requests(message: impl Into<String>, retry_after: u64) -> Self {
+        let mut error = ApiError::new(ErrorCode::TooManyRequests, message);
+        error.retry_after = Some(retry_after);
+        HttpResult(StatusCode::TOO_MANY_REQUESTS, error)
+    }
+
+    pub fn into_response(self, state: &State) -> hyper::Response<Body> {
+        error_response(state, self.0, self.1)
+    }
+}
 
 impl IntoHttpError for Option<hyper::Response<Body>> {
     fn into_http_result(self, state: &State) -> Result<hyper::Response<Body>, hyper::Response<Body>> {
-        self.ok_or_else(|| create_response(state, StatusCode::NOT_FOUND, mime::APPLICATION_JSON, Body::empty()))
+        self.ok_or_else(|| HttpResult::from(StatusCode::NOT_FOUND).into_response(state))
     }
 }
 
 impl IntoHttpError for Result<hyper::Response<Body>, HttpResult> {
     fn into_http_result(self, state: &State) -> Result<hyper::Response<Body>, hyper::Response<Body>> {
-        self.map_err(|e| {
-            let HttpResult(status, mime, body) = e;
-            create_response(state, status, mime, body)
-        })
+        self.map_err(|e| e.into_response(state))
     }
 }
 
 impl From<bcrypt::BcryptError> for HttpResult {
     fn from(_: bcrypt::BcryptError) -> Self {
-        HttpResult(StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, Body::empty())
+        HttpResult::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Could not hash the password")
     }
 }
 
 impl From<StatusCode> for HttpResult {
     fn from(status: StatusCode) -> Self {
-        HttpResult(status, mime::APPLICATION_JSON, Body::empty())
+        let code = match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::ValidationFailed,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+            s if s.is_server_error() => ErrorCode::Internal,
+            _ => ErrorCode::BadRequest,
+        };
+        HttpResult::new(status, code, status.canonical_reason().unwrap_or("Request failed"))
     }
 }
 
 impl From<serde_json::Error> for HttpResult {
-    fn from(_: serde_json::Error) -> Self {
-        HttpResult(StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, Body::empty())
+    fn from(e: serde_json::Error) -> Self {
+        HttpResult::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, e.to_string())
     }
 }
 
 impl From<hyper::http::header::ToStrError> for HttpResult {
-    fn from(_: hyper::http::header::ToStrError) -> Self {
-        HttpResult(StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, Body::empty())
+    fn from(e: hyper::http::header::ToStrError) -> Self {
+        bad_request(e)
     }
 }
 
 impl From<frank_jwt::Error> for HttpResult {
     fn from(_: frank_jwt::Error) -> Self {
-        HttpResult(StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, Body::empty())
+        HttpResult::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Invalid or expired token")
+    }
+}
+
+impl From<AuthError> for HttpResult {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::Jwt(e) => From::from(e),
+            AuthError::Revoked =>
+                HttpResult::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Token has been revoked"),
+            AuthError::Malformed | AuthError::UnknownKey =>
+                HttpResult::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Invalid token"),
+            AuthError::Db(e) => From::from(e),
+        }
     }
 }
+
+impl From<DbError> for HttpResult {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::UniqueViolation(_) =>
+                HttpResult::new(StatusCode::CONFLICT, ErrorCode::Conflict, "Already exists"),
+            DbError::ForeignKeyViolation(_) =>
+                HttpResult::new(StatusCode::NOT_FOUND, ErrorCode::NotFound, "Referenced resource does not exist"),
+            DbError::NotFound => HttpResult::from(StatusCode::NOT_FOUND),
+            DbError::Connection(_) =>
+                HttpResult::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::Unavailable,
+                                "The database is unavailable, try again later"),
+            DbError::Blocking(e) => {
+                eprintln!("Blocking outside of the thread pool: {}", e);
+                HttpResult::from(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            DbError::Other(e) => {
+                eprintln!("Database error: {}", e);
+                HttpResult::from(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for HttpResult {
+    fn from(e: std::io::Error) -> Self {
+        eprintln!("I/O error: {}", e);
+        HttpResult::from(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
 impl From<std::num::TryFromIntError> for HttpResult {
-    fn from(_: std::num::TryFromIntError) -> Self {
-        HttpResult(StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, Body::empty())
+    fn from(e: std::num::TryFromIntError) -> Self {
+        bad_request(e)
     }
 }
 
 impl From<std::option::NoneError> for HttpResult {
     fn from(_: std::option::NoneError) -> Self {
-        HttpResult(StatusCode::NOT_FOUND, mime::APPLICATION_JSON, Body::empty())
+        HttpResult::from(StatusCode::NOT_FOUND)
     }
 }
 
 pub fn with_json<F, T, I>(mut state: State, action: F) -> Box<HandlerFuture>
 where T: serde::de::DeserializeOwned + Send + Sync + 'static,
-      F: FnOnce(&mut State, T) -> I + Send + Sync + 'static,
+      F: FnOnce(&mut State, T) -> I + Send + 'static,
       I: IntoHttpError {
     let f = extract_json::<T>(&mut state)
-        .then(|req| {
+        .then(move |req| blocking_response(state, move |mut state| {
             let body = match req {
                 Ok(req) => req,
-                Err(_) => {
-                    let resp = create_response(&state, StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, Body::empty());
-                    return Ok((state, resp))
+                Err(e) => {
+                    let resp = e.into_response(&state);
+                    return (state, resp)
                 }
             };
             match action(&mut state, body).into_http_result(&state) {
-                Ok(res) | Err(res) => Ok((state, res))
+                Ok(res) | Err(res) => (state, res)
             }
-        });
+        }));
     Box::new(f)
 }
+
+/// Like `with_json`, for handlers without a request body
+pub fn respond<I: IntoHttpError>(state: State, result: I) -> (State, hyper::Response<Body>) {
+    match result.into_http_result(&state) {
+        Ok(res) | Err(res) => (state, res)
+    }
+}
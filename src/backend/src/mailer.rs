@@ -0,0 +1,123 @@
+use chrono::Utc;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::blob_store;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl fmt::Display for Mail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Line breaks in the headers would start new ones
+        let header = |value: &str| value.replace(|c| c == '\r' || c == '\n', " ");
+        write!(f, "To: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+               header(&self.to), header(&self.subject), self.body.replace('\n', "\r\n"))
+    }
+}
+
+/// `address` trimmed and lowercased, or `None` if it doesn't look like an
+/// address mail can be sent to. Quoted local parts and the like are
+/// rejected along with typos, nobody signs up with them.
+pub fn normalize_address(address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    let special = |c: char| c.is_whitespace() || c.is_control() || "<>()[],;:\\\"".contains(c);
+    if address.len() > 254 || address.chars().any(special) {
+        return None;
+    }
+    let mut parts = address.split('@');
+    let (local, domain) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() || local.is_empty() || local.len() > 64 {
+        return None;
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    let invalid = |label: &&str| label.is_empty() || label.starts_with('-') || label.ends_with('-');
+    if labels.len() < 2 || labels.iter().any(invalid) {
+        return None;
+    }
+    Some(address)
+}
+
+/// How mails to users, such as password reset links, are sent
+pub trait Mailer: Send + Sync + fmt::Debug {
+    fn send(&self, mail: &Mail) -> io::Result<()>;
+}
+
+pub type Outbox = Arc<dyn Mailer>;
+
+/// Prints mails instead of sending them, for local use
+#[derive(Debug)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, mail: &Mail) -> io::Result<()> {
+        println!("Mail:\n{}\n", mail);
+        Ok(())
+    }
+}
+
+/// Writes every mail into an `.eml` file of its own, for local use or for
+/// something else to pick up and send
+#[derive(Debug)]
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    /// Use `dir`, creating it if it doesn't exist
+    pub fn new(dir: &Path) -> io::Result<FileMailer> {
+        fs::create_dir_all(dir)?;
+        Ok(FileMailer { dir: dir.to_path_buf() })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> io::Result<()> {
+        let name = format!("{}-{}.eml", Utc::now().format("%Y%m%dT%H%M%S"), blob_store::new_key());
+        fs::write(self.dir.join(name), mail.to_string())
+    }
+}
+
+/// Keeps the mails, for tests to read
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryMailer {
+    sent: std::sync::Mutex<Vec<Mail>>,
+}
+
+#[cfg(test)]
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Mailer for MemoryMailer {
+    fn send(&self, mail: &Mail) -> io::Result<()> {
+        self.sent.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_normalized() {
+        assert_eq!(normalize_address(" Alice@Example.COM "), Some("alice@example.com".to_string()));
+        assert_eq!(normalize_address("a.b+forum@mail.example.org"), Some("a.b+forum@mail.example.org".to_string()));
+        for invalid in &["alice", "@example.com", "alice@", "alice@localhost", "a@b@example.com", "alice@example..com",
+                         "alice smith@example.com", "<alice@example.com>", "alice@-example.com"] {
+            assert_eq!(normalize_address(invalid), None, "{}", invalid);
+        }
+    }
+}
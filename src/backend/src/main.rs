@@ -1,23 +1,62 @@
 #![feature(proc_macro_hygiene, decl_macro, type_alias_impl_trait, try_trait)]
 
-#[macro_use]
-extern crate lazy_static;
 #[macro_use]
 extern crate gotham_derive;
 #[macro_use]
 extern crate serde_json;
 
+use std::sync::Arc;
+use structopt::StructOpt;
+
+mod attachments;
 mod auth;
+mod auth_middleware;
+mod blob_store;
+mod config;
 mod db;
 mod db_traits;
+mod events;
 #[macro_use]
 mod handler_utils;
+mod mailer;
+mod markdown;
+#[cfg(test)]
+mod memory_store;
+mod migrate;
+mod password;
+mod rate_limit;
 mod router;
+mod sse;
+mod store;
+#[cfg(test)]
+mod testing;
+mod totp;
+mod websocket;
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let state = router::S::new();
-    let addr = "127.0.0.1:7878";
-    db::DB_CONNECTION.get()?; // Apply migrations
+    let opt = config::Opt::from_args();
+    let config = config::Config::load(&opt)?;
+    if let Some(config::Command::Migrate(command)) = &opt.command {
+        return migrate::run(&config, command);
+    }
+    if config.uses_dev_jwt_secret() {
+        eprintln!("WARNING: tokens are signed with the public default jwt_secret, anyone can forge them. \
+                   Set FSTACK_JWT_SECRET or jwt_keys; release builds refuse to start like this.");
+    }
+    let addr = config.bind_address.clone();
+    let database = db::get_db_connection(&config)?; // Checks that migrations are applied
+    let blobs = blob_store::LocalBlobStore::new(&config.attachment_dir)?;
+    let limits: rate_limit::Limits = match config.rate_limits.store {
+        config::LimitStoreKind::Memory => Arc::new(rate_limit::MemoryLimits::default()),
+        config::LimitStoreKind::Postgres => Arc::new(rate_limit::PostgresLimits(database.clone())),
+    };
+    let mailer: mailer::Outbox = match config.mailer {
+        config::MailerKind::Log => Arc::new(mailer::LogMailer),
+        config::MailerKind::File => Arc::new(mailer::FileMailer::new(&config.mail_dir)?),
+    };
+    let store = Arc::new(store::Postgres(database.clone()));
+    let state = router::S::new(config, store, Arc::new(blobs), limits, mailer)?;
+    events::spawn_listener(state.config.clone(), database, state.events.clone());
     println!("Listening for requests at http://{}", addr);
     Ok(gotham::start(addr, router::router(state)))
 }
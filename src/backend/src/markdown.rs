@@ -0,0 +1,87 @@
+use ammonia::Builder;
+use pulldown_cmark::{Options, Parser, html};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// Tags messages may contain. Headings stay out so that a message can't
+/// outshout the thread title.
+const TAGS: &[&str] = &[
+    "a", "blockquote", "br", "code", "del", "em", "hr", "li", "ol", "p", "pre", "strong",
+    "table", "tbody", "td", "th", "thead", "tr", "ul",
+];
+
+const URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Only the `language-<name>` classes of code blocks, so messages can't put
+/// the classes of the site itself on their content
+fn language_class(value: &str) -> Option<Cow<str>> {
+    let classes: Vec<&str> = value.split_whitespace()
+        .filter(|class| class.starts_with("language-") && class.len() > "language-".len())
+        .filter(|class| class.chars().all(|c| c.is_ascii_alphanumeric() || "-_+#.".contains(c)))
+        .collect();
+    if classes.is_empty() {
+        None
+    } else {
+        Some(classes.join(" ").into())
+    }
+}
+
+fn sanitizer() -> Builder<'static> {
+    let mut attributes = HashMap::new();
+    attributes.insert("a", ["href", "title"].iter().cloned().collect());
+    // Fenced code blocks get a `language-<name>` class, see `language_class`
+    attributes.insert("code", ["class"].iter().cloned().collect());
+    attributes.insert("ol", ["start"].iter().cloned().collect());
+    attributes.insert("td", ["align"].iter().cloned().collect());
+    attributes.insert("th", ["align"].iter().cloned().collect());
+
+    let mut builder = Builder::default();
+    builder
+        .tags(TAGS.iter().cloned().collect())
+        .tag_attributes(attributes)
+        .generic_attributes(HashSet::new())
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("code", "class") => language_class(value),
+            _ => Some(value.into()),
+        })
+        .url_schemes(URL_SCHEMES.iter().cloned().collect())
+        .link_rel(Some("nofollow noopener noreferrer"));
+    builder
+}
+
+/// Render CommonMark message content to HTML that is safe to show as is.
+/// Raw HTML in the source is sanitized along with the rest.
+pub fn render(source: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    let mut unsafe_html = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, Parser::new_ext(source, options));
+    sanitizer().clean(&unsafe_html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_commonmark() {
+        assert_eq!(render("Some *emphasis*"), "<p>Some <em>emphasis</em></p>\n");
+        assert!(render("- a\n- b").contains("<ul>\n<li>a</li>\n<li>b</li>\n</ul>"));
+        assert!(render("```rust\nfn main() {}\n```").contains("<pre><code class=\"language-rust\">fn main() {}"));
+        assert!(render("[docs](https://docs.rs)")
+            .contains("<a href=\"https://docs.rs\" rel=\"nofollow noopener noreferrer\">docs</a>"));
+    }
+
+    #[test]
+    fn strips_what_isnt_allowed() {
+        for source in &["<script>alert(1)</script>", "<img src=x onerror=alert(1)>",
+                        "[click](javascript:alert(1))", "<p onclick=\"alert(1)\">hi</p>"] {
+            let rendered = render(source);
+            assert!(!rendered.contains("alert"), "{} rendered to {}", source, rendered);
+        }
+        assert!(!render("# Title").contains("<h1>"));
+        assert_eq!(render("<code class=\"fixed-top alert\">x</code>"), "<p><code>x</code></p>\n");
+        assert!(render("<code class=\"language-c fixed-top\">x</code>").contains("<code class=\"language-c\">"));
+    }
+}
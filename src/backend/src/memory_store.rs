@@ -0,0 +1,915 @@
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use types::{Account, Attachment, Message, MessageRevision, Page, Role, SearchResult, SnippetPart, Thread,
+            ThreadSort};
+
+use crate::db::{AccountEmail, Credentials, Cursor, DbError, NewAttachment, NewRefreshToken, Ownership, PageRequest,
+                RefreshToken, StoredAttachment, ThreadKey, Totp, into_page};
+use crate::store::{Backend, ForumStore, StoreTransaction};
+
+#[derive(Clone, Debug)]
+struct AccountRow {
+    username: String,
+    email: Option<String>,
+    email_verified: bool,
+    password: String,
+    role: Role,
+    banned: bool,
+    totp_secret: Option<Vec<u8>>,
+    totp_enabled: bool,
+    totp_last_step: Option<i64>,
+}
+
+impl AccountRow {
+    fn credentials(&self, id: i32) -> Credentials {
+        Credentials {
+            id,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            banned: self.banned,
+            email: self.email.clone(),
+            email_verified: self.email_verified,
+            totp_enabled: self.totp_enabled,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ThreadRow {
+    creator: i32,
+    title: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    locked: bool,
+    deleted: bool,
+}
+
+#[derive(Clone, Debug)]
+struct MessageRow {
+    thread_id: i32,
+    creator: i32,
+    content: String,
+    content_html: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    edited_at: Option<DateTime<Utc>>,
+    parent_id: Option<i32>,
+    depth: i32,
+    deleted: bool,
+}
+
+#[derive(Clone, Debug)]
+struct RevisionRow {
+    message_id: i32,
+    thread_id: i32,
+    content: String,
+    created_at: DateTime<Utc>,
+    replaced_at: DateTime<Utc>,
+    replaced_by: i32,
+}
+
+#[derive(Clone, Debug)]
+struct AttachmentRow {
+    uploader: i32,
+    message_id: Option<i32>,
+    filename: String,
+    content_type: String,
+    size: i32,
+    blob_key: String,
+    thumbnail_key: Option<String>,
+    created_at: DateTime<Utc>,
+    /// Ids are positions in `attachments`, so deleted rows stay behind
+    deleted: bool,
+}
+
+impl AttachmentRow {
+    fn attachment(&self, id: i32) -> Attachment {
+        Attachment {
+            id,
+            filename: self.filename.clone(),
+            content_type: self.content_type.clone(),
+            size: self.size,
+            thumbnail: self.thumbnail_key.is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RefreshTokenRow {
+    account_id: i32,
+    token_hash: String,
+    family: String,
+    access_jti: String,
+    access_expires_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    used: bool,
+    revoked: bool,
+}
+
+#[derive(Clone, Debug)]
+struct PasswordResetRow {
+    account_id: i32,
+    token_hash: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Kept like password resets
+type EmailVerificationRow = PasswordResetRow;
+
+#[derive(Clone, Debug)]
+struct RecoveryCodeRow {
+    account_id: i32,
+    code_hash: String,
+}
+
+#[derive(Clone, Debug)]
+struct MfaChallengeRow {
+    account_id: i32,
+    token_hash: String,
+    expires_at: DateTime<Utc>,
+    attempts: i32,
+}
+
+/// The tables, with the id of a row being its index plus one
+#[derive(Clone, Debug, Default)]
+struct Data {
+    accounts: Vec<AccountRow>,
+    threads: Vec<ThreadRow>,
+    messages: Vec<MessageRow>,
+    revisions: Vec<RevisionRow>,
+    attachments: Vec<AttachmentRow>,
+    refresh_tokens: Vec<RefreshTokenRow>,
+    /// Rows are deleted when used, nothing refers to them by id
+    password_resets: Vec<PasswordResetRow>,
+    email_verifications: Vec<EmailVerificationRow>,
+    recovery_codes: Vec<RecoveryCodeRow>,
+    mfa_challenges: Vec<MfaChallengeRow>,
+    revoked_tokens: HashMap<String, DateTime<Utc>>,
+}
+
+fn index(id: i32) -> usize {
+    // Ids below 1 wrap around to an index that doesn't exist
+    (id as usize).wrapping_sub(1)
+}
+
+impl Data {
+    fn account(&self, id: i32) -> Result<&AccountRow, DbError> {
+        self.accounts.get(index(id)).ok_or(DbError::NotFound)
+    }
+
+    fn account_mut(&mut self, id: i32) -> Result<&mut AccountRow, DbError> {
+        self.accounts.get_mut(index(id)).ok_or(DbError::NotFound)
+    }
+
+    /// Fail like Postgres would when a row references a missing account
+    fn references_account(&self, id: i32, constraint: &str) -> Result<(), DbError> {
+        self.account(id).map(|_| ()).map_err(|_| DbError::ForeignKeyViolation(Some(constraint.to_string())))
+    }
+
+    fn username(&self, id: i32) -> String {
+        self.account(id).map(|account| account.username.clone()).unwrap_or_default()
+    }
+
+    fn thread_row(&self, id: i32) -> Result<&ThreadRow, DbError> {
+        self.threads.get(index(id)).filter(|thread| !thread.deleted).ok_or(DbError::NotFound)
+    }
+
+    fn thread_row_mut(&mut self, id: i32) -> Result<&mut ThreadRow, DbError> {
+        self.threads.get_mut(index(id)).filter(|thread| !thread.deleted).ok_or(DbError::NotFound)
+    }
+
+    fn message_row(&self, thread_id: i32, id: i32) -> Result<&MessageRow, DbError> {
+        self.messages.get(index(id))
+            .filter(|message| message.thread_id == thread_id && !message.deleted)
+            .ok_or(DbError::NotFound)
+    }
+
+    fn message_row_mut(&mut self, thread_id: i32, id: i32) -> Result<&mut MessageRow, DbError> {
+        self.messages.get_mut(index(id))
+            .filter(|message| message.thread_id == thread_id && !message.deleted)
+            .ok_or(DbError::NotFound)
+    }
+
+    /// The messages of a thread that weren't deleted, oldest first
+    fn thread_messages(&self, thread_id: i32) -> impl Iterator<Item = (i32, &MessageRow)> + '_ {
+        (1..).zip(&self.messages).filter(move |(_, message)| message.thread_id == thread_id && !message.deleted)
+    }
+
+    fn message(&self, id: i32, row: &MessageRow) -> Message {
+        Message {
+            id,
+            creator: self.username(row.creator),
+            creator_id: row.creator,
+            content: row.content.clone(),
+            content_html: row.content_html.clone(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            edited_at: row.edited_at,
+            parent_id: row.parent_id,
+            depth: row.depth,
+            attachments: (1..).zip(&self.attachments)
+                .filter(|(_, attachment)| attachment.message_id == Some(id))
+                .map(|(attachment_id, attachment)| attachment.attachment(attachment_id))
+                .collect(),
+        }
+    }
+
+    /// A thread without its messages, like `db::get_thread_summary`
+    fn thread(&self, id: i32) -> Result<Thread, DbError> {
+        let row = self.thread_row(id)?;
+        let (message_count, latest) = self.thread_messages(id)
+            .fold((0, None), |(count, _), latest| (count + 1, Some(latest)));
+        Ok(Thread {
+            id,
+            creator: self.username(row.creator),
+            creator_id: row.creator,
+            title: row.title.clone(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            message_count,
+            locked: row.locked,
+            messages: None,
+            latest_message: latest.map(|(id, message)| self.message(id, message)),
+        })
+    }
+
+    fn visible_threads(&self) -> impl Iterator<Item = Thread> + '_ {
+        (1..).zip(&self.threads)
+            .filter(|(_, thread)| !thread.deleted)
+            .filter_map(move |(id, _)| self.thread(id).ok())
+    }
+
+    /// Revoke the refresh tokens matching `filter` and the access tokens
+    /// that were issued with them
+    fn revoke_refresh_tokens<F: Fn(&RefreshTokenRow) -> bool>(&mut self, filter: F) {
+        let now = Utc::now();
+        let revoked_tokens = &mut self.revoked_tokens;
+        for token in self.refresh_tokens.iter_mut().filter(|token| filter(token)) {
+            if token.access_expires_at > now {
+                revoked_tokens.entry(token.access_jti.clone()).or_insert(token.access_expires_at);
+            }
+            token.revoked = true;
+        }
+    }
+}
+
+/// Take the rows of a page out of `items` the way `db::keyset` fetches them,
+/// for `into_page` to finish
+fn keyset_page<T, K, F>(mut items: Vec<T>, key: F, page: &PageRequest<K>, descending: bool) -> Vec<T>
+where K: PartialOrd,
+      F: Fn(&T) -> K {
+    items.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+    let backwards = match page.cursor {
+        Cursor::Before(_) => !descending,
+        Cursor::Start | Cursor::After(_) => descending,
+    };
+    if backwards {
+        items.reverse();
+    }
+    if let Some(cursor) = page.cursor.key() {
+        items.retain(|item| if backwards { key(item) < *cursor } else { key(item) > *cursor });
+    }
+    items.truncate(page.limit as usize + 1);
+    items
+}
+
+/// Split `text` into runs of alphanumeric and other characters
+fn runs(text: &str) -> Vec<&str> {
+    let mut runs = vec![];
+    let mut start = 0;
+    let mut word = None;
+    for (i, c) in text.char_indices() {
+        let is_word = c.is_alphanumeric();
+        if word.map_or(false, |word| word != is_word) {
+            runs.push(&text[start..i]);
+            start = i;
+        }
+        word = Some(is_word);
+    }
+    if start < text.len() {
+        runs.push(&text[start..]);
+    }
+    runs
+}
+
+fn is_word(run: &str) -> bool {
+    run.chars().next().map_or(false, char::is_alphanumeric)
+}
+
+/// A rough stand-in for `websearch_to_tsquery`: every word must occur, except
+/// `-excluded` ones. Quotes and `or` are ignored, and there's no stemming.
+struct Query {
+    words: HashSet<String>,
+    excluded: HashSet<String>,
+}
+
+impl Query {
+    fn parse(query: &str) -> Query {
+        let mut words = HashSet::new();
+        let mut excluded = HashSet::new();
+        for term in query.split_whitespace().filter(|term| !term.eq_ignore_ascii_case("or")) {
+            let target = if term.starts_with('-') { &mut excluded } else { &mut words };
+            target.extend(runs(term).into_iter().filter(|run| is_word(run)).map(str::to_lowercase));
+        }
+        Query { words, excluded }
+    }
+
+    /// The rank of `text`, if it matches
+    fn rank(&self, text: &str) -> Option<f32> {
+        let words: Vec<String> = runs(text).into_iter().filter(|run| is_word(run)).map(str::to_lowercase).collect();
+        let found: HashSet<&String> = words.iter().filter(|word| self.words.contains(*word)).collect();
+        if self.words.is_empty() || found.len() < self.words.len() || words.iter().any(|w| self.excluded.contains(w)) {
+            return None;
+        }
+        let hits = words.iter().filter(|word| self.words.contains(*word)).count();
+        Some(hits as f32 / words.len() as f32)
+    }
+
+    fn snippet(&self, text: &str) -> Vec<SnippetPart> {
+        let mut parts: Vec<SnippetPart> = vec![];
+        for run in runs(text) {
+            let highlighted = is_word(run) && self.words.contains(&run.to_lowercase());
+            match parts.last_mut() {
+                Some(part) if !part.highlighted && !highlighted => part.text.push_str(run),
+                _ => parts.push(SnippetPart { text: run.to_string(), highlighted }),
+            }
+        }
+        parts
+    }
+}
+
+/// Keeps everything in memory, so the HTTP API can be run without Postgres,
+/// as in tests. A transaction works on a copy of the data, which replaces
+/// the original when committed: changes made outside of the transaction in
+/// the meantime are lost.
+#[derive(Debug, Default)]
+pub struct MemoryStore<'a> {
+    data: Mutex<Data>,
+    /// The store a transaction was started on
+    parent: Option<&'a MemoryStore<'a>>,
+}
+
+impl MemoryStore<'_> {
+    fn lock(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().unwrap()
+    }
+}
+
+impl Backend for MemoryStore<'static> {
+    fn begin(&self) -> Result<Box<dyn StoreTransaction + '_>, DbError> {
+        Ok(Box::new(MemoryStore { data: Mutex::new(self.lock().clone()), parent: Some(self) }))
+    }
+}
+
+impl StoreTransaction for MemoryStore<'_> {
+    fn commit(self: Box<Self>) -> Result<(), DbError> {
+        if let Some(parent) = self.parent {
+            *parent.lock() = self.data.into_inner().unwrap();
+        }
+        Ok(())
+    }
+}
+
+impl ForumStore for MemoryStore<'_> {
+    fn create_account(&self, username: &str, email: &str, password: &str) -> Result<i32, DbError> {
+        let mut data = self.lock();
+        if data.accounts.iter().any(|account| account.username == username) {
+            return Err(DbError::UniqueViolation(Some("account_username_key".to_string())));
+        }
+        if data.accounts.iter().any(|account| account.email.as_ref().map(String::as_str) == Some(email)) {
+            return Err(DbError::UniqueViolation(Some("account_email_key".to_string())));
+        }
+        data.accounts.push(AccountRow {
+            username: username.to_string(),
+            email: Some(email.to_string()),
+            email_verified: false,
+            password: password.to_string(),
+            role: Role::User,
+            banned: false,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+        });
+        Ok(data.accounts.len() as i32)
+    }
+
+    fn get_credentials(&self, username: &str) -> Result<Credentials, DbError> {
+        (1..).zip(&self.lock().accounts)
+            .find(|(_, account)| account.username == username)
+            .map(|(id, account)| account.credentials(id))
+            .ok_or(DbError::NotFound)
+    }
+
+    fn get_credentials_by_email(&self, email: &str) -> Result<Credentials, DbError> {
+        (1..).zip(&self.lock().accounts)
+            .find(|(_, account)| account.email.as_ref().map(String::as_str) == Some(email))
+            .map(|(id, account)| account.credentials(id))
+            .ok_or(DbError::NotFound)
+    }
+
+    fn update_last_logged_in(&self, _username: &str) -> Result<(), DbError> {
+        // Nothing reads it back
+        Ok(())
+    }
+
+    fn get_account(&self, id: i32) -> Result<Account, DbError> {
+        let data = self.lock();
+        let account = data.account(id)?;
+        Ok(Account { id, username: account.username.clone(), role: account.role, banned: account.banned })
+    }
+
+    fn get_role(&self, id: i32) -> Result<Role, DbError> {
+        Ok(self.lock().account(id)?.role)
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), DbError> {
+        self.lock().account_mut(id)?.role = role;
+        Ok(())
+    }
+
+    fn set_banned(&self, id: i32, banned: bool, _reason: Option<&str>) -> Result<(), DbError> {
+        self.lock().account_mut(id)?.banned = banned;
+        Ok(())
+    }
+
+    fn set_password(&self, id: i32, password: &str) -> Result<(), DbError> {
+        self.lock().account_mut(id)?.password = password.to_string();
+        Ok(())
+    }
+
+    fn create_password_reset(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError> {
+        let mut data = self.lock();
+        data.references_account(account_id, "password_reset_account_id_fkey")?;
+        data.password_resets.push(PasswordResetRow { account_id, token_hash: token_hash.to_string(), expires_at });
+        Ok(())
+    }
+
+    fn use_password_reset(&self, token_hash: &str) -> Result<i32, DbError> {
+        let mut data = self.lock();
+        let now = Utc::now();
+        let account_id = data.password_resets.iter()
+            .find(|reset| reset.token_hash == token_hash && reset.expires_at > now)
+            .ok_or(DbError::NotFound)?
+            .account_id;
+        data.password_resets.retain(|reset| reset.account_id != account_id);
+        Ok(account_id)
+    }
+
+    fn get_email(&self, id: i32) -> Result<AccountEmail, DbError> {
+        let data = self.lock();
+        let account = data.account(id)?;
+        Ok(AccountEmail { email: account.email.clone(), verified: account.email_verified })
+    }
+
+    fn create_email_verification(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError> {
+        let mut data = self.lock();
+        data.references_account(account_id, "email_verification_account_id_fkey")?;
+        data.email_verifications.push(EmailVerificationRow {
+            account_id,
+            token_hash: token_hash.to_string(),
+            expires_at,
+        });
+        Ok(())
+    }
+
+    fn verify_email(&self, token_hash: &str) -> Result<i32, DbError> {
+        let mut data = self.lock();
+        let now = Utc::now();
+        let account_id = data.email_verifications.iter()
+            .find(|verification| verification.token_hash == token_hash && verification.expires_at > now)
+            .ok_or(DbError::NotFound)?
+            .account_id;
+        data.email_verifications.retain(|verification| verification.account_id != account_id);
+        data.account_mut(account_id)?.email_verified = true;
+        Ok(account_id)
+    }
+
+    fn get_totp(&self, account_id: i32) -> Result<Totp, DbError> {
+        let data = self.lock();
+        let account = data.account(account_id)?;
+        Ok(Totp {
+            secret: account.totp_secret.clone(),
+            enabled: account.totp_enabled,
+            last_step: account.totp_last_step,
+            recovery_codes_left: data.recovery_codes.iter().filter(|code| code.account_id == account_id).count() as i64,
+        })
+    }
+
+    fn set_totp_secret(&self, account_id: i32, secret: Option<&[u8]>) -> Result<(), DbError> {
+        let mut data = self.lock();
+        let account = data.account_mut(account_id)?;
+        account.totp_secret = secret.map(<[u8]>::to_vec);
+        account.totp_enabled = false;
+        account.totp_last_step = None;
+        Ok(())
+    }
+
+    fn enable_totp(&self, account_id: i32) -> Result<(), DbError> {
+        let mut data = self.lock();
+        let account = data.account_mut(account_id)?;
+        if account.totp_secret.is_none() {
+            return Err(DbError::NotFound);
+        }
+        account.totp_enabled = true;
+        Ok(())
+    }
+
+    fn use_totp_step(&self, account_id: i32, step: i64) -> Result<(), DbError> {
+        let mut data = self.lock();
+        let account = data.account_mut(account_id)?;
+        if account.totp_last_step.map_or(false, |last| last >= step) {
+            return Err(DbError::NotFound);
+        }
+        account.totp_last_step = Some(step);
+        Ok(())
+    }
+
+    fn set_recovery_codes(&self, account_id: i32, code_hashes: &[String]) -> Result<(), DbError> {
+        let mut data = self.lock();
+        data.references_account(account_id, "recovery_code_account_id_fkey")?;
+        data.recovery_codes.retain(|code| code.account_id != account_id);
+        data.recovery_codes.extend(code_hashes.iter().map(|code_hash| RecoveryCodeRow {
+            account_id,
+            code_hash: code_hash.clone(),
+        }));
+        Ok(())
+    }
+
+    fn use_recovery_code(&self, account_id: i32, code_hash: &str) -> Result<(), DbError> {
+        let mut data = self.lock();
+        let before = data.recovery_codes.len();
+        data.recovery_codes.retain(|code| code.account_id != account_id || code.code_hash != code_hash);
+        if data.recovery_codes.len() == before { Err(DbError::NotFound) } else { Ok(()) }
+    }
+
+    fn create_mfa_challenge(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError> {
+        let mut data = self.lock();
+        data.references_account(account_id, "mfa_challenge_account_id_fkey")?;
+        data.mfa_challenges.push(MfaChallengeRow {
+            account_id,
+            token_hash: token_hash.to_string(),
+            expires_at,
+            attempts: 0,
+        });
+        Ok(())
+    }
+
+    fn attempt_mfa_challenge(&self, token_hash: &str, max_attempts: i32) -> Result<i32, DbError> {
+        let mut data = self.lock();
+        let now = Utc::now();
+        let challenge = data.mfa_challenges.iter_mut()
+            .find(|challenge| challenge.token_hash == token_hash && challenge.expires_at > now
+                  && challenge.attempts < max_attempts)
+            .ok_or(DbError::NotFound)?;
+        challenge.attempts += 1;
+        Ok(challenge.account_id)
+    }
+
+    fn delete_mfa_challenges(&self, account_id: i32) -> Result<(), DbError> {
+        self.lock().mfa_challenges.retain(|challenge| challenge.account_id != account_id);
+        Ok(())
+    }
+
+    fn create_thread(&self, account_id: i32, title: &str) -> Result<(), DbError> {
+        let mut data = self.lock();
+        data.references_account(account_id, "thread_creator_fkey")?;
+        let now = Utc::now();
+        data.threads.push(ThreadRow {
+            creator: account_id,
+            title: title.to_string(),
+            created_at: now,
+            updated_at: now,
+            locked: false,
+            deleted: false,
+        });
+        Ok(())
+    }
+
+    fn get_threads(&self, sort: ThreadSort, page: &PageRequest<ThreadKey>) -> Result<Page<Thread>, DbError> {
+        let threads = self.lock().visible_threads().collect();
+        let descending = sort != ThreadSort::Title;
+        let threads = keyset_page(threads, |thread| ThreadKey::of(thread, sort), page, descending);
+        Ok(into_page(threads, page, |thread| ThreadKey::of(thread, sort).to_cursor()))
+    }
+
+    fn get_thread(&self, id: i32, page: &PageRequest) -> Result<Thread, DbError> {
+        let data = self.lock();
+        let mut thread = data.thread(id)?;
+        let messages = data.thread_messages(id).map(|(id, message)| data.message(id, message)).collect();
+        let messages = keyset_page(messages, |message: &Message| message.id, page, false);
+        thread.messages = Some(into_page(messages, page, |message| message.id.to_string()));
+        Ok(thread)
+    }
+
+    fn get_threads_created_after(&self, id: i32, limit: i64) -> Result<Vec<Thread>, DbError> {
+        Ok(self.lock().visible_threads()
+            .filter(|thread| thread.id > id)
+            .take(limit as usize)
+            .collect())
+    }
+
+    fn get_thread_summary(&self, id: i32) -> Result<Thread, DbError> {
+        self.lock().thread(id)
+    }
+
+    fn get_thread_ownership(&self, id: i32) -> Result<Ownership, DbError> {
+        let data = self.lock();
+        let thread = data.thread_row(id)?;
+        Ok(Ownership { creator: thread.creator, locked: thread.locked })
+    }
+
+    fn set_thread_locked(&self, id: i32, locked: bool) -> Result<(), DbError> {
+        self.lock().thread_row_mut(id)?.locked = locked;
+        Ok(())
+    }
+
+    fn update_thread(&self, id: i32, title: &str) -> Result<(), DbError> {
+        self.lock().thread_row_mut(id)?.title = title.to_string();
+        Ok(())
+    }
+
+    fn delete_thread(&self, id: i32) -> Result<(), DbError> {
+        self.lock().thread_row_mut(id)?.deleted = true;
+        Ok(())
+    }
+
+    fn search(&self, query: &str, limit: i64) -> Result<Vec<SearchResult>, DbError> {
+        let data = self.lock();
+        let query = Query::parse(query);
+        let result = |thread_id: i32, message_id, text: &str, creator, created_at| {
+            let rank = query.rank(text)?;
+            Some(SearchResult {
+                thread_id,
+                thread_title: data.thread_row(thread_id).ok()?.title.clone(),
+                message_id,
+                creator: data.username(creator),
+                created_at,
+                rank,
+                snippet: query.snippet(text),
+            })
+        };
+        let threads = (1..).zip(&data.threads)
+            .filter(|(_, thread)| !thread.deleted)
+            .filter_map(|(id, thread)| result(id, None, &thread.title, thread.creator, thread.created_at));
+        let messages = (1..).zip(&data.messages)
+            .filter(|(_, message)| !message.deleted)
+            .filter_map(|(id, message)| result(message.thread_id, Some(id), &message.content,
+                                               message.creator, message.created_at));
+        let mut results: Vec<SearchResult> = threads.chain(messages).collect();
+        results.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap_or(Ordering::Equal)
+            .then(b.created_at.cmp(&a.created_at)));
+        results.truncate(limit as usize);
+        Ok(results)
+    }
+
+    fn get_message(&self, thread_id: i32, id: i32) -> Result<Message, DbError> {
+        let data = self.lock();
+        let message = data.message_row(thread_id, id)?;
+        Ok(data.message(id, message))
+    }
+
+    fn create_message(&self, account_id: i32, thread_id: i32, content: &str, content_html: &str,
+                      parent_id: Option<i32>) -> Result<i32, DbError> {
+        let mut data = self.lock();
+        data.thread_row(thread_id)?;
+        data.references_account(account_id, "message_creator_fkey")?;
+        let depth = match parent_id {
+            Some(parent_id) => data.message_row(thread_id, parent_id)?.depth + 1,
+            None => 0,
+        };
+        let now = Utc::now();
+        data.messages.push(MessageRow {
+            thread_id,
+            creator: account_id,
+            content: content.to_string(),
+            content_html: content_html.to_string(),
+            created_at: now,
+            updated_at: now,
+            edited_at: None,
+            parent_id,
+            depth,
+            deleted: false,
+        });
+        data.thread_row_mut(thread_id)?.updated_at = now;
+        Ok(data.messages.len() as i32)
+    }
+
+    fn get_message_ownership(&self, thread_id: i32, id: i32) -> Result<Ownership, DbError> {
+        let data = self.lock();
+        let message = data.message_row(thread_id, id)?;
+        let thread = data.thread_row(thread_id)?;
+        Ok(Ownership { creator: message.creator, locked: thread.locked })
+    }
+
+    fn update_message(&self, account_id: i32, thread_id: i32, id: i32, content: &str, content_html: &str)
+        -> Result<(), DbError> {
+        let mut data = self.lock();
+        let now = Utc::now();
+        let message = data.message_row_mut(thread_id, id)?;
+        let revision = RevisionRow {
+            message_id: id,
+            thread_id,
+            content: std::mem::replace(&mut message.content, content.to_string()),
+            created_at: message.edited_at.unwrap_or(message.created_at),
+            replaced_at: now,
+            replaced_by: account_id,
+        };
+        message.content_html = content_html.to_string();
+        message.edited_at = Some(now);
+        message.updated_at = now;
+        data.revisions.push(revision);
+        Ok(())
+    }
+
+    fn delete_message(&self, thread_id: i32, id: i32) -> Result<(), DbError> {
+        self.lock().message_row_mut(thread_id, id)?.deleted = true;
+        Ok(())
+    }
+
+    fn get_message_revisions(&self, thread_id: i32, id: i32) -> Result<Vec<MessageRevision>, DbError> {
+        let data = self.lock();
+        Ok(data.revisions.iter()
+            .filter(|revision| revision.message_id == id && revision.thread_id == thread_id)
+            .map(|revision| MessageRevision {
+                content: revision.content.clone(),
+                created_at: revision.created_at,
+                replaced_at: revision.replaced_at,
+                replaced_by: data.username(revision.replaced_by),
+            })
+            .collect())
+    }
+
+    fn create_attachment(&self, attachment: &NewAttachment) -> Result<Attachment, DbError> {
+        let mut data = self.lock();
+        data.references_account(attachment.uploader, "attachment_uploader_fkey")?;
+        let row = AttachmentRow {
+            uploader: attachment.uploader,
+            message_id: None,
+            filename: attachment.filename.to_string(),
+            content_type: attachment.content_type.to_string(),
+            size: attachment.size,
+            blob_key: attachment.blob_key.to_string(),
+            thumbnail_key: attachment.thumbnail_key.map(str::to_string),
+            created_at: Utc::now(),
+            deleted: false,
+        };
+        let created = row.attachment(data.attachments.len() as i32 + 1);
+        data.attachments.push(row);
+        Ok(created)
+    }
+
+    fn get_attachment(&self, id: i32) -> Result<StoredAttachment, DbError> {
+        let data = self.lock();
+        let row = data.attachments.get(index(id)).filter(|row| !row.deleted).ok_or(DbError::NotFound)?;
+        if let Some(message_id) = row.message_id {
+            let message = data.messages.get(index(message_id)).ok_or(DbError::NotFound)?;
+            data.message_row(message.thread_id, message_id)?;
+            data.thread_row(message.thread_id)?;
+        }
+        Ok(StoredAttachment {
+            attachment: row.attachment(id),
+            uploader: row.uploader,
+            message_id: row.message_id,
+            blob_key: row.blob_key.clone(),
+            thumbnail_key: row.thumbnail_key.clone(),
+        })
+    }
+
+    fn attach(&self, account_id: i32, message_id: i32, ids: &[i32]) -> Result<(), DbError> {
+        let mut data = self.lock();
+        let mut attached = 0;
+        for (id, row) in (1..).zip(data.attachments.iter_mut()) {
+            if ids.contains(&id) && row.uploader == account_id && row.message_id.is_none() && !row.deleted {
+                row.message_id = Some(message_id);
+                attached += 1;
+            }
+        }
+        if attached == ids.len() { Ok(()) } else { Err(DbError::NotFound) }
+    }
+
+    fn delete_unattached(&self, created_before: DateTime<Utc>) -> Result<Vec<String>, DbError> {
+        let mut data = self.lock();
+        let mut keys = Vec::new();
+        for row in data.attachments.iter_mut() {
+            if row.message_id.is_none() && row.created_at < created_before && !row.deleted {
+                row.deleted = true;
+                keys.push(row.blob_key.clone());
+                keys.extend(row.thumbnail_key.clone());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn create_refresh_token(&self, token: &NewRefreshToken) -> Result<(), DbError> {
+        let mut data = self.lock();
+        data.references_account(token.account_id, "refresh_token_account_id_fkey")?;
+        data.refresh_tokens.push(RefreshTokenRow {
+            account_id: token.account_id,
+            token_hash: token.token_hash.to_string(),
+            family: token.family.to_string(),
+            access_jti: token.access_jti.to_string(),
+            access_expires_at: token.access_expires_at,
+            expires_at: token.expires_at,
+            used: false,
+            revoked: false,
+        });
+        Ok(())
+    }
+
+    fn get_refresh_token(&self, token_hash: &str) -> Result<RefreshToken, DbError> {
+        (1..).zip(&self.lock().refresh_tokens)
+            .find(|(_, token)| token.token_hash == token_hash)
+            .map(|(id, token)| RefreshToken {
+                id,
+                account_id: token.account_id,
+                family: token.family.clone(),
+                expires_at: token.expires_at,
+                used: token.used,
+                revoked: token.revoked,
+            })
+            .ok_or(DbError::NotFound)
+    }
+
+    fn mark_refresh_token_used(&self, id: i32) -> Result<bool, DbError> {
+        match self.lock().refresh_tokens.get_mut(index(id)) {
+            Some(token) if !token.used => {
+                token.used = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn revoke_refresh_token_family(&self, family: &str) -> Result<(), DbError> {
+        self.lock().revoke_refresh_tokens(|token| token.family == family);
+        Ok(())
+    }
+
+    fn revoke_account_tokens(&self, account_id: i32) -> Result<(), DbError> {
+        self.lock().revoke_refresh_tokens(|token| token.account_id == account_id);
+        Ok(())
+    }
+
+    fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), DbError> {
+        let mut data = self.lock();
+        let now = Utc::now();
+        data.revoked_tokens.retain(|_, until| *until >= now);
+        data.revoked_tokens.entry(jti.to_string()).or_insert(expires_at);
+        Ok(())
+    }
+
+    fn is_token_revoked(&self, jti: &str) -> Result<bool, DbError> {
+        Ok(self.lock().revoked_tokens.contains_key(jti))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(cursor: Cursor, limit: i64) -> PageRequest {
+        PageRequest { cursor, limit }
+    }
+
+    #[test]
+    fn pages_like_keyset() {
+        let ids = vec![3, 1, 4, 2, 5];
+        assert_eq!(keyset_page(ids.clone(), |i| *i, &page(Cursor::Start, 2), true), vec![5, 4, 3]);
+        assert_eq!(keyset_page(ids.clone(), |i| *i, &page(Cursor::After(4), 2), true), vec![3, 2, 1]);
+        // Fetched in reverse, like `keyset` does, for `into_page` to flip
+        assert_eq!(keyset_page(ids.clone(), |i| *i, &page(Cursor::Before(2), 2), true), vec![3, 4, 5]);
+        assert_eq!(keyset_page(ids, |i| *i, &page(Cursor::After(4), 2), false), vec![5]);
+    }
+
+    #[test]
+    fn searches_words() {
+        let query = Query::parse("Rust -java");
+        assert!(query.rank("I like rust.").is_some());
+        assert!(query.rank("Rust or java?").is_none());
+        assert!(query.rank("rusty").is_none());
+
+        let part = |text: &str, highlighted| SnippetPart { text: text.to_string(), highlighted };
+        assert_eq!(query.snippet("I like Rust."), vec![part("I like ", false), part("Rust", true), part(".", false)]);
+    }
+
+    #[test]
+    fn transactions_apply_on_commit() {
+        let store = MemoryStore::default();
+        let id = store.create_account("user", "user@example.com", "hash").unwrap();
+
+        let tx = store.begin().unwrap();
+        tx.create_thread(id, "Discarded").unwrap();
+        drop(tx);
+        assert_eq!(store.get_threads_created_after(0, 10).unwrap().len(), 0);
+
+        let tx = store.begin().unwrap();
+        tx.create_thread(id, "Kept").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(store.get_threads_created_after(0, 10).unwrap()[0].title, "Kept");
+    }
+}
@@ -0,0 +1,349 @@
+use postgres::transaction::Transaction;
+use postgres::{Connection, GenericConnection, TlsMode};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use structopt::StructOpt;
+
+use crate::config::Config;
+
+/// `(file name, contents)` of every file in `migrations/`, see build.rs
+const FILES: &[(&str, &str)] = include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+/// Held while migrating, so that instances deployed at the same time don't
+/// run the same migrations concurrently. The value is arbitrary.
+const LOCK_KEY: i64 = 0x6673_7461_636b;
+
+#[derive(Debug, StructOpt)]
+pub enum Migrate {
+    /// Apply every pending migration
+    Up,
+    /// Revert the latest applied migrations
+    Down {
+        /// How many migrations to revert
+        #[structopt(long, default_value = "1")]
+        steps: usize,
+    },
+    /// List the migrations and whether they have been applied
+    Status,
+    /// Revert and reapply the latest applied migration
+    Redo,
+}
+
+/// A pair of `<version>_<name>.up.sql` and `<version>_<name>.down.sql` files
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i32,
+    pub name: String,
+    up: &'static str,
+    down: &'static str,
+}
+
+impl Migration {
+    /// Stored when the migration is applied, to notice if it's edited later
+    fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}_{}", self.version, self.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// A file isn't named `<version>_<name>.up.sql` or `.down.sql`
+    InvalidFileName(String),
+    /// The files of a version don't make up a migration
+    Invalid(i32, &'static str),
+    /// An applied migration has been changed since
+    Edited(String),
+    /// The database has a migration applied that doesn't exist here
+    Unknown(String),
+    /// The server was started with this many migrations left to apply
+    Pending(usize),
+    Db(postgres::Error),
+}
+
+impl From<postgres::Error> for MigrationError {
+    fn from(e: postgres::Error) -> Self {
+        MigrationError::Db(e)
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::InvalidFileName(name) =>
+                write!(f, "migration file {:?} isn't named <version>_<name>.up.sql or .down.sql", name),
+            MigrationError::Invalid(version, reason) =>
+                write!(f, "invalid migration {:04}: {}", version, reason),
+            MigrationError::Edited(name) =>
+                write!(f, "migration {} has been edited after it was applied", name),
+            MigrationError::Unknown(name) =>
+                write!(f, "migration {} is applied but doesn't exist, is this an older version?", name),
+            MigrationError::Pending(count) =>
+                write!(f, "{} pending migration(s), apply them with `backend migrate up`", count),
+            MigrationError::Db(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Applied,
+    Pending,
+    Edited,
+}
+
+#[derive(Debug)]
+struct Applied {
+    version: i32,
+    name: String,
+    checksum: String,
+}
+
+/// Split `0001_initial.up.sql` into its version, name and direction
+fn parse_file_name(file_name: &str) -> Option<(i32, &str, bool)> {
+    let (stem, up) = if file_name.ends_with(".up.sql") {
+        (&file_name[..file_name.len() - ".up.sql".len()], true)
+    } else if file_name.ends_with(".down.sql") {
+        (&file_name[..file_name.len() - ".down.sql".len()], false)
+    } else {
+        return None;
+    };
+    let separator = stem.find('_')?;
+    let version = stem[..separator].parse().ok()?;
+    Some((version, &stem[separator + 1..], up))
+}
+
+fn discover(files: &[(&str, &'static str)]) -> Result<Vec<Migration>, MigrationError> {
+    let mut versions: BTreeMap<i32, (&str, Option<&'static str>, Option<&'static str>)> = BTreeMap::new();
+    for &(file_name, sql) in files {
+        let (version, name, up) = parse_file_name(file_name)
+            .ok_or_else(|| MigrationError::InvalidFileName(file_name.to_string()))?;
+        let entry = versions.entry(version).or_insert((name, None, None));
+        if entry.0 != name {
+            return Err(MigrationError::Invalid(version, "more than one migration has this version"));
+        }
+        let slot = if up { &mut entry.1 } else { &mut entry.2 };
+        *slot = Some(sql);
+    }
+    versions.into_iter()
+        .map(|(version, (name, up, down))| match (up, down) {
+            (Some(up), Some(down)) => Ok(Migration { version, name: name.to_string(), up, down }),
+            (None, _) => Err(MigrationError::Invalid(version, "the .up.sql file is missing")),
+            (_, None) => Err(MigrationError::Invalid(version, "the .down.sql file is missing")),
+        })
+        .collect()
+}
+
+/// The migrations embedded from `migrations/`, oldest first
+pub fn migrations() -> Result<Vec<Migration>, MigrationError> {
+    discover(FILES)
+}
+
+/// Create the bookkeeping table. Before migrations were versioned it only
+/// had the names of the applied migrations, which are matched to the files.
+fn prepare<C: GenericConnection>(conn: &C, migrations: &[Migration]) -> Result<(), MigrationError> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS _migration (name TEXT UNIQUE); \
+         ALTER TABLE _migration \
+             ADD COLUMN IF NOT EXISTS version INTEGER UNIQUE, \
+             ADD COLUMN IF NOT EXISTS checksum TEXT, \
+             ADD COLUMN IF NOT EXISTS applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();")?;
+    for row in &conn.query("SELECT name FROM _migration WHERE version IS NULL", &[])? {
+        let name: String = row.get(0);
+        let migration = migrations.iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| MigrationError::Unknown(name.clone()))?;
+        conn.execute("UPDATE _migration SET version = $1, checksum = $2 WHERE name = $3",
+                     &[&migration.version, &migration.checksum(), &name])?;
+    }
+    Ok(())
+}
+
+fn applied<C: GenericConnection>(conn: &C) -> Result<Vec<Applied>, MigrationError> {
+    Ok(conn.query("SELECT version, name, checksum FROM _migration ORDER BY version", &[])?
+        .iter()
+        .map(|row| Applied { version: row.get(0), name: row.get(1), checksum: row.get(2) })
+        .collect())
+}
+
+fn status<'a>(migrations: &'a [Migration], applied: &[Applied]) -> Result<Vec<(&'a Migration, State)>, MigrationError> {
+    if let Some(unknown) = applied.iter().find(|a| !migrations.iter().any(|m| m.version == a.version)) {
+        return Err(MigrationError::Unknown(format!("{:04}_{}", unknown.version, unknown.name)));
+    }
+    Ok(migrations.iter()
+        .map(|migration| {
+            let state = match applied.iter().find(|a| a.version == migration.version) {
+                None => State::Pending,
+                Some(a) if a.checksum != migration.checksum() => State::Edited,
+                Some(_) => State::Applied,
+            };
+            (migration, state)
+        })
+        .collect())
+}
+
+/// Migrating past an edited migration would leave the schema in a state
+/// nobody has tested
+fn unedited(status: &[(&Migration, State)]) -> Result<(), MigrationError> {
+    match status.iter().find(|(_, state)| *state == State::Edited) {
+        Some((migration, _)) => Err(MigrationError::Edited(migration.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Run `action` in a transaction holding the migration lock. Postgres DDL is
+/// transactional, so a failing migration leaves nothing behind.
+fn with_lock<F>(conn: &Connection, migrations: &[Migration], action: F) -> Result<(), MigrationError>
+where F: FnOnce(&Transaction, Vec<(&Migration, State)>) -> Result<(), MigrationError> {
+    let tx = conn.transaction()?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+    prepare(&tx, migrations)?;
+    let status = status(migrations, &applied(&tx)?)?;
+    action(&tx, status)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn apply(tx: &Transaction, migration: &Migration) -> Result<(), MigrationError> {
+    println!("Applying migration {}", migration);
+    tx.batch_execute(migration.up)?;
+    tx.execute("INSERT INTO _migration (version, name, checksum) VALUES ($1, $2, $3)",
+               &[&migration.version, &migration.name, &migration.checksum()])?;
+    Ok(())
+}
+
+fn revert(tx: &Transaction, migration: &Migration) -> Result<(), MigrationError> {
+    println!("Reverting migration {}", migration);
+    tx.batch_execute(migration.down)?;
+    tx.execute("DELETE FROM _migration WHERE version = $1", &[&migration.version])?;
+    Ok(())
+}
+
+fn apply_pending(tx: &Transaction, status: &[(&Migration, State)]) -> Result<(), MigrationError> {
+    unedited(status)?;
+    let pending: Vec<_> = status.iter().filter(|(_, state)| *state == State::Pending).collect();
+    if pending.is_empty() {
+        println!("All migrations are applied!");
+    }
+    for (migration, _) in pending {
+        apply(tx, migration)?;
+    }
+    Ok(())
+}
+
+fn applied_migrations<'a>(status: &[(&'a Migration, State)]) -> Vec<&'a Migration> {
+    status.iter()
+        .filter(|(_, state)| *state != State::Pending)
+        .map(|(migration, _)| *migration)
+        .collect()
+}
+
+/// Fail unless every migration has been applied, as the queries expect the
+/// latest schema. Run when the server starts.
+pub fn check(conn: &Connection) -> Result<(), MigrationError> {
+    let migrations = migrations()?;
+    with_lock(conn, &migrations, |_, status| {
+        unedited(&status)?;
+        match status.iter().filter(|(_, state)| *state == State::Pending).count() {
+            0 => Ok(()),
+            pending => Err(MigrationError::Pending(pending)),
+        }
+    })
+}
+
+/// Apply every pending migration, as `backend migrate up` does. Tests run
+/// this on schemas of their own.
+#[cfg(test)]
+pub fn up(conn: &Connection) -> Result<(), MigrationError> {
+    let migrations = migrations()?;
+    with_lock(conn, &migrations, |tx, status| apply_pending(tx, &status))
+}
+
+/// `backend migrate <command>`
+pub fn run(config: &Config, command: &Migrate) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = Connection::connect(config.database_url.as_str(), TlsMode::None)?;
+    let migrations = migrations()?;
+    with_lock(&conn, &migrations, |tx, status| {
+        match command {
+            Migrate::Up => apply_pending(tx, &status)?,
+            Migrate::Down { steps } => {
+                unedited(&status)?;
+                for migration in applied_migrations(&status).into_iter().rev().take(*steps) {
+                    revert(tx, migration)?;
+                }
+            }
+            Migrate::Redo => {
+                unedited(&status)?;
+                if let Some(migration) = applied_migrations(&status).pop() {
+                    revert(tx, migration)?;
+                    apply(tx, migration)?;
+                }
+            }
+            Migrate::Status => {
+                for (migration, state) in status {
+                    let state = match state {
+                        State::Applied => "applied",
+                        State::Pending => "pending",
+                        State::Edited => "applied, edited since",
+                    };
+                    println!("{:<40} {}", migration.to_string(), state);
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_migrations_are_valid() {
+        let migrations = migrations().unwrap();
+        assert_eq!(migrations[0].to_string(), "0001_initial");
+        assert!(migrations.windows(2).all(|pair| pair[0].version < pair[1].version));
+    }
+
+    #[test]
+    fn pairs_up_and_down_files() {
+        let migrations = discover(&[
+            ("0002_b.down.sql", "DROP TABLE b"),
+            ("0001_a.up.sql", "CREATE TABLE a ()"),
+            ("0002_b.up.sql", "CREATE TABLE b ()"),
+            ("0001_a.down.sql", "DROP TABLE a"),
+        ]).unwrap();
+        assert_eq!(migrations.iter().map(|m| m.to_string()).collect::<Vec<_>>(), vec!["0001_a", "0002_b"]);
+        assert_eq!(migrations[1].down, "DROP TABLE b");
+
+        assert!(discover(&[("0001_a.up.sql", "")]).is_err());
+        assert!(discover(&[("0001_a.up.sql", ""), ("0001_b.down.sql", "")]).is_err());
+        assert!(discover(&[("initial.sql", "")]).is_err());
+    }
+
+    #[test]
+    fn detects_edited_and_unknown_migrations() {
+        let migrations = discover(&[
+            ("0001_a.up.sql", "CREATE TABLE a ()"), ("0001_a.down.sql", ""),
+            ("0002_b.up.sql", "CREATE TABLE b ()"), ("0002_b.down.sql", ""),
+        ]).unwrap();
+        let applied = |version, checksum: &str| Applied { version, name: "a".to_string(), checksum: checksum.to_string() };
+
+        let status = status(&migrations, &[applied(1, &migrations[0].checksum())]).unwrap();
+        assert_eq!(status.iter().map(|s| s.1).collect::<Vec<_>>(), vec![State::Applied, State::Pending]);
+
+        let edited = super::status(&migrations, &[applied(1, "old")]).unwrap();
+        assert!(unedited(&edited).is_err());
+
+        assert!(super::status(&migrations, &[applied(3, "")]).is_err());
+    }
+}
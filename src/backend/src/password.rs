@@ -0,0 +1,88 @@
+use bcrypt::{hash, verify};
+use types::FieldError;
+
+use crate::config::PasswordPolicy;
+use crate::handler_utils::HttpResult;
+
+/// Passwords that are tried first, whatever the policy says about them
+const COMMON: &[&str] = &[
+    "password", "password1", "password123", "passw0rd", "123456", "12345678", "123456789", "1234567890",
+    "qwerty", "qwertyuiop", "abc123", "111111", "iloveyou", "letmein", "welcome", "admin", "monkey",
+    "dragon", "sunshine", "football", "baseball", "trustno1", "princess", "starwars", "whatever",
+];
+
+/// Why `password` doesn't meet `policy`, if it doesn't
+fn problem(policy: &PasswordPolicy, username: &str, password: &str) -> Option<String> {
+    if password.chars().count() < policy.min_length {
+        return Some(format!("must be at least {} characters long", policy.min_length));
+    }
+    if password.len() > policy.max_length {
+        return Some(format!("must be at most {} bytes long", policy.max_length));
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|&&class| class).count() < policy.min_character_classes {
+        return Some(format!("must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                            policy.min_character_classes));
+    }
+    if policy.reject_common {
+        let lowercase = password.to_lowercase();
+        if COMMON.contains(&lowercase.as_str()) {
+            return Some("is too common".to_string());
+        }
+        if username.chars().count() >= 3 && lowercase.contains(&username.to_lowercase()) {
+            return Some("must not contain the username".to_string());
+        }
+    }
+    None
+}
+
+/// Fail with a validation error for `field` unless `password` meets `policy`
+pub fn validate(policy: &PasswordPolicy, field: &str, username: &str, password: &str) -> Result<(), HttpResult> {
+    match problem(policy, username, password) {
+        Some(message) => Err(HttpResult::validation(vec![FieldError { field: field.to_string(), message }])),
+        None => Ok(()),
+    }
+}
+
+pub fn hash_password(password: &str, cost: u32) -> Result<String, HttpResult> {
+    Ok(hash(password, cost)?)
+}
+
+pub fn verify_password(password: &str, hashed: &str) -> Result<bool, HttpResult> {
+    Ok(verify(password, hashed)?)
+}
+
+/// Whether `hashed` was hashed with another cost than `cost`, read from its
+/// `$2b$<cost>$...` prefix
+pub fn needs_rehash(hashed: &str, cost: u32) -> bool {
+    hashed.split('$').nth(2).and_then(|c| c.parse::<u32>().ok()) != Some(cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_passwords_are_rejected() {
+        let policy = PasswordPolicy { min_length: 8, max_length: 72, min_character_classes: 2, reject_common: true };
+        assert!(problem(&policy, "alice", "correct horse battery staple").is_none());
+        assert!(problem(&policy, "alice", "short 1").is_some());
+        assert!(problem(&policy, "alice", "onlylowercase").is_some());
+        assert!(problem(&policy, "alice", "Password123").is_some());
+        assert!(problem(&policy, "alice", "I am Alice!").is_some());
+        assert!(problem(&policy, "alice", &"long enough ".repeat(7)).is_some());
+    }
+
+    #[test]
+    fn rehashes_other_costs() {
+        let hashed = hash_password("correct horse", 4).unwrap();
+        assert!(!needs_rehash(&hashed, 4));
+        assert!(needs_rehash(&hashed, 5));
+        assert!(verify_password("correct horse", &hashed).unwrap());
+    }
+}
@@ -0,0 +1,279 @@
+use chrono::{DateTime, Duration, Utc};
+use futures::{future, Future};
+use gotham::handler::HandlerFuture;
+use gotham::middleware::Middleware;
+use gotham::state::{FromState, State, client_addr};
+use hyper::HeaderMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::auth_middleware::AuthenticatedAccount;
+use crate::config::{Bucket, Lockout, RouteLimit};
+use crate::db::{self, Database, DbError, FailedLogins, blocking};
+use crate::handler_utils::HttpResult;
+use crate::router::S;
+
+/// Buckets, and logins with failures, `MemoryLimits` keeps before it forgets
+/// the ones that are the same as new again: full buckets, and failures over
+/// `reset_after` ago
+const MAX_MEMORY_KEYS: usize = 100_000;
+
+/// Where the state of rate limits and login lockouts is kept
+pub trait LimitStore: Send + Sync + fmt::Debug {
+    /// Take a request out of the bucket `key`. `None` if it may go ahead,
+    /// otherwise the seconds until it may be retried.
+    fn take(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, DbError>;
+    /// Recent failed logins in a row as `username`
+    fn failed_logins(&self, username: &str, lockout: &Lockout) -> Result<Option<FailedLogins>, DbError>;
+    fn record_failed_login(&self, username: &str, lockout: &Lockout) -> Result<FailedLogins, DbError>;
+    fn clear_failed_logins(&self, username: &str) -> Result<(), DbError>;
+}
+
+pub type Limits = Arc<dyn LimitStore>;
+
+impl Bucket {
+    fn per_second(&self) -> f64 {
+        self.per_minute / 60.0
+    }
+
+    /// `tokens` after refilling for `elapsed`
+    fn refill(&self, tokens: f64, elapsed: Duration) -> f64 {
+        let seconds = elapsed.num_milliseconds().max(0) as f64 / 1000.0;
+        (tokens + seconds * self.per_second()).min(f64::from(self.burst))
+    }
+
+    /// Whole seconds until a bucket with `tokens` has a token to take
+    fn retry_after(&self, tokens: f64) -> u64 {
+        ((1.0 - tokens) / self.per_second()).ceil().max(1.0) as u64
+    }
+}
+
+impl Lockout {
+    /// When logins are allowed again after `failed`, if they're locked
+    pub fn locked_until(&self, failed: &FailedLogins) -> Option<DateTime<Utc>> {
+        let over = failed.failures - self.max_failures as i32;
+        if over < 0 {
+            return None;
+        }
+        let delay = 2u64.checked_pow(over as u32)
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        Some(failed.last_failure_at + Duration::seconds(delay as i64))
+    }
+}
+
+/// Fail with 429 while logins as `username` are locked
+pub fn check_lockout(limits: &dyn LimitStore, lockout: &Lockout, username: &str) -> Result<(), HttpResult> {
+    let locked_until = limits.failed_logins(username, lockout)?
+        .and_then(|failed| lockout.locked_until(&failed));
+    let remaining = locked_until.map_or(0, |until| (until - Utc::now()).num_milliseconds());
+    if remaining > 0 {
+        let retry_after = (remaining as u64 + 999) / 1000;
+        return Err(HttpResult::too_many_requests("Too many failed logins, try again later", retry_after));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+    /// When the bucket has refilled completely
+    full_at: DateTime<Utc>,
+}
+
+/// Limits of a single instance, forgotten on restart
+#[derive(Debug, Default)]
+pub struct MemoryLimits {
+    buckets: Mutex<HashMap<String, BucketState>>,
+    failed_logins: Mutex<HashMap<String, FailedLogins>>,
+}
+
+impl MemoryLimits {
+    fn recent_failed_logins(failed_logins: &HashMap<String, FailedLogins>, username: &str, lockout: &Lockout)
+        -> Option<FailedLogins> {
+        let reset_at = Utc::now() - Duration::seconds(lockout.reset_after as i64);
+        failed_logins.get(username).filter(|failed| failed.last_failure_at > reset_at).cloned()
+    }
+}
+
+impl LimitStore for MemoryLimits {
+    fn take(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, DbError> {
+        let now = Utc::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_MEMORY_KEYS {
+            buckets.retain(|_, state| state.full_at > now);
+        }
+        let tokens = match buckets.get(key) {
+            Some(state) => bucket.refill(state.tokens, now - state.updated_at),
+            None => f64::from(bucket.burst),
+        };
+        let (tokens, retry_after) = if tokens >= 1.0 {
+            (tokens - 1.0, None)
+        } else {
+            (tokens, Some(bucket.retry_after(tokens)))
+        };
+        let refill_ms = (f64::from(bucket.burst) - tokens) / bucket.per_second() * 1000.0;
+        let full_at = now + Duration::milliseconds(refill_ms.ceil() as i64);
+        buckets.insert(key.to_string(), BucketState { tokens, updated_at: now, full_at });
+        Ok(retry_after)
+    }
+
+    fn failed_logins(&self, username: &str, lockout: &Lockout) -> Result<Option<FailedLogins>, DbError> {
+        Ok(MemoryLimits::recent_failed_logins(&self.failed_logins.lock().unwrap(), username, lockout))
+    }
+
+    fn record_failed_login(&self, username: &str, lockout: &Lockout) -> Result<FailedLogins, DbError> {
+        let mut failed_logins = self.failed_logins.lock().unwrap();
+        if failed_logins.len() >= MAX_MEMORY_KEYS {
+            let reset_at = Utc::now() - Duration::seconds(lockout.reset_after as i64);
+            failed_logins.retain(|_, failed| failed.last_failure_at > reset_at);
+        }
+        let failures = MemoryLimits::recent_failed_logins(&failed_logins, username, lockout)
+            .map_or(0, |failed| failed.failures);
+        let failed = FailedLogins { failures: failures + 1, last_failure_at: Utc::now() };
+        failed_logins.insert(username.to_string(), failed);
+        Ok(failed)
+    }
+
+    fn clear_failed_logins(&self, username: &str) -> Result<(), DbError> {
+        self.failed_logins.lock().unwrap().remove(username);
+        Ok(())
+    }
+}
+
+/// Limits shared by every instance using the database
+#[derive(Debug)]
+pub struct PostgresLimits(pub Database);
+
+impl LimitStore for PostgresLimits {
+    fn take(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, DbError> {
+        let tokens = db::take_token(&self.0.get()?, key, f64::from(bucket.burst), bucket.per_second())?;
+        Ok(tokens.map(|tokens| bucket.retry_after(tokens)))
+    }
+
+    fn failed_logins(&self, username: &str, lockout: &Lockout) -> Result<Option<FailedLogins>, DbError> {
+        db::get_failed_logins(&self.0.get()?, username, lockout.reset_after as f64)
+    }
+
+    fn record_failed_login(&self, username: &str, lockout: &Lockout) -> Result<FailedLogins, DbError> {
+        db::record_failed_login(&self.0.get()?, username, lockout.reset_after as f64)
+    }
+
+    fn clear_failed_logins(&self, username: &str) -> Result<(), DbError> {
+        db::clear_failed_logins(&self.0.get()?, username)
+    }
+}
+
+/// The address of the client. Behind a reverse proxy that's the last entry
+/// of `X-Forwarded-For`, the one the proxy added; earlier ones come from the
+/// client and can't be trusted.
+fn client_ip(state: &State, trust_forwarded_for: bool) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = HeaderMap::borrow_from(state).get_all("x-forwarded-for").iter()
+            .last()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    client_addr(state).map(|addr| addr.ip())
+}
+
+/// Token bucket rate limits of a route, per client address and per account.
+/// Goes after `AuthMiddleware` for the per account limit to apply.
+#[derive(Clone, NewMiddleware)]
+pub struct RateLimitMiddleware {
+    route: &'static str,
+    limit: RouteLimit,
+}
+
+impl RateLimitMiddleware {
+    /// `route` names the limit in the keys of the buckets
+    pub fn new(route: &'static str, limit: RouteLimit) -> Self {
+        RateLimitMiddleware { route, limit }
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Box<HandlerFuture>
+    where Chain: FnOnce(State) -> Box<HandlerFuture> + Send + 'static {
+        let s = S::borrow_from(&state);
+        let limits = s.limits.clone();
+        let ip = client_ip(&state, s.config.rate_limits.trust_forwarded_for);
+        let account = AuthenticatedAccount::try_borrow_from(&state);
+        let mut buckets = Vec::new();
+        if let (Some(bucket), Some(ip)) = (self.limit.per_ip, ip) {
+            buckets.push((format!("{}:ip:{}", self.route, ip), bucket));
+        }
+        if let (Some(bucket), Some(account)) = (self.limit.per_account, account) {
+            buckets.push((format!("{}:account:{}", self.route, account.id), bucket));
+        }
+        if buckets.is_empty() {
+            return chain(state);
+        }
+
+        let checked = blocking(move || -> Result<Option<u64>, DbError> {
+            for (key, bucket) in &buckets {
+                if let Some(retry_after) = limits.take(key, bucket)? {
+                    return Ok(Some(retry_after));
+                }
+            }
+            Ok(None)
+        });
+        Box::new(checked.then(move |checked| -> Box<HandlerFuture> {
+            let error = match checked {
+                Ok(None) => return chain(state),
+                Ok(Some(retry_after)) =>
+                    HttpResult::too_many_requests("Too many requests, try again later", retry_after),
+                Err(e) => HttpResult::from(e),
+            };
+            let response = error.into_response(&state);
+            Box::new(future::ok((state, response)))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_run_out_and_refill() {
+        let limits = MemoryLimits::default();
+        let bucket = Bucket { burst: 2, per_minute: 6.0 };
+        assert_eq!(limits.take("key", &bucket).unwrap(), None);
+        assert_eq!(limits.take("key", &bucket).unwrap(), None);
+        assert_eq!(limits.take("key", &bucket).unwrap(), Some(10));
+        assert_eq!(limits.take("other", &bucket).unwrap(), None);
+        assert!((bucket.refill(0.0, Duration::seconds(15)) - 1.5).abs() < 1e-9);
+        assert_eq!(bucket.refill(1.0, Duration::hours(1)), 2.0);
+    }
+
+    #[test]
+    fn lockouts_grow_exponentially() {
+        let lockout = Lockout { max_failures: 3, base_delay: 30, max_delay: 600, reset_after: 3600 };
+        let now = Utc::now();
+        let locked_for = |failures| lockout.locked_until(&FailedLogins { failures, last_failure_at: now })
+            .map(|until| (until - now).num_seconds());
+        assert_eq!(locked_for(2), None);
+        assert_eq!(locked_for(3), Some(30));
+        assert_eq!(locked_for(5), Some(120));
+        assert_eq!(locked_for(9), Some(600));
+        assert_eq!(locked_for(200), Some(600));
+    }
+
+    #[test]
+    fn old_failed_logins_are_forgotten() {
+        let limits = MemoryLimits::default();
+        let lockout = Lockout::default();
+        let old = FailedLogins { failures: 1, last_failure_at: Utc::now() - Duration::days(2) };
+        limits.failed_logins.lock().unwrap().extend((0..MAX_MEMORY_KEYS).map(|i| (i.to_string(), old)));
+        limits.record_failed_login("alice", &lockout).unwrap();
+        assert_eq!(limits.failed_logins.lock().unwrap().len(), 1);
+    }
+}
@@ -1,29 +1,71 @@
-use bcrypt::{DEFAULT_COST, hash, verify};
+use chrono::{Duration, Utc};
 use gotham::handler::HandlerFuture;
 use gotham::handler::assets::FileOptions;
 use gotham::helpers::http::response::create_response;
 use gotham::middleware::state::StateMiddleware;
-use gotham::pipeline::single::single_pipeline;
 use gotham::pipeline::new_pipeline;
+use gotham::pipeline::set::{finalize_pipeline_set, new_pipeline_set};
 use gotham::router::builder::*;
 use gotham::router::Router;
 use gotham::state::{State, FromState};
-use hyper::{Body, StatusCode, HeaderMap};
+use hyper::{Body, StatusCode};
 use serde::Deserialize;
-use std::convert::TryInto;
+use std::sync::Arc;
 use types::*;
 
+use crate::attachments;
 use crate::auth;
-use crate::db;
-use crate::handler_utils::{r, with_json};
+use crate::auth_middleware::{AuthMiddleware, AuthPolicy, AuthenticatedAccount};
+use crate::blob_store::Blobs;
+use crate::config::Config;
+use crate::db::{self, DbError};
+use crate::events;
+use crate::handler_utils::{HttpResult, json_response, r, require_fields, respond, with_json};
+use crate::mailer::{self, Mail, Outbox};
+use crate::markdown;
+use crate::password;
+use crate::rate_limit::{self, Limits, RateLimitMiddleware};
+use crate::sse;
+use crate::totp;
+use crate::store::{ForumStore, Store};
+use crate::websocket::{self, SocketQuery};
 
 #[derive(Clone, Debug, StateData)]
-pub struct S { }
-impl S { pub fn new() -> Self { S { } } }
+pub struct S {
+    pub config: Arc<Config>,
+    pub keys: Arc<auth::KeySet>,
+    pub store: Store,
+    pub blobs: Blobs,
+    pub limits: Limits,
+    pub mailer: Outbox,
+    pub events: Arc<events::Hub>,
+}
+
+impl S {
+    pub fn new(config: Config, store: Store, blobs: Blobs, limits: Limits, mailer: Outbox)
+        -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = auth::KeySet::load(&config)?;
+        Ok(S {
+            config: Arc::new(config),
+            keys: Arc::new(keys),
+            store,
+            blobs,
+            limits,
+            mailer,
+            events: Arc::new(events::Hub::default()),
+        })
+    }
+}
 
 #[derive(Deserialize, StateData, StaticResponseExtender)]
-struct ThreadId {
+pub(crate) struct ThreadId {
+    pub(crate) id: i32,
+}
+
+#[derive(Clone, Copy, Deserialize, StateData, StaticResponseExtender)]
+struct MessageId {
     id: i32,
+    message_id: i32,
 }
 
 #[derive(Deserialize, StateData, StaticResponseExtender)]
@@ -31,94 +73,931 @@ struct AccountId {
     id: i32,
 }
 
-fn get_token(id: i32) -> Token {
-    let token = auth::sign(json!({"sub": id})).unwrap();
-    Token { token }
+const MAX_USERNAME_LENGTH: usize = 64;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct PageQuery {
+    limit: Option<i64>,
+    before: Option<String>,
+    after: Option<String>,
+    /// Only used by the thread listing
+    sort: Option<String>,
+}
+
+const DEFAULT_SEARCH_RESULTS: i64 = 20;
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct SearchQuery {
+    q: Option<String>,
+    limit: Option<i64>,
+}
+
+fn invalid_field(field: &str, message: &str) -> HttpResult {
+    HttpResult::validation(vec![FieldError { field: field.to_string(), message: message.to_string() }])
+}
+
+impl PageQuery {
+    /// Build a page request, decoding the cursor with `key`
+    fn page_request<K, F>(&self, key: F) -> Result<db::PageRequest<K>, HttpResult>
+    where F: Fn(&str) -> Option<K> {
+        let cursor_key = |field: &str, value: &str| key(value)
+            .ok_or_else(|| invalid_field(field, "is not a valid cursor"));
+        let cursor = match (&self.before, &self.after) {
+            (None, None) => db::Cursor::Start,
+            (Some(before), None) => db::Cursor::Before(cursor_key("before", before)?),
+            (None, Some(after)) => db::Cursor::After(cursor_key("after", after)?),
+            (Some(_), Some(_)) => return Err(invalid_field("before", "can't be combined with after")),
+        };
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if limit < 1 || limit > MAX_PAGE_SIZE {
+            return Err(invalid_field("limit", &format!("must be between 1 and {}", MAX_PAGE_SIZE)));
+        }
+        Ok(db::PageRequest { cursor, limit })
+    }
+
+    fn thread_sort(&self) -> Result<ThreadSort, HttpResult> {
+        let sort = match &self.sort {
+            Some(sort) => sort,
+            None => return Ok(ThreadSort::default()),
+        };
+        [ThreadSort::RecentActivity, ThreadSort::Created, ThreadSort::Title].iter()
+            .cloned()
+            .find(|s| s.as_str() == sort)
+            .ok_or_else(|| invalid_field("sort", "must be one of recent_activity, created or title"))
+    }
 }
 
-pub fn new_account(state: State, connection: db::Connection) -> Box<HandlerFuture> {
-    with_json(state, |state, account: CreateAccount| {
-        let hashed = hash(account.password, DEFAULT_COST - 2)?;
-        let id = db::create_account(connection, &account.username, &hashed).ok_or(StatusCode::CONFLICT)?;
-        let body = serde_json::to_string(&get_token(id))?;
-        Ok(create_response(&state, StatusCode::CREATED, mime::APPLICATION_JSON, body.to_string()))
+/// Issue a new access token and a refresh token for it. Refresh tokens
+/// rotated from an earlier one keep the same family, so that reuse of a
+/// rotated token can revoke the whole chain.
+fn get_token<St: ForumStore + ?Sized>(store: &St, s: &S, id: i32, family: Option<String>)
+    -> Result<Token, HttpResult> {
+    let config = &s.config;
+    let jti = auth::new_jti();
+    let role = store.get_role(id)?;
+    let email_verified = store.get_email(id)?.verified;
+    let token = auth::sign(config, &s.keys, json!({"sub": id, "jti": jti, "role": role.as_str()}))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let refresh_token = auth::new_opaque_token();
+    let now = Utc::now();
+    store.create_refresh_token(&db::NewRefreshToken {
+        account_id: id,
+        token_hash: &auth::hash_opaque_token(&refresh_token),
+        family: &family.unwrap_or_else(auth::new_jti),
+        access_jti: &jti,
+        access_expires_at: now + Duration::seconds(config.token_lifetime as i64),
+        expires_at: now + Duration::seconds(config.refresh_token_lifetime as i64),
+    })?;
+    Ok(Token { token, account_id: id, role, refresh_token, expires_in: config.token_lifetime, email_verified })
+}
+
+fn forbidden(message: &str) -> HttpResult {
+    HttpResult::new(StatusCode::FORBIDDEN, ErrorCode::Forbidden, message)
+}
+
+/// Moderators may change anything. Everyone else may only change what they
+/// wrote, and only while the thread isn't locked.
+fn require_author(account: &AuthenticatedAccount, ownership: db::Ownership) -> Result<(), HttpResult> {
+    if account.has_role(Role::Moderator) {
+        Ok(())
+    } else if ownership.locked {
+        Err(forbidden("The thread is locked"))
+    } else if ownership.creator != account.id {
+        Err(forbidden("Only the author can do that"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Unless the config says otherwise, accounts may only post once they've
+/// verified their email address
+pub fn require_verified<St: ForumStore + ?Sized>(store: &St, config: &Config, account_id: i32)
+    -> Result<(), HttpResult> {
+    if config.require_verified_email && !store.get_email(account_id)?.verified {
+        return Err(forbidden("Verify your email address before posting"));
+    }
+    Ok(())
+}
+
+fn no_content(state: &State) -> hyper::Response<Body> {
+    create_response(state, StatusCode::NO_CONTENT, mime::APPLICATION_JSON, Body::empty())
+}
+
+/// Look an account up by what it logs in with: its email address, or the
+/// username of an account from before addresses were separate, which has
+/// none. Each account has exactly one login, see `lockout_key`.
+fn find_credentials<St: ForumStore + ?Sized>(store: &St, login: &str) -> Result<db::Credentials, DbError> {
+    let by_username = || store.get_credentials(login).and_then(|credentials| match credentials.email {
+        None => Ok(credentials),
+        Some(_) => Err(DbError::NotFound),
+    });
+    match mailer::normalize_address(login) {
+        Some(email) => match store.get_credentials_by_email(&email) {
+            Err(DbError::NotFound) => by_username(),
+            result => result,
+        },
+        None => by_username(),
+    }
+}
+
+/// What failed logins of an account are counted under: its login, normalized
+fn lockout_key(credentials: &db::Credentials) -> &str {
+    credentials.email.as_ref().unwrap_or(&credentials.username)
+}
+
+fn verification_mail(config: &Config, username: &str, email: &str, token: &str) -> Mail {
+    let link = format!("{}/#verify-email/{}", config.public_url.trim_end_matches('/'), token);
+    Mail {
+        to: email.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!("Welcome, {}! Confirm that {} is your address by opening\n\n\
+                       {}\n\n\
+                       The link works for the next {} hours. If you didn't sign up, just ignore this mail.\n",
+                      username, email, link, (config.email_verification_lifetime + 3599) / 3600),
+    }
+}
+
+/// Mail a new verification link to `email`. Failing to send it doesn't fail
+/// the request, the link can be sent again.
+fn send_verification<St: ForumStore + ?Sized>(store: &St, s: &S, account_id: i32, username: &str, email: &str)
+    -> Result<(), HttpResult> {
+    let token = auth::new_opaque_token();
+    let expires_at = Utc::now() + Duration::seconds(s.config.email_verification_lifetime as i64);
+    store.create_email_verification(account_id, &auth::hash_opaque_token(&token), expires_at)?;
+    if let Err(e) = s.mailer.send(&verification_mail(&s.config, username, email, &token)) {
+        eprintln!("Could not send a verification mail: {}", e);
+    }
+    Ok(())
+}
+
+/// `POST /account`: sign up, and get a link to verify the email address
+/// mailed to it
+pub fn new_account(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, account: CreateAccount| {
+        require_fields(&[("username", &account.username), ("email", &account.email),
+                         ("password", &account.password)])?;
+        if account.username.contains('@') {
+            // So that logins can tell usernames and addresses apart
+            return Err(invalid_field("username", "must not contain @"));
+        }
+        if account.username.chars().count() > MAX_USERNAME_LENGTH {
+            let message = format!("must be at most {} characters long", MAX_USERNAME_LENGTH);
+            return Err(invalid_field("username", &message));
+        }
+        let email = mailer::normalize_address(&account.email)
+            .ok_or_else(|| invalid_field("email", "is not a valid email address"))?;
+        let s = S::borrow_from(&state);
+        password::validate(&s.config.password_policy, "password", &account.username, &account.password)?;
+        let hashed = password::hash_password(&account.password, s.config.bcrypt_cost)?;
+        let tx = store.begin()?;
+        let id = tx.create_account(&account.username, &email, &hashed).map_err(|e| match e {
+            DbError::UniqueViolation(Some(ref constraint)) if constraint == "account_email_key" =>
+                HttpResult::new(StatusCode::CONFLICT, ErrorCode::Conflict, "Email address is already in use"),
+            DbError::UniqueViolation(_) =>
+                HttpResult::new(StatusCode::CONFLICT, ErrorCode::Conflict, "Username is already taken"),
+            e => HttpResult::from(e),
+        })?;
+        let token = get_token(&*tx, s, id, None)?;
+        send_verification(&*tx, s, id, &account.username, &email)?;
+        tx.commit()?;
+        json_response(&state, StatusCode::CREATED, &token)
     })
 }
 
-pub fn login(state: State, connection: db::Connection) -> Box<HandlerFuture> {
-    with_json(state, |state, account: Login| {
-        connection.transaction(|tx| {
-            let (id, password) = db::get_password(&tx, &account.username).ok_or(StatusCode::NOT_FOUND)?;
-            let valid = verify(&account.password, &password)?;
-            if valid {
-                db::update_last_logged_in(&tx, &account.username);
-                tx.commit().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-                let body = serde_json::to_string(&get_token(id))?;
-                Ok(create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body.to_string()))
-            } else {
-                Err(From::from(StatusCode::NOT_FOUND))
-            }
+/// `POST /account/verify`: verify an email address with the token of the
+/// link mailed to it
+pub fn verify_email(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: VerifyEmail| {
+        store.verify_email(&auth::hash_opaque_token(&body.token)).map_err(|e| match e {
+            DbError::NotFound => invalid_field("token", "is invalid or has expired"),
+            e => HttpResult::from(e),
+        })?;
+        Ok(no_content(&state))
+    })
+}
+
+/// `POST /account/verify/resend`: mail another verification link to the
+/// signed in account
+pub fn resend_verification(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let s = S::borrow_from(&state);
+    let account_id = AuthenticatedAccount::borrow_from(&state).id;
+    let result = store.get_account(account_id)
+        .and_then(|account| store.get_credentials(&account.username))
+        .map_err(HttpResult::from)
+        .and_then(|credentials| match credentials.email {
+            Some(email) if !credentials.email_verified =>
+                send_verification(&*store, s, account_id, &credentials.username, &email),
+            _ => Err(HttpResult::new(StatusCode::CONFLICT, ErrorCode::Conflict,
+                                     "There's no email address to verify")),
         })
+        .map(|_| create_response(&state, StatusCode::ACCEPTED, mime::APPLICATION_JSON, Body::empty()));
+    respond(state, result)
+}
+
+/// `POST /login`. Failed attempts are counted per account, or per login
+/// for the ones that don't exist, and lock it out for a while once there are
+/// too many. Accounts with two-factor authentication get a challenge to
+/// answer with `login_mfa` instead of a token.
+pub fn login(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, account: Login| {
+        let s = S::borrow_from(&state);
+        let lockout = &s.config.rate_limits.lockout;
+        let invalid = |key: &str| match s.limits.record_failed_login(key, lockout) {
+            Ok(_) => HttpResult::new(StatusCode::NOT_FOUND, ErrorCode::NotFound, "Invalid email or password"),
+            Err(e) => HttpResult::from(e),
+        };
+        // bcrypt is slow, so no connection is held while it runs. The
+        // transaction is only for the writes after it.
+        let credentials = match find_credentials(&*store, &account.email) {
+            Ok(credentials) => credentials,
+            Err(DbError::NotFound) => {
+                let login = mailer::normalize_address(&account.email).unwrap_or_else(|| account.email.clone());
+                rate_limit::check_lockout(&*s.limits, lockout, &login)?;
+                return Err(invalid(&login));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = lockout_key(&credentials);
+        rate_limit::check_lockout(&*s.limits, lockout, key)?;
+        if !password::verify_password(&account.password, &credentials.password)? {
+            return Err(invalid(key));
+        }
+        // With two-factor authentication the login isn't over until the code
+        // is checked, and wrong codes count as failed logins too
+        if !credentials.totp_enabled {
+            s.limits.clear_failed_logins(key)?;
+        }
+        if credentials.banned {
+            return Err(forbidden("This account has been banned"));
+        }
+        let rehashed = if password::needs_rehash(&credentials.password, s.config.bcrypt_cost) {
+            Some(password::hash_password(&account.password, s.config.bcrypt_cost)?)
+        } else {
+            None
+        };
+
+        let tx = store.begin()?;
+        if let Some(hashed) = rehashed {
+            tx.set_password(credentials.id, &hashed)?;
+        }
+        if credentials.totp_enabled {
+            let mfa_token = auth::new_opaque_token();
+            let expires_at = Utc::now() + Duration::seconds(s.config.mfa_challenge_lifetime as i64);
+            tx.create_mfa_challenge(credentials.id, &auth::hash_opaque_token(&mfa_token), expires_at)?;
+            tx.commit()?;
+            let challenge = MfaChallenge { mfa_token, expires_in: s.config.mfa_challenge_lifetime };
+            return json_response(&state, StatusCode::OK, &LoginResult::MfaRequired(challenge));
+        }
+        tx.update_last_logged_in(&credentials.username)?;
+        let token = get_token(&*tx, s, credentials.id, None)?;
+        tx.commit()?;
+        json_response(&state, StatusCode::OK, &LoginResult::Token(token))
+    })
+}
+
+/// Whether `code` is a current TOTP code of the account, or one of its
+/// recovery codes, using it up either way
+fn check_second_factor<St: ForumStore + ?Sized>(store: &St, account_id: i32, code: &str)
+    -> Result<bool, HttpResult> {
+    let totp = store.get_totp(account_id)?;
+    let secret = match totp.secret {
+        Some(ref secret) if totp.enabled => secret,
+        _ => return Ok(false),
+    };
+    let used = if totp::is_code(code) {
+        match totp::verify(secret, code, totp.last_step) {
+            Some(step) => store.use_totp_step(account_id, step),
+            None => return Ok(false),
+        }
+    } else {
+        store.use_recovery_code(account_id, &auth::hash_opaque_token(&totp::normalize_recovery_code(code)))
+    };
+    match used {
+        Ok(()) => Ok(true),
+        Err(DbError::NotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// New recovery codes for the account, replacing the old ones
+fn replace_recovery_codes<St: ForumStore + ?Sized>(store: &St, account_id: i32) -> Result<RecoveryCodes, HttpResult> {
+    let codes = totp::new_recovery_codes();
+    let hashes: Vec<String> = codes.iter()
+        .map(|code| auth::hash_opaque_token(&totp::normalize_recovery_code(code)))
+        .collect();
+    store.set_recovery_codes(account_id, &hashes)?;
+    Ok(RecoveryCodes { codes })
+}
+
+/// `POST /login/mfa`: finish logging in with the challenge from `/login`
+/// and a code. A challenge only survives a few wrong codes, and every wrong
+/// code counts towards the lockout of the account like a wrong password.
+pub fn login_mfa(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: MfaLogin| {
+        let s = S::borrow_from(&state);
+        let lockout = &s.config.rate_limits.lockout;
+        let challenge = auth::hash_opaque_token(&body.mfa_token);
+        let account_id = store.attempt_mfa_challenge(&challenge, totp::MAX_ATTEMPTS).map_err(|e| match e {
+            DbError::NotFound => invalid_field("mfa_token", "is invalid or has expired, log in again"),
+            e => HttpResult::from(e),
+        })?;
+        let credentials = store.get_credentials(&store.get_account(account_id)?.username)?;
+        let key = lockout_key(&credentials);
+        rate_limit::check_lockout(&*s.limits, lockout, key)?;
+
+        let tx = store.begin()?;
+        if !check_second_factor(&*tx, account_id, &body.code)? {
+            drop(tx);
+            s.limits.record_failed_login(key, lockout)?;
+            return Err(invalid_field("code", "is incorrect"));
+        }
+        if credentials.banned {
+            return Err(forbidden("This account has been banned"));
+        }
+        tx.delete_mfa_challenges(account_id)?;
+        tx.update_last_logged_in(&credentials.username)?;
+        let token = get_token(&*tx, s, account_id, None)?;
+        tx.commit()?;
+        s.limits.clear_failed_logins(key)?;
+        json_response(&state, StatusCode::OK, &token)
     })
 }
 
-pub fn get_account(state: State, connection: db::Connection) -> (State, String) {
+/// `GET /account/totp`
+pub fn get_totp(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let account_id = AuthenticatedAccount::borrow_from(&state).id;
+    let result = store.get_totp(account_id)
+        .map_err(HttpResult::from)
+        .and_then(|totp| json_response(&state, StatusCode::OK, &TotpStatus {
+            enabled: totp.enabled,
+            recovery_codes_left: totp.recovery_codes_left,
+        }));
+    respond(state, result)
+}
+
+fn totp_already_enabled() -> HttpResult {
+    HttpResult::new(StatusCode::CONFLICT, ErrorCode::Conflict, "Two-factor authentication is already enabled")
+}
+
+fn enroll_totp(state: &State, store: &Store) -> Result<hyper::Response<Body>, HttpResult> {
+    let s = S::borrow_from(state);
+    let account_id = AuthenticatedAccount::borrow_from(state).id;
+    if store.get_totp(account_id)?.enabled {
+        return Err(totp_already_enabled());
+    }
+    let secret = totp::new_secret();
+    store.set_totp_secret(account_id, Some(&secret))?;
+    let label = match store.get_email(account_id)?.email {
+        Some(email) => email,
+        None => store.get_account(account_id)?.username,
+    };
+    let uri = totp::provisioning_uri(&s.config.totp_issuer, &label, &secret);
+    let qr_code = totp::qr_code(&uri).map_err(|e| {
+        eprintln!("Could not render a QR code: {}", e);
+        HttpResult::from(StatusCode::INTERNAL_SERVER_ERROR)
+    })?;
+    json_response(state, StatusCode::CREATED, &TotpEnrollment { secret: totp::base32(&secret), uri, qr_code })
+}
+
+/// `POST /account/totp`: start setting up two-factor authentication with a
+/// new secret, replacing any earlier one that wasn't confirmed
+pub fn start_totp(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let result = enroll_totp(&state, &store);
+    respond(state, result)
+}
+
+/// `POST /account/totp/confirm`: turn two-factor authentication on with a
+/// code of the new secret, getting the recovery codes
+pub fn confirm_totp(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: TotpCode| {
+        let account_id = AuthenticatedAccount::borrow_from(&state).id;
+        let tx = store.begin()?;
+        let totp = tx.get_totp(account_id)?;
+        if totp.enabled {
+            return Err(totp_already_enabled());
+        }
+        let secret = totp.secret.ok_or_else(|| invalid_field("code", "can't be checked before enrolling"))?;
+        let step = totp::verify(&secret, &body.code, totp.last_step)
+            .ok_or_else(|| invalid_field("code", "is incorrect"))?;
+        tx.use_totp_step(account_id, step)?;
+        tx.enable_totp(account_id)?;
+        let codes = replace_recovery_codes(&*tx, account_id)?;
+        tx.commit()?;
+        json_response(&state, StatusCode::OK, &codes)
+    })
+}
+
+/// `POST /account/totp/recovery-codes`: replace the recovery codes, given a
+/// code
+pub fn new_recovery_codes(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: TotpCode| {
+        let account_id = AuthenticatedAccount::borrow_from(&state).id;
+        let tx = store.begin()?;
+        if !check_second_factor(&*tx, account_id, &body.code)? {
+            return Err(invalid_field("code", "is incorrect"));
+        }
+        let codes = replace_recovery_codes(&*tx, account_id)?;
+        tx.commit()?;
+        json_response(&state, StatusCode::OK, &codes)
+    })
+}
+
+/// `POST /account/totp/disable`: turn two-factor authentication off, given
+/// the password and a code
+pub fn disable_totp(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: DisableTotp| {
+        let account_id = AuthenticatedAccount::borrow_from(&state).id;
+        let tx = store.begin()?;
+        let credentials = tx.get_credentials(&tx.get_account(account_id)?.username)?;
+        if !password::verify_password(&body.password, &credentials.password)? {
+            return Err(invalid_field("password", "is incorrect"));
+        }
+        if !check_second_factor(&*tx, account_id, &body.code)? {
+            return Err(invalid_field("code", "is incorrect"));
+        }
+        tx.set_totp_secret(account_id, None)?;
+        tx.set_recovery_codes(account_id, &[])?;
+        tx.delete_mfa_challenges(account_id)?;
+        tx.commit()?;
+        Ok(no_content(&state))
+    })
+}
+
+/// `POST /account/password`: change the password of the signed in account.
+/// Every session of the account ends, and a new one is returned instead.
+pub fn change_password(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: ChangePassword| {
+        let s = S::borrow_from(&state);
+        let lockout = &s.config.rate_limits.lockout;
+        let account_id = AuthenticatedAccount::borrow_from(&state).id;
+        // Like in `login`, wrong passwords count towards the lockout, and no
+        // connection is held while bcrypt runs
+        let username = store.get_account(account_id)?.username;
+        let credentials = store.get_credentials(&username)?;
+        let key = lockout_key(&credentials);
+        rate_limit::check_lockout(&*s.limits, lockout, key)?;
+        if !password::verify_password(&body.current_password, &credentials.password)? {
+            s.limits.record_failed_login(key, lockout)?;
+            return Err(invalid_field("current_password", "is incorrect"));
+        }
+        password::validate(&s.config.password_policy, "new_password", &username, &body.new_password)?;
+        let hashed = password::hash_password(&body.new_password, s.config.bcrypt_cost)?;
+
+        let tx = store.begin()?;
+        tx.set_password(account_id, &hashed)?;
+        tx.revoke_account_tokens(account_id)?;
+        let token = get_token(&*tx, s, account_id, None)?;
+        tx.commit()?;
+        json_response(&state, StatusCode::OK, &token)
+    })
+}
+
+fn password_reset_mail(config: &Config, username: &str, email: &str, token: &str) -> Mail {
+    let link = format!("{}/#reset-password/{}", config.public_url.trim_end_matches('/'), token);
+    Mail {
+        to: email.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!("Someone, hopefully you, asked to reset the password of {}. Choose a new one at\n\n\
+                       {}\n\n\
+                       The link works once, for the next {} minutes. If you didn't ask for it, just ignore \
+                       this mail and your password stays as it is.\n",
+                      username, link, (config.password_reset_lifetime + 59) / 60),
+    }
+}
+
+/// `POST /password-reset`: mail a reset link to the address of the account.
+/// Always 202, so that it doesn't tell which accounts exist.
+pub fn request_password_reset(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: RequestPasswordReset| {
+        let s = S::borrow_from(&state);
+        match find_credentials(&*store, &body.email) {
+            Ok(db::Credentials { id, username, banned: false, email: Some(email), .. }) => {
+                let token = auth::new_opaque_token();
+                let expires_at = Utc::now() + Duration::seconds(s.config.password_reset_lifetime as i64);
+                store.create_password_reset(id, &auth::hash_opaque_token(&token), expires_at)?;
+                if let Err(e) = s.mailer.send(&password_reset_mail(&s.config, &username, &email, &token)) {
+                    eprintln!("Could not send a password reset mail: {}", e);
+                }
+            }
+            Ok(_) | Err(DbError::NotFound) => {}
+            Err(e) => return Err(From::from(e)),
+        }
+        Ok(create_response(&state, StatusCode::ACCEPTED, mime::APPLICATION_JSON, Body::empty()))
+    })
+}
+
+/// `POST /password-reset/confirm`: set a new password with the token of a
+/// reset link. Every session of the account ends.
+pub fn reset_password(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: ResetPassword| {
+        let s = S::borrow_from(&state);
+        let tx = store.begin()?;
+        let account_id = tx.use_password_reset(&auth::hash_opaque_token(&body.token)).map_err(|e| match e {
+            DbError::NotFound => invalid_field("token", "is invalid or has expired"),
+            e => HttpResult::from(e),
+        })?;
+        let credentials = tx.get_credentials(&tx.get_account(account_id)?.username)?;
+        password::validate(&s.config.password_policy, "new_password", &credentials.username, &body.new_password)?;
+        tx.set_password(account_id, &password::hash_password(&body.new_password, s.config.bcrypt_cost)?)?;
+        tx.revoke_account_tokens(account_id)?;
+        tx.commit()?;
+        // Failed guesses of the old password don't count against the new one
+        s.limits.clear_failed_logins(lockout_key(&credentials))?;
+        Ok(no_content(&state))
+    })
+}
+
+pub fn refresh_token(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: RefreshToken| {
+        let s = S::borrow_from(&state);
+        let tx = store.begin()?;
+        let expired = || HttpResult::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Session has expired");
+        let stored = tx.get_refresh_token(&auth::hash_opaque_token(&body.refresh_token))
+            .map_err(|e| match e {
+                DbError::NotFound => expired(),
+                e => HttpResult::from(e),
+            })?;
+        if !stored.used && (stored.revoked || stored.expires_at < Utc::now()) {
+            return Err(expired());
+        }
+        // Marked conditionally, so a refresh racing this one with the same
+        // token can't also find it unused
+        if stored.used || !tx.mark_refresh_token_used(stored.id)? {
+            // A rotated token is being replayed, so either the client or
+            // an attacker holds a stolen copy. Kill the whole chain.
+            tx.revoke_refresh_token_family(&stored.family)?;
+            tx.commit()?;
+            return Err(expired());
+        }
+        let token = get_token(&*tx, s, stored.account_id, Some(stored.family))?;
+        tx.commit()?;
+        json_response(&state, StatusCode::OK, &token)
+    })
+}
+
+pub fn logout(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: RefreshToken| {
+        let account = AuthenticatedAccount::borrow_from(&state);
+        let tx = store.begin()?;
+        tx.revoke_token(&account.jti, account.expires_at)?;
+        match tx.get_refresh_token(&auth::hash_opaque_token(&body.refresh_token)) {
+            Ok(stored) if stored.account_id == account.id =>
+                tx.revoke_refresh_token_family(&stored.family)?,
+            Ok(_) | Err(DbError::NotFound) => {}
+            Err(e) => return Err(From::from(e)),
+        }
+        tx.commit()?;
+        Ok(create_response(&state, StatusCode::NO_CONTENT, mime::APPLICATION_JSON, Body::empty()))
+    })
+}
+
+pub fn jwks(state: State) -> (State, hyper::Response<Body>) {
+    let jwks = S::borrow_from(&state).keys.jwks().to_string();
+    let response = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, jwks);
+    (state, response)
+}
+
+pub fn get_account(state: State, store: Store) -> (State, hyper::Response<Body>) {
     let id = AccountId::borrow_from(&state).id;
-    let account = db::get_account(connection, id);
-    (state, serde_json::to_string(&account).unwrap())
+    let result = store.get_account(id)
+        .map_err(HttpResult::from)
+        .and_then(|account| json_response(&state, StatusCode::OK, &account));
+    respond(state, result)
 }
 
-pub fn get_threads(state: State, connection: db::Connection) -> (State, String) {
-    (state, serde_json::to_string(&db::get_threads(connection)).unwrap())
+pub fn get_threads(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let query = PageQuery::borrow_from(&state);
+    let result = query.thread_sort()
+        .and_then(|sort| {
+            let page = query.page_request(|cursor| db::ThreadKey::from_cursor(sort, cursor))?;
+            Ok(store.get_threads(sort, &page)?)
+        })
+        .and_then(|threads| json_response(&state, StatusCode::OK, &threads));
+    respond(state, result)
 }
 
-pub fn get_thread(state: State, connection: db::Connection) -> (State, String) {
+pub fn get_thread(state: State, store: Store) -> (State, hyper::Response<Body>) {
     let id = ThreadId::borrow_from(&state).id;
-    let thread = db::get_thread(connection, id);
-    (state, serde_json::to_string(&thread).unwrap())
+    let result = PageQuery::borrow_from(&state).page_request(|cursor| cursor.parse().ok())
+        .and_then(|page| Ok(store.get_thread(id, &page)?))
+        .and_then(|thread| json_response(&state, StatusCode::OK, &thread));
+    respond(state, result)
 }
 
-pub fn create_thread(state: State, connection: db::Connection) -> Box<HandlerFuture> {
-    with_json(state, |state, thread: CreateThread| {
-        let headers = HeaderMap::borrow_from(&state);
-        let token = headers.get("token")?.to_str()?;
-        let sub: i32 = auth::unsign(token)?.1["sub"].as_i64()?.try_into()?;
+pub fn search(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let query = SearchQuery::borrow_from(&state);
+    let q = query.q.as_ref().map(|q| q.trim()).unwrap_or("");
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_RESULTS);
+    let result = if q.is_empty() {
+        Err(invalid_field("q", "must not be empty"))
+    } else if limit < 1 || limit > MAX_PAGE_SIZE {
+        Err(invalid_field("limit", &format!("must be between 1 and {}", MAX_PAGE_SIZE)))
+    } else {
+        store.search(q, limit).map_err(HttpResult::from)
+    };
+    let result = result.and_then(|results| json_response(&state, StatusCode::OK, &results));
+    respond(state, result)
+}
 
-        db::create_thread(connection, sub, &thread.title);
+pub fn create_thread(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, thread: CreateThread| {
+        require_fields(&[("title", &thread.title)])?;
+        let account = AuthenticatedAccount::borrow_from(&state);
+        require_verified(&*store, &S::borrow_from(&state).config, account.id)?;
+        store.create_thread(account.id, &thread.title)?;
         Ok(create_response(state, StatusCode::CREATED, mime::APPLICATION_JSON, Body::empty()))
     })
 }
 
 
-pub fn create_message(state: State, connection: db::Connection) -> Box<HandlerFuture> {
-    with_json(state, |state, message: CreateMessage| {
+pub fn create_message(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, message: CreateMessage| {
+        if message.attachment_ids.is_empty() {
+            require_fields(&[("content", &message.content)])?;
+        }
         let thread_id = ThreadId::borrow_from(&state).id;
-        let headers = HeaderMap::borrow_from(&state);
-        let token = headers.get("token")?.to_str()?;
-        let sub: i32 = auth::unsign(token)?.1["sub"].as_i64()?.try_into()?;
-        db::create_message(connection, sub, thread_id, &message.content);
+        let account = AuthenticatedAccount::borrow_from(&state);
+        require_verified(&*store, &S::borrow_from(&state).config, account.id)?;
+        let thread = store.get_thread_ownership(thread_id)?;
+        if thread.locked && !account.has_role(Role::Moderator) {
+            return Err(forbidden("The thread is locked"));
+        }
+        if let Some(parent_id) = message.parent_id {
+            match store.get_message(thread_id, parent_id) {
+                Ok(_) => {}
+                Err(DbError::NotFound) => return Err(invalid_field("parent_id", "is not a message in this thread")),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let content_html = markdown::render(&message.content);
+        let tx = store.begin()?;
+        let id = tx.create_message(account.id, thread_id, &message.content, &content_html, message.parent_id)?;
+        if !message.attachment_ids.is_empty() {
+            tx.attach(account.id, id, &message.attachment_ids).map_err(|e| match e {
+                DbError::NotFound => invalid_field("attachment_ids", "must be your own uploads, attached only once"),
+                e => HttpResult::from(e),
+            })?;
+        }
+        tx.commit()?;
         Ok(create_response(&state, StatusCode::CREATED, mime::APPLICATION_JSON, Body::empty()))
     })
 }
 
+pub fn update_thread(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, thread: UpdateThread| {
+        require_fields(&[("title", &thread.title)])?;
+        let id = ThreadId::borrow_from(&state).id;
+        let account = AuthenticatedAccount::borrow_from(&state);
+        require_author(account, store.get_thread_ownership(id)?)?;
+        store.update_thread(id, &thread.title)?;
+        Ok(no_content(&state))
+    })
+}
+
+pub fn delete_thread(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let id = ThreadId::borrow_from(&state).id;
+    let account = AuthenticatedAccount::borrow_from(&state);
+    let result = store.get_thread_ownership(id)
+        .map_err(HttpResult::from)
+        .and_then(|ownership| require_author(account, ownership))
+        .and_then(|_| Ok(store.delete_thread(id)?))
+        .map(|_| no_content(&state));
+    respond(state, result)
+}
+
+pub fn update_message(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, message: UpdateMessage| {
+        require_fields(&[("content", &message.content)])?;
+        let MessageId { id: thread_id, message_id } = *MessageId::borrow_from(&state);
+        let account = AuthenticatedAccount::borrow_from(&state);
+        let content_html = markdown::render(&message.content);
+        let tx = store.begin()?;
+        require_author(account, tx.get_message_ownership(thread_id, message_id)?)?;
+        tx.update_message(account.id, thread_id, message_id, &message.content, &content_html)?;
+        tx.commit()?;
+        Ok(no_content(&state))
+    })
+}
+
+pub fn delete_message(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let MessageId { id: thread_id, message_id } = *MessageId::borrow_from(&state);
+    let account = AuthenticatedAccount::borrow_from(&state);
+    let result = store.get_message_ownership(thread_id, message_id)
+        .map_err(HttpResult::from)
+        .and_then(|ownership| require_author(account, ownership))
+        .and_then(|_| Ok(store.delete_message(thread_id, message_id)?))
+        .map(|_| no_content(&state));
+    respond(state, result)
+}
+
+/// Render message content the way it would be shown once posted
+pub fn preview_markdown(state: State) -> Box<HandlerFuture> {
+    with_json(state, |state, message: UpdateMessage| {
+        json_response(state, StatusCode::OK, &MessagePreview { content_html: markdown::render(&message.content) })
+    })
+}
+
+pub fn get_message_revisions(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let MessageId { id: thread_id, message_id } = *MessageId::borrow_from(&state);
+    let result = store.get_message_ownership(thread_id, message_id)
+        .and_then(|_| store.get_message_revisions(thread_id, message_id))
+        .map_err(HttpResult::from)
+        .and_then(|revisions| json_response(&state, StatusCode::OK, &revisions));
+    respond(state, result)
+}
+
+/// Change the role of another account. Admin only.
+pub fn set_account_role(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: SetRole| {
+        let id = AccountId::borrow_from(&state).id;
+        let account = AuthenticatedAccount::borrow_from(&state);
+        if id == account.id {
+            // Keeps the last admin from locking everyone out
+            return Err(forbidden("You can't change your own role"));
+        }
+        let tx = store.begin()?;
+        tx.set_role(id, body.role)?;
+        // Existing tokens carry the old role in their claims
+        tx.revoke_account_tokens(id)?;
+        tx.commit()?;
+        Ok(no_content(&state))
+    })
+}
+
+/// Ban or unban an account. Moderators may only ban plain users.
+pub fn set_account_banned(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: SetBanned| {
+        let id = AccountId::borrow_from(&state).id;
+        let account = AuthenticatedAccount::borrow_from(&state);
+        let tx = store.begin()?;
+        if tx.get_role(id)? >= account.role {
+            return Err(forbidden("You can only ban accounts with a lesser role"));
+        }
+        tx.set_banned(id, body.banned, body.reason.as_ref().map(|s| s.as_str()))?;
+        if body.banned {
+            tx.revoke_account_tokens(id)?;
+        }
+        tx.commit()?;
+        Ok(no_content(&state))
+    })
+}
+
+pub fn set_thread_locked(state: State, store: Store) -> Box<HandlerFuture> {
+    with_json(state, move |state, body: SetLocked| {
+        let id = ThreadId::borrow_from(&state).id;
+        store.set_thread_locked(id, body.locked)?;
+        Ok(no_content(&state))
+    })
+}
+
 pub fn router(state: S) -> Router {
-    let middleware = StateMiddleware::new(state);
-    let pipeline = new_pipeline().add(middleware).build();
-    let (chain, pipelines) = single_pipeline(pipeline);
-
-    // build a router with the chain & pipeline
-    build_router(chain, pipelines, |route| {
-        route.post("/login").to_new_handler(r(login));
-        route.post("/account").to_new_handler(r(new_account));
-        route.get("/account/:id").to_new_handler(r(get_account));
-        route.get("/thread").to_new_handler(r(get_threads));
-        route.get("/thread/:id")
-            .with_path_extractor::<ThreadId>()
-            .to_new_handler(r(get_thread));
-        route.post("/thread").to_new_handler(r(create_thread));
-        route.post("/thread/:id")
+    let limits = state.config.rate_limits.clone();
+    let pipelines = new_pipeline_set();
+    let (pipelines, default) = pipelines.add(
+        new_pipeline().add(StateMiddleware::new(state)).build());
+    let (pipelines, required) = pipelines.add(
+        new_pipeline().add(AuthMiddleware::new(AuthPolicy::Required)).build());
+    let (pipelines, optional) = pipelines.add(
+        new_pipeline().add(AuthMiddleware::new(AuthPolicy::Optional)).build());
+    let (pipelines, moderator) = pipelines.add(
+        new_pipeline().add(AuthMiddleware::new(AuthPolicy::Role(Role::Moderator))).build());
+    let (pipelines, admin) = pipelines.add(
+        new_pipeline().add(AuthMiddleware::new(AuthPolicy::Role(Role::Admin))).build());
+    let limit = |route, limit| new_pipeline().add(RateLimitMiddleware::new(route, limit)).build();
+    let (pipelines, login_limit) = pipelines.add(limit("login", limits.login));
+    let (pipelines, account_limit) = pipelines.add(limit("create_account", limits.create_account));
+    let (pipelines, thread_limit) = pipelines.add(limit("create_thread", limits.create_thread));
+    let (pipelines, message_limit) = pipelines.add(limit("create_message", limits.create_message));
+    let (pipelines, reset_limit) = pipelines.add(limit("password_reset", limits.password_reset));
+    let (pipelines, verification_limit) = pipelines.add(limit("verification", limits.verification));
+    let (pipelines, upload_limit) = pipelines.add(limit("upload", limits.upload));
+    let pipelines = finalize_pipeline_set(pipelines);
+
+    // The auth pipelines run after the default one, which provides `S`
+    let default_chain = (default, ());
+    let auth_required = (required, default_chain);
+    let auth_optional = (optional, default_chain);
+    let auth_moderator = (moderator, default_chain);
+    let auth_admin = (admin, default_chain);
+    // Rate limits go last, to see the account of the request
+    let login_limited = (login_limit, default_chain);
+    let account_limited = (account_limit, default_chain);
+    let thread_limited = (thread_limit, auth_required);
+    let message_limited = (message_limit, auth_required);
+    let reset_limited = (reset_limit, default_chain);
+    let verification_limited = (verification_limit, auth_required);
+    let upload_limited = (upload_limit, auth_required);
+    let guess_limited = (login_limit, auth_required);
+
+    build_router(default_chain, pipelines, |route| {
+        route.get("/.well-known/jwks.json").to(jwks);
+        route.get("/events/ws")
+            .with_query_string_extractor::<SocketQuery>()
+            .to(websocket::events_socket);
+        route.get("/events").to_new_handler(r(sse::events));
+        route.get("/thread/:id/events")
             .with_path_extractor::<ThreadId>()
-            .to_new_handler(r(create_message));
+            .to_new_handler(r(sse::thread_events));
+        route.with_pipeline_chain(login_limited, |route| {
+            route.post("/login").to_new_handler(r(login));
+            route.post("/login/mfa").to_new_handler(r(login_mfa));
+        });
+        route.post("/token/refresh").to_new_handler(r(refresh_token));
+        route.with_pipeline_chain(account_limited, |route| {
+            route.post("/account").to_new_handler(r(new_account));
+        });
+        route.with_pipeline_chain(reset_limited, |route| {
+            route.post("/password-reset").to_new_handler(r(request_password_reset));
+        });
+        // Like reset tokens, verification tokens can't be guessed
+        route.post("/account/verify").to_new_handler(r(verify_email));
+        // Guessing passwords or codes counts against the login limit
+        route.with_pipeline_chain(guess_limited, |route| {
+            route.post("/account/password").to_new_handler(r(change_password));
+            route.post("/account/totp/confirm").to_new_handler(r(confirm_totp));
+            route.post("/account/totp/recovery-codes").to_new_handler(r(new_recovery_codes));
+            route.post("/account/totp/disable").to_new_handler(r(disable_totp));
+        });
+        route.with_pipeline_chain(verification_limited, |route| {
+            route.post("/account/verify/resend").to_new_handler(r(resend_verification));
+        });
+        // Reset tokens are far too long to guess, so this one isn't limited
+        route.post("/password-reset/confirm").to_new_handler(r(reset_password));
+        route.get("/account/:id")
+            .with_path_extractor::<AccountId>()
+            .to_new_handler(r(get_account));
+
+        route.with_pipeline_chain(auth_optional, |route| {
+            route.get("/thread")
+                .with_query_string_extractor::<PageQuery>()
+                .to_new_handler(r(get_threads));
+            route.get("/thread/:id")
+                .with_path_extractor::<ThreadId>()
+                .with_query_string_extractor::<PageQuery>()
+                .to_new_handler(r(get_thread));
+            route.get("/search")
+                .with_query_string_extractor::<SearchQuery>()
+                .to_new_handler(r(search));
+            route.get("/thread/:id/message/:message_id/revisions")
+                .with_path_extractor::<MessageId>()
+                .to_new_handler(r(get_message_revisions));
+        });
+
+        route.with_pipeline_chain(auth_required, |route| {
+            route.post("/logout").to_new_handler(r(logout));
+            route.get("/account/totp").to_new_handler(r(get_totp));
+            route.post("/account/totp").to_new_handler(r(start_totp));
+            route.post("/markdown/preview").to(preview_markdown);
+            route.get("/attachment/:id")
+                .with_path_extractor::<attachments::AttachmentId>()
+                .to_new_handler(r(attachments::download));
+            route.get("/attachment/:id/thumbnail")
+                .with_path_extractor::<attachments::AttachmentId>()
+                .to_new_handler(r(attachments::download_thumbnail));
+            route.put("/thread/:id")
+                .with_path_extractor::<ThreadId>()
+                .to_new_handler(r(update_thread));
+            route.delete("/thread/:id")
+                .with_path_extractor::<ThreadId>()
+                .to_new_handler(r(delete_thread));
+            route.put("/thread/:id/message/:message_id")
+                .with_path_extractor::<MessageId>()
+                .to_new_handler(r(update_message));
+            route.delete("/thread/:id/message/:message_id")
+                .with_path_extractor::<MessageId>()
+                .to_new_handler(r(delete_message));
+        });
+
+        route.with_pipeline_chain(thread_limited, |route| {
+            route.post("/thread").to_new_handler(r(create_thread));
+        });
+        route.with_pipeline_chain(message_limited, |route| {
+            route.post("/thread/:id")
+                .with_path_extractor::<ThreadId>()
+                .to_new_handler(r(create_message));
+        });
+        route.with_pipeline_chain(upload_limited, |route| {
+            route.post("/attachment").to_new_handler(r(attachments::upload));
+        });
+
+        route.with_pipeline_chain(auth_moderator, |route| {
+            route.put("/admin/account/:id/ban")
+                .with_path_extractor::<AccountId>()
+                .to_new_handler(r(set_account_banned));
+            route.put("/admin/thread/:id/lock")
+                .with_path_extractor::<ThreadId>()
+                .to_new_handler(r(set_thread_locked));
+        });
+
+        route.with_pipeline_chain(auth_admin, |route| {
+            route.put("/admin/account/:id/role")
+                .with_path_extractor::<AccountId>()
+                .to_new_handler(r(set_account_role));
+        });
+
         route.get("/").to_file("assets/index.html");
         route.get("/*").to_dir(
             FileOptions::new("assets")
@@ -131,22 +1010,605 @@ pub fn router(state: S) -> Router {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use gotham::test::TestServer;
     use hyper::StatusCode;
+    use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
     use uuid::Uuid;
 
+    use crate::config::Bucket;
+    use crate::testing::{PASSWORD, TestApp, error, expect, json};
+
     #[test]
     fn receive_hello_world_response() {
-        let s = S::new();
-        let test_server = TestServer::new(router(s)).unwrap();
-        let response = test_server
-            .client()
-            .post("http://localhost/account",
-                  format!("{{\"username\": \"{}\", \"password\": \"{}\"}}", Uuid::new_v4(), Uuid::new_v4()),
-                  mime::APPLICATION_JSON)
-            .perform()
-            .unwrap();
+        let app = TestApp::new();
+        let username = Uuid::new_v4().to_string();
+        let body = json!({
+            "username": username, "email": format!("{}@example.com", username), "password": Uuid::new_v4().to_string(),
+        });
+        let response = app.post("/account", &body.to_string(), None);
 
         assert_eq!(response.status(), StatusCode::CREATED);
     }
+
+    #[test]
+    fn login_checks_the_password() {
+        let app = TestApp::new();
+        app.create_account("alice");
+        assert_eq!(app.login("alice").role, Role::User);
+
+        let wrong = json!({"email": "alice@example.com", "password": "wrong"}).to_string();
+        assert_eq!(error(app.post("/login", &wrong, None), StatusCode::NOT_FOUND).code, ErrorCode::NotFound);
+        let unknown = json!({"email": "bob@example.com", "password": PASSWORD}).to_string();
+        error(app.post("/login", &unknown, None), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn passwords_are_checked_and_changed() {
+        let app = TestApp::new();
+        let weak = json!({"username": "alice", "email": "alice@example.com", "password": "letmein"}).to_string();
+        let invalid = error(app.post("/account", &weak, None), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(invalid.details[0].field, "password");
+
+        let alice = app.create_account("alice");
+        let change = |current: &str, new: &str| app.post("/account/password", &json!({
+            "current_password": current, "new_password": new,
+        }).to_string(), Some(&alice.token));
+        error(change("wrong", "a new long password"), StatusCode::UNPROCESSABLE_ENTITY);
+        error(change(PASSWORD, "password"), StatusCode::UNPROCESSABLE_ENTITY);
+        let token: Token = json(expect(change(PASSWORD, "a new long password"), StatusCode::OK));
+
+        // The old session is over, the new one works
+        error(app.post("/thread", r#"{"title": "Hi"}"#, Some(&alice.token)), StatusCode::UNAUTHORIZED);
+        app.create_thread(&token, "Hi");
+        let old = json!({"email": "alice@example.com", "password": PASSWORD}).to_string();
+        error(app.post("/login", &old, None), StatusCode::NOT_FOUND);
+        let new = json!({"email": "alice@example.com", "password": "a new long password"}).to_string();
+        expect(app.post("/login", &new, None), StatusCode::OK);
+    }
+
+    #[test]
+    fn wrong_current_passwords_lock_the_account() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let change = |current: &str| app.post("/account/password", &json!({
+            "current_password": current, "new_password": "a new long password",
+        }).to_string(), Some(&alice.token));
+        for _ in 0..app.config.rate_limits.lockout.max_failures {
+            error(change("wrong"), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        error(change(PASSWORD), StatusCode::TOO_MANY_REQUESTS);
+        let right = json!({"email": "alice@example.com", "password": PASSWORD}).to_string();
+        error(app.post("/login", &right, None), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn passwords_are_rehashed_with_the_configured_cost() {
+        let mut config = Config::default();
+        config.bcrypt_cost = 4;
+        let app = TestApp::with_config(config);
+        let alice = app.create_account("alice");
+        app.store.set_password(alice.account_id, &password::hash_password(PASSWORD, 5).unwrap()).unwrap();
+        app.login("alice");
+        let hashed = app.store.get_credentials("alice").unwrap().password;
+        assert!(!password::needs_rehash(&hashed, 4));
+    }
+
+    #[test]
+    fn passwords_are_reset_with_mailed_links() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let mailed = app.mail.sent().len();
+        let request = |email: &str| app.post("/password-reset", &json!({"email": email}).to_string(), None);
+        expect(request("nobody@example.com"), StatusCode::ACCEPTED);
+        assert_eq!(app.mail.sent().len(), mailed);
+        expect(request("Alice@Example.com"), StatusCode::ACCEPTED);
+        assert_eq!(app.mail.sent().len(), mailed + 1);
+        let token = &app.mailed_token("alice@example.com", "#reset-password/");
+
+        let reset = |token: &str, password: &str| app.post("/password-reset/confirm", &json!({
+            "token": token, "new_password": password,
+        }).to_string(), None);
+        error(reset("made up", "a new long password"), StatusCode::UNPROCESSABLE_ENTITY);
+        // A rejected password doesn't use up the link
+        error(reset(token, "short"), StatusCode::UNPROCESSABLE_ENTITY);
+        expect(reset(token, "a new long password"), StatusCode::NO_CONTENT);
+        error(reset(token, "another long password"), StatusCode::UNPROCESSABLE_ENTITY);
+
+        error(app.post("/thread", r#"{"title": "Hi"}"#, Some(&alice.token)), StatusCode::UNAUTHORIZED);
+        let new = json!({"email": "alice@example.com", "password": "a new long password"}).to_string();
+        expect(app.post("/login", &new, None), StatusCode::OK);
+    }
+
+    #[test]
+    fn failed_logins_lock_the_account() {
+        let app = TestApp::new();
+        app.create_account("alice");
+        let wrong = json!({"email": "Alice@example.com", "password": "wrong"}).to_string();
+        for _ in 0..app.config.rate_limits.lockout.max_failures {
+            error(app.post("/login", &wrong, None), StatusCode::NOT_FOUND);
+        }
+        let right = json!({"email": "alice@example.com", "password": PASSWORD}).to_string();
+        let response = app.post("/login", &right, None);
+        let retry_after: u64 = response.headers()[RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        let locked = error(response, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(locked.code, ErrorCode::TooManyRequests);
+        assert_eq!(locked.retry_after, Some(retry_after));
+        assert!(retry_after > 0 && retry_after <= app.config.rate_limits.lockout.base_delay);
+        // Other accounts aren't affected
+        app.create_account("bob");
+        app.login("bob");
+    }
+
+    #[test]
+    fn password_resets_lift_the_lockout() {
+        let app = TestApp::new();
+        app.create_account("alice");
+        let wrong = json!({"email": "alice@example.com", "password": "wrong"}).to_string();
+        for _ in 0..app.config.rate_limits.lockout.max_failures {
+            error(app.post("/login", &wrong, None), StatusCode::NOT_FOUND);
+        }
+        let right = json!({"email": "alice@example.com", "password": PASSWORD}).to_string();
+        error(app.post("/login", &right, None), StatusCode::TOO_MANY_REQUESTS);
+
+        expect(app.post("/password-reset", r#"{"email": "alice@example.com"}"#, None), StatusCode::ACCEPTED);
+        let token = app.mailed_token("alice@example.com", "#reset-password/");
+        let reset = json!({"token": token, "new_password": "a new long password"}).to_string();
+        expect(app.post("/password-reset/confirm", &reset, None), StatusCode::NO_CONTENT);
+        let new = json!({"email": "alice@example.com", "password": "a new long password"}).to_string();
+        expect(app.post("/login", &new, None), StatusCode::OK);
+    }
+
+    #[test]
+    fn posting_is_rate_limited() {
+        let mut config = Config::default();
+        config.rate_limits.create_message.per_account = Some(Bucket { burst: 2, per_minute: 1.0 });
+        let app = TestApp::with_config(config);
+        let alice = app.create_account("alice");
+        let bob = app.create_account("bob");
+        let thread_id = app.create_thread(&alice, "Limits");
+        app.create_message(&alice, thread_id, "One");
+        app.create_message(&alice, thread_id, "Two");
+        let body = json!({"content": "Three"}).to_string();
+        let response = app.post(&format!("/thread/{}", thread_id), &body, Some(&alice.token));
+        let retry_after = error(response, StatusCode::TOO_MANY_REQUESTS).retry_after.unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+        // The limit is per account
+        app.create_message(&bob, thread_id, "Three");
+    }
+
+    #[test]
+    fn usernames_are_unique() {
+        let app = TestApp::new();
+        app.create_account("alice");
+        let body = json!({"username": "alice", "email": "other@example.com", "password": PASSWORD}).to_string();
+        assert_eq!(error(app.post("/account", &body, None), StatusCode::CONFLICT).code, ErrorCode::Conflict);
+        let body = json!({"username": "alicia", "email": "ALICE@example.com", "password": PASSWORD}).to_string();
+        let taken = error(app.post("/account", &body, None), StatusCode::CONFLICT);
+        assert_eq!(taken.message, "Email address is already in use");
+    }
+
+    #[test]
+    fn accounts_log_in_with_their_email_address() {
+        let app = TestApp::new();
+        let invalid = json!({"username": "alice", "email": "alice", "password": PASSWORD}).to_string();
+        assert_eq!(error(app.post("/account", &invalid, None), StatusCode::UNPROCESSABLE_ENTITY).details[0].field,
+                   "email");
+        let at = json!({"username": "a@b.com", "email": "alice@example.com", "password": PASSWORD}).to_string();
+        assert_eq!(error(app.post("/account", &at, None), StatusCode::UNPROCESSABLE_ENTITY).details[0].field,
+                   "username");
+
+        let alice = app.create_account("alice");
+        let login = json!({"email": " Alice@Example.COM", "password": PASSWORD}).to_string();
+        let token: Token = json(expect(app.post("/login", &login, None), StatusCode::OK));
+        assert_eq!(token.account_id, alice.account_id);
+        assert!(token.email_verified);
+        // Only accounts without an address log in with their username
+        let username = json!({"email": "alice", "password": PASSWORD}).to_string();
+        error(app.post("/login", &username, None), StatusCode::NOT_FOUND);
+        // The address isn't shown to anyone
+        let account = expect(app.get(&format!("/account/{}", alice.account_id), None), StatusCode::OK);
+        assert!(!account.read_utf8_body().unwrap().contains("example.com"));
+    }
+
+    #[test]
+    fn logins_need_a_second_factor_once_it_is_enabled() {
+        let mut config = Config::default();
+        config.rate_limits.login.per_ip = Some(Bucket { burst: 100, per_minute: 60.0 });
+        // Used up challenges are tested here, locked out accounts below
+        config.rate_limits.lockout.max_failures = 100;
+        let app = TestApp::with_config(config);
+        let alice = app.create_account("alice");
+        let status: TotpStatus = json(expect(app.get("/account/totp", Some(&alice.token)), StatusCode::OK));
+        assert!(!status.enabled);
+        let enrollment: TotpEnrollment = json(expect(app.post("/account/totp", "", Some(&alice.token)),
+                                                     StatusCode::CREATED));
+        assert!(enrollment.uri.starts_with("otpauth://totp/fstack:alice%40example.com?secret="));
+        assert!(enrollment.qr_code.starts_with("data:image/png;base64,"));
+        let secret = app.store.get_totp(alice.account_id).unwrap().secret.unwrap();
+        assert_eq!(totp::base32(&secret), enrollment.secret);
+
+        let confirm = |code: &str| app.post("/account/totp/confirm", &json!({"code": code}).to_string(),
+                                            Some(&alice.token));
+        error(confirm(&totp::code_at(&secret, 10)), StatusCode::UNPROCESSABLE_ENTITY);
+        let recovery: RecoveryCodes = json(expect(confirm(&totp::code_at(&secret, 0)), StatusCode::OK));
+        error(app.post("/account/totp", "", Some(&alice.token)), StatusCode::CONFLICT);
+
+        let login = json!({"email": "alice@example.com", "password": PASSWORD}).to_string();
+        let challenge = || match json(expect(app.post("/login", &login, None), StatusCode::OK)) {
+            LoginResult::MfaRequired(challenge) => challenge.mfa_token,
+            LoginResult::Token(_) => panic!("logged in without a second factor"),
+        };
+        let mfa = |mfa_token: &str, code: &str| app.post("/login/mfa", &json!({
+            "mfa_token": mfa_token, "code": code,
+        }).to_string(), None);
+        let mfa_token = challenge();
+        assert_eq!(error(mfa(&mfa_token, &totp::code_at(&secret, 10)), StatusCode::UNPROCESSABLE_ENTITY)
+                       .details[0].field, "code");
+        let code = totp::code_at(&secret, 1);
+        let token: Token = json(expect(mfa(&mfa_token, &code), StatusCode::OK));
+        assert_eq!(token.account_id, alice.account_id);
+        // Challenges and codes only work once
+        error(mfa(&mfa_token, &totp::code_at(&secret, 1)), StatusCode::UNPROCESSABLE_ENTITY);
+        error(mfa(&challenge(), &code), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let mfa_token = challenge();
+        expect(mfa(&mfa_token, &recovery.codes[0].to_uppercase()), StatusCode::OK);
+        let mfa_token = challenge();
+        error(mfa(&mfa_token, &recovery.codes[0]), StatusCode::UNPROCESSABLE_ENTITY);
+        for _ in 1..totp::MAX_ATTEMPTS {
+            error(mfa(&mfa_token, "not a code"), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let used_up = error(mfa(&mfa_token, &recovery.codes[1]), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(used_up.details[0].field, "mfa_token");
+
+        let status: TotpStatus = json(expect(app.get("/account/totp", Some(&token.token)), StatusCode::OK));
+        assert!(status.enabled);
+        assert_eq!(status.recovery_codes_left, recovery.codes.len() as i64 - 1);
+        let disable = |password: &str, code: &str| app.post("/account/totp/disable", &json!({
+            "password": password, "code": code,
+        }).to_string(), Some(&token.token));
+        error(disable("wrong", &recovery.codes[1]), StatusCode::UNPROCESSABLE_ENTITY);
+        expect(disable(PASSWORD, &recovery.codes[1]), StatusCode::NO_CONTENT);
+        app.login("alice");
+    }
+
+    #[test]
+    fn wrong_codes_lock_the_account() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        expect(app.post("/account/totp", "", Some(&alice.token)), StatusCode::CREATED);
+        let secret = app.store.get_totp(alice.account_id).unwrap().secret.unwrap();
+        let confirm = json!({"code": totp::code_at(&secret, 0)}).to_string();
+        expect(app.post("/account/totp/confirm", &confirm, Some(&alice.token)), StatusCode::OK);
+
+        let login = json!({"email": "alice@example.com", "password": PASSWORD}).to_string();
+        let challenge = || match json(expect(app.post("/login", &login, None), StatusCode::OK)) {
+            LoginResult::MfaRequired(challenge) => challenge.mfa_token,
+            LoginResult::Token(_) => panic!("logged in without a second factor"),
+        };
+        let mfa = |mfa_token: &str, code: &str| app.post("/login/mfa", &json!({
+            "mfa_token": mfa_token, "code": code,
+        }).to_string(), None);
+        let spare = challenge();
+        // A new challenge for every guess doesn't give more guesses
+        for _ in 0..app.config.rate_limits.lockout.max_failures {
+            error(mfa(&challenge(), &totp::code_at(&secret, 10)), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        error(app.post("/login", &login, None), StatusCode::TOO_MANY_REQUESTS);
+        error(mfa(&spare, &totp::code_at(&secret, 1)), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn unverified_accounts_cannot_post() {
+        let app = TestApp::new();
+        let alice = app.create_unverified_account("alice");
+        assert!(!alice.email_verified);
+        let thread = r#"{"title": "Hi"}"#;
+        let unverified = error(app.post("/thread", thread, Some(&alice.token)), StatusCode::FORBIDDEN);
+        assert_eq!(unverified.message, "Verify your email address before posting");
+
+        // Only the latest link is any good once another one is sent
+        let first = app.mailed_token("alice@example.com", "#verify-email/");
+        expect(app.post("/account/verify/resend", "", Some(&alice.token)), StatusCode::ACCEPTED);
+        let second = app.mailed_token("alice@example.com", "#verify-email/");
+        assert_ne!(first, second);
+        let verify = |token: &str| app.post("/account/verify", &json!({"token": token}).to_string(), None);
+        error(verify("made up"), StatusCode::UNPROCESSABLE_ENTITY);
+        expect(verify(&second), StatusCode::NO_CONTENT);
+        error(verify(&first), StatusCode::UNPROCESSABLE_ENTITY);
+        app.create_thread(&alice, "Hi");
+        error(app.post("/account/verify/resend", "", Some(&alice.token)), StatusCode::CONFLICT);
+
+        let mut config = Config::default();
+        config.require_verified_email = false;
+        let app = TestApp::with_config(config);
+        let bob = app.create_unverified_account("bob");
+        app.create_thread(&bob, "Hi");
+    }
+
+    #[test]
+    fn creates_threads_and_messages() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let thread_id = app.create_thread(&alice, "Hello");
+        app.create_message(&alice, thread_id, "First!");
+
+        let thread: Thread = json(expect(app.get(&format!("/thread/{}", thread_id), None), StatusCode::OK));
+        assert_eq!((thread.title.as_str(), thread.creator.as_str()), ("Hello", "alice"));
+        assert_eq!(thread.message_count, 1);
+        let messages = thread.messages.unwrap().items;
+        assert_eq!(messages[0].content, "First!");
+        assert_eq!(messages[0].creator_id, alice.account_id);
+
+        error(app.get("/thread/999", None), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn replies_stay_in_their_thread() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let thread_id = app.create_thread(&alice, "Hello");
+        let other_thread_id = app.create_thread(&alice, "Elsewhere");
+        app.create_message(&alice, thread_id, "First!");
+        app.create_message(&alice, other_thread_id, "Over here");
+        let path = format!("/thread/{}", thread_id);
+        let first: Thread = json(expect(app.get(&path, None), StatusCode::OK));
+        let first_id = first.messages.unwrap().items[0].id;
+
+        let body = json!({"content": "Second", "parent_id": first_id}).to_string();
+        expect(app.post(&path, &body, Some(&alice.token)), StatusCode::CREATED);
+        let thread: Thread = json(expect(app.get(&path, None), StatusCode::OK));
+        let reply = &thread.messages.unwrap().items[1];
+        assert_eq!((reply.parent_id, reply.depth), (Some(first_id), 1));
+
+        let elsewhere: Thread = json(expect(app.get(&format!("/thread/{}", other_thread_id), None), StatusCode::OK));
+        let body = json!({"content": "Lost", "parent_id": elsewhere.messages.unwrap().items[0].id}).to_string();
+        let e = error(app.post(&path, &body, Some(&alice.token)), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(e.details[0].field, "parent_id");
+    }
+
+    #[test]
+    fn messages_are_rendered_to_sanitized_html() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let thread_id = app.create_thread(&alice, "Hello");
+        app.create_message(&alice, thread_id, "Some *emphasis*<script>alert(1)</script>");
+
+        let thread: Thread = json(expect(app.get(&format!("/thread/{}", thread_id), None), StatusCode::OK));
+        let message = &thread.messages.unwrap().items[0];
+        assert_eq!(message.content, "Some *emphasis*<script>alert(1)</script>");
+        assert_eq!(message.content_html, "<p>Some <em>emphasis</em></p>\n");
+
+        let body = json!({"content": "**bold**"}).to_string();
+        let preview: MessagePreview = json(expect(app.post("/markdown/preview", &body, Some(&alice.token)),
+                                                  StatusCode::OK));
+        assert_eq!(preview.content_html, "<p><strong>bold</strong></p>\n");
+    }
+
+    /// A 1x1 PNG
+    const PNG: &[u8] = b"\x89\x50\x4e\x47\x0d\x0a\x1a\x0a\x00\x00\x00\x0d\x49\x48\x44\x52\x00\x00\x00\x01\x00\x00\x00\
+                         \x01\x08\x02\x00\x00\x00\x90\x77\x53\xde\x00\x00\x00\x0c\x49\x44\x41\x54\x78\x9c\x63\xf8\
+                         \xcf\xc0\x00\x00\x03\x01\x01\x00\xc9\xfe\x92\xef\x00\x00\x00\x00\x49\x45\x4e\x44\xae\x42\
+                         \x60\x82";
+
+    #[test]
+    fn attachments_go_with_messages() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let bob = app.create_account("bob");
+        let thread_id = app.create_thread(&alice, "Pictures");
+        let image = app.create_attachment(&alice, "dot.png", PNG);
+        assert_eq!((image.content_type.as_str(), image.thumbnail), ("image/png", true));
+        let notes = app.create_attachment(&alice, "notes.txt", b"Hello");
+        assert_eq!((notes.content_type.as_str(), notes.thumbnail), ("text/plain; charset=utf-8", false));
+
+        // Until it's attached, an upload is only visible to the uploader
+        let path = format!("/attachment/{}", image.id);
+        error(app.get(&path, Some(&bob.token)), StatusCode::NOT_FOUND);
+        let download = expect(app.get(&path, Some(&alice.token)), StatusCode::OK);
+        assert_eq!(download.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(download.read_body().unwrap(), PNG);
+
+        let post = |token: &Token, ids: &[i32]| {
+            let body = json!({"content": "", "attachment_ids": ids}).to_string();
+            app.post(&format!("/thread/{}", thread_id), &body, Some(&token.token))
+        };
+        error(post(&bob, &[image.id]), StatusCode::UNPROCESSABLE_ENTITY);
+        expect(post(&alice, &[image.id, notes.id]), StatusCode::CREATED);
+        error(post(&alice, &[image.id]), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let thread: Thread = json(expect(app.get(&format!("/thread/{}", thread_id), None), StatusCode::OK));
+        let messages = thread.messages.unwrap().items;
+        assert_eq!(messages.len(), 1);
+        let ids: Vec<i32> = messages[0].attachments.iter().map(|attachment| attachment.id).collect();
+        assert_eq!(ids, vec![image.id, notes.id]);
+
+        let thumbnail = expect(app.get(&format!("{}/thumbnail", path), Some(&bob.token)), StatusCode::OK);
+        assert_eq!(thumbnail.headers()[CONTENT_TYPE], "image/png");
+        let download = expect(app.get(&format!("/attachment/{}", notes.id), Some(&bob.token)), StatusCode::OK);
+        assert_eq!(download.headers()[CONTENT_DISPOSITION],
+                   "attachment; filename=\"notes.txt\"; filename*=UTF-8''notes.txt");
+        error(app.get(&format!("/attachment/{}/thumbnail", notes.id), Some(&bob.token)), StatusCode::NOT_FOUND);
+        error(app.get(&path, None), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn uploads_are_checked() {
+        let mut config = Config::default();
+        config.max_attachment_size = 16;
+        let app = TestApp::with_config(config);
+        let alice = app.create_account("alice");
+        error(app.upload(&alice, "program.exe", b"MZ\x90\x00\x03\x00\x00\x00"), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        error(app.upload(&alice, "long.txt", &[b'a'; 17]), StatusCode::PAYLOAD_TOO_LARGE);
+        error(app.upload(&alice, "empty.txt", b""), StatusCode::UNPROCESSABLE_ENTITY);
+        error(app.post("/attachment", "{}", Some(&alice.token)), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    /// CRC-32 of PNG chunks
+    fn crc32(bytes: &[u8]) -> u32 {
+        !bytes.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ u32::from(byte), |crc, _| if crc & 1 == 1 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 })
+        })
+    }
+
+    #[test]
+    fn huge_images_are_rejected_before_decoding() {
+        // A few dozen bytes claiming to be 65535×65535 pixels
+        let mut png = PNG.to_vec();
+        png[16..24].copy_from_slice(&[0, 0, 0xff, 0xff, 0, 0, 0xff, 0xff]);
+        let crc = crc32(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.to_be_bytes());
+        // The checksum is right, so the header is read
+        assert_eq!(&crc32(&PNG[12..29]).to_be_bytes()[..], &PNG[29..33]);
+
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let rejected = error(app.upload(&alice, "bomb.png", &png), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(rejected.details[0].field, "file");
+        app.create_attachment(&alice, "dot.png", PNG);
+    }
+
+    #[test]
+    fn unattached_uploads_expire() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let thread_id = app.create_thread(&alice, "Pictures");
+        let attached = app.create_attachment(&alice, "dot.png", PNG);
+        let body = json!({"content": "", "attachment_ids": [attached.id]}).to_string();
+        expect(app.post(&format!("/thread/{}", thread_id), &body, Some(&alice.token)), StatusCode::CREATED);
+        let unattached = app.create_attachment(&alice, "notes.txt", b"Hello");
+
+        let keys = app.store.delete_unattached(Utc::now() + Duration::seconds(1)).unwrap();
+        assert_eq!(keys.len(), 1);
+        error(app.get(&format!("/attachment/{}", unattached.id), Some(&alice.token)), StatusCode::NOT_FOUND);
+        expect(app.get(&format!("/attachment/{}", attached.id), Some(&alice.token)), StatusCode::OK);
+        let body = json!({"content": "", "attachment_ids": [unattached.id]}).to_string();
+        error(app.post(&format!("/thread/{}", thread_id), &body, Some(&alice.token)), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn writing_needs_a_token() {
+        let app = TestApp::new();
+        let response = app.post("/thread", r#"{"title": "Hello"}"#, None);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer realm=\"fstack\"");
+        assert_eq!(error(response, StatusCode::UNAUTHORIZED).code, ErrorCode::Unauthorized);
+
+        error(app.post("/thread", r#"{"title": "Hello"}"#, Some("not.a.token")), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn only_authors_may_edit() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let bob = app.create_account("bob");
+        let thread_id = app.create_thread(&alice, "Hello");
+        app.create_message(&alice, thread_id, "First!");
+        let thread_path = format!("/thread/{}", thread_id);
+        let thread: Thread = json(expect(app.get(&thread_path, None), StatusCode::OK));
+        let message_path = format!("{}/message/{}", thread_path, thread.messages.unwrap().items[0].id);
+
+        let edit = json!({"content": "Second!"}).to_string();
+        error(app.put(&thread_path, r#"{"title": "Mine"}"#, Some(&bob.token)), StatusCode::FORBIDDEN);
+        error(app.put(&message_path, &edit, Some(&bob.token)), StatusCode::FORBIDDEN);
+        error(app.delete(&message_path, Some(&bob.token)), StatusCode::FORBIDDEN);
+
+        expect(app.put(&message_path, &edit, Some(&alice.token)), StatusCode::NO_CONTENT);
+        let revisions: Vec<MessageRevision> =
+            json(expect(app.get(&format!("{}/revisions", message_path), None), StatusCode::OK));
+        assert_eq!(revisions.len(), 1);
+        assert_eq!((revisions[0].content.as_str(), revisions[0].replaced_by.as_str()), ("First!", "alice"));
+
+        expect(app.delete(&thread_path, Some(&alice.token)), StatusCode::NO_CONTENT);
+        error(app.get(&thread_path, None), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn moderation_needs_a_role() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let thread_id = app.create_thread(&alice, "Hello");
+        let lock = format!("/admin/thread/{}/lock", thread_id);
+        error(app.put(&lock, r#"{"locked": true}"#, Some(&alice.token)), StatusCode::FORBIDDEN);
+
+        let moderator = app.create_account("moderator");
+        app.store.set_role(moderator.account_id, Role::Moderator).unwrap();
+        // The role is read from the token, so log in again
+        let moderator = app.login("moderator");
+        expect(app.put(&lock, r#"{"locked": true}"#, Some(&moderator.token)), StatusCode::NO_CONTENT);
+
+        let message = json!({"content": "Hi"}).to_string();
+        let locked = error(app.post(&format!("/thread/{}", thread_id), &message, Some(&alice.token)),
+                           StatusCode::FORBIDDEN);
+        assert_eq!(locked.message, "The thread is locked");
+    }
+
+    #[test]
+    fn malformed_json_is_a_bad_request() {
+        let app = TestApp::new();
+        let truncated = error(app.post("/account", r#"{"username": "#, None), StatusCode::BAD_REQUEST);
+        assert_eq!(truncated.code, ErrorCode::BadRequest);
+        assert!(truncated.request_id.is_some());
+        error(app.post("/account", r#"{"username": "alice"}"#, None), StatusCode::BAD_REQUEST);
+
+        let blank = error(app.post("/account", r#"{"username": " ", "email": "", "password": ""}"#, None),
+                          StatusCode::UNPROCESSABLE_ENTITY);
+        let fields: Vec<&str> = blank.details.iter().map(|detail| detail.field.as_str()).collect();
+        assert_eq!(fields, vec!["username", "email", "password"]);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let claims = json!({"sub": alice.account_id, "jti": auth::new_jti(), "role": "user",
+                            "exp": Utc::now().timestamp() - 60});
+        let expired = frank_jwt::encode(json!({}), &app.config.jwt_secret, &claims, frank_jwt::Algorithm::HS256)
+            .unwrap();
+
+        let response = app.post("/thread", r#"{"title": "Hello"}"#, Some(&expired));
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer realm=\"fstack\", error=\"invalid_token\"");
+        error(response, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn logout_revokes_the_session() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let body = json!({"refresh_token": alice.refresh_token}).to_string();
+        expect(app.post("/logout", &body, Some(&alice.token)), StatusCode::NO_CONTENT);
+
+        error(app.post("/thread", r#"{"title": "Hello"}"#, Some(&alice.token)), StatusCode::UNAUTHORIZED);
+        error(app.post("/token/refresh", &body, None), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn replayed_refresh_tokens_revoke_the_family() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let body = json!({"refresh_token": alice.refresh_token}).to_string();
+        let rotated: Token = json(expect(app.post("/token/refresh", &body, None), StatusCode::OK));
+        assert_ne!(rotated.refresh_token, alice.refresh_token);
+
+        error(app.post("/token/refresh", &body, None), StatusCode::UNAUTHORIZED);
+        let rotated_body = json!({"refresh_token": rotated.refresh_token}).to_string();
+        error(app.post("/token/refresh", &rotated_body, None), StatusCode::UNAUTHORIZED);
+        error(app.post("/thread", r#"{"title": "Hello"}"#, Some(&rotated.token)), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn racing_refreshes_only_rotate_once() {
+        let app = TestApp::new();
+        let alice = app.create_account("alice");
+        let hash = auth::hash_opaque_token(&alice.refresh_token);
+        // Both refreshes read the token before either of them marks it
+        let first = app.store.get_refresh_token(&hash).unwrap();
+        let second = app.store.get_refresh_token(&hash).unwrap();
+        assert!(!first.used && !second.used);
+        assert!(app.store.mark_refresh_token_used(first.id).unwrap());
+        assert!(!app.store.mark_refresh_token_used(second.id).unwrap());
+
+        // Which the handler treats as a replay
+        let body = json!({"refresh_token": alice.refresh_token}).to_string();
+        error(app.post("/token/refresh", &body, None), StatusCode::UNAUTHORIZED);
+        error(app.post("/thread", r#"{"title": "Hello"}"#, Some(&alice.token)), StatusCode::UNAUTHORIZED);
+    }
 }
@@ -0,0 +1,122 @@
+use futures::sync::mpsc::UnboundedReceiver;
+use futures::{stream, Stream};
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+use hyper::header::{HeaderValue, CACHE_CONTROL};
+use hyper::{Body, HeaderMap, StatusCode};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::timer::Interval;
+use types::Event;
+
+use crate::db::{Cursor, PageRequest};
+use crate::handler_utils::{HttpResult, respond};
+use crate::router::{S, ThreadId};
+use crate::store::Store;
+
+/// Comment sent regularly so proxies don't time out idle streams
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Most events replayed to a client resuming with `Last-Event-ID`
+const REPLAY_LIMIT: i64 = 500;
+
+/// Format an event for an event stream. Created messages (on thread streams)
+/// and created threads (on the global stream) carry their id as the event
+/// id, which the client sends back in `Last-Event-ID` when reconnecting.
+fn format_event(event: &Event) -> String {
+    let id = match event {
+        Event::MessageCreated { message, .. } => Some(message.id),
+        Event::ThreadCreated { thread } => Some(thread.id),
+        _ => None,
+    };
+    let data = serde_json::to_string(event).unwrap_or_default();
+    match id {
+        Some(id) => format!("id: {}\ndata: {}\n\n", id, data),
+        None => format!("data: {}\n\n", data),
+    }
+}
+
+fn last_event_id(state: &State) -> Option<i32> {
+    HeaderMap::borrow_from(state)
+        .get("last-event-id")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Stream `replay` followed by the live events accepted by `filter`
+fn event_stream<F>(replay: Vec<Event>, live: UnboundedReceiver<Arc<Event>>, filter: F) -> Body
+where F: Fn(&Event) -> bool + Send + 'static {
+    let replay = stream::iter_ok(replay.iter().map(format_event).collect::<Vec<_>>());
+    let live = live
+        .filter(move |event| filter(&**event))
+        .map(|event| format_event(&event));
+    let keep_alive = Interval::new_interval(KEEP_ALIVE)
+        .map(|_| ": keep-alive\n\n".to_string())
+        .map_err(|_| ());
+    let events = replay
+        .chain(live.select(keep_alive))
+        .map_err(|()| io::Error::new(io::ErrorKind::Other, "event stream closed"));
+    Body::wrap_stream(events)
+}
+
+fn stream_response(state: &State, body: Body) -> hyper::Response<Body> {
+    let mut response = create_response(state, StatusCode::OK, mime::TEXT_EVENT_STREAM, body);
+    response.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    // Keep nginx from buffering the stream
+    response.headers_mut().insert("x-accel-buffering", HeaderValue::from_static("no"));
+    response
+}
+
+/// `GET /events`: thread creation, update and deletion events
+pub fn events(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    // Subscribe before loading the replay, so nothing falls in between
+    let live = S::borrow_from(&state).events.subscribe();
+    let replay = match last_event_id(&state) {
+        Some(id) => store.get_threads_created_after(id, REPLAY_LIMIT)
+            .map(|threads| threads.into_iter().map(|thread| Event::ThreadCreated { thread }).collect()),
+        None => Ok(vec![]),
+    };
+    let result = replay
+        .map_err(HttpResult::from)
+        .map(|replay| stream_response(&state, event_stream(replay, live, |event| !event.is_message_event())));
+    respond(state, result)
+}
+
+/// `GET /thread/:id/events`: the events of a single thread
+pub fn thread_events(state: State, store: Store) -> (State, hyper::Response<Body>) {
+    let thread_id = ThreadId::borrow_from(&state).id;
+    let live = S::borrow_from(&state).events.subscribe();
+    // Also makes sure the thread exists
+    let page = PageRequest {
+        cursor: last_event_id(&state).map(Cursor::After).unwrap_or(Cursor::Start),
+        limit: REPLAY_LIMIT,
+    };
+    let result = store.get_thread(thread_id, &page)
+        .map_err(HttpResult::from)
+        .map(|thread| {
+            let replay = match page.cursor {
+                Cursor::Start => vec![],
+                _ => thread.messages.map(|page| page.items).unwrap_or_default().into_iter()
+                    .map(|message| Event::MessageCreated { thread_id, message })
+                    .collect(),
+            };
+            let body = event_stream(replay, live, move |event| event.thread_id() == thread_id);
+            stream_response(&state, body)
+        });
+    respond(state, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_created_events_have_ids() {
+        assert_eq!(format_event(&Event::ThreadDeleted { thread_id: 4 }),
+                   "data: {\"type\":\"thread_deleted\",\"thread_id\":4}\n\n");
+        assert!(format_event(&Event::MessageDeleted { thread_id: 4, message_id: 9 }).starts_with("data: "));
+    }
+}
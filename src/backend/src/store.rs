@@ -0,0 +1,397 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+use types::{Account, Attachment, Message, MessageRevision, Page, Role, SearchResult, Thread, ThreadSort};
+
+use crate::db::{self, AccountEmail, Connection, Credentials, Database, DbError, NewAttachment, NewRefreshToken,
+                Ownership, PageRequest, RefreshToken, StoredAttachment, ThreadKey, Totp};
+use crate::db_traits::IntoGenericConnection;
+
+/// Accounts, threads and messages, wherever they are kept. Everything
+/// deleted through the store is soft deleted and hidden from then on.
+pub trait ForumStore {
+    /// Create an account with an unverified, already normalized, `email`
+    fn create_account(&self, username: &str, email: &str, password: &str) -> Result<i32, DbError>;
+    fn get_credentials(&self, username: &str) -> Result<Credentials, DbError>;
+    fn get_credentials_by_email(&self, email: &str) -> Result<Credentials, DbError>;
+    fn update_last_logged_in(&self, username: &str) -> Result<(), DbError>;
+    fn get_account(&self, id: i32) -> Result<Account, DbError>;
+    fn get_role(&self, id: i32) -> Result<Role, DbError>;
+    fn set_role(&self, id: i32, role: Role) -> Result<(), DbError>;
+    fn set_banned(&self, id: i32, banned: bool, reason: Option<&str>) -> Result<(), DbError>;
+    /// Replace the password hash of an account
+    fn set_password(&self, id: i32, password: &str) -> Result<(), DbError>;
+    fn create_password_reset(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError>;
+    /// Use up an unexpired password reset, along with every other one of
+    /// its account, returning the account. `NotFound` if there's none.
+    fn use_password_reset(&self, token_hash: &str) -> Result<i32, DbError>;
+    fn get_email(&self, id: i32) -> Result<AccountEmail, DbError>;
+    fn create_email_verification(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError>;
+    /// Use up an unexpired email verification, along with every other one
+    /// of its account, and mark the address of the account verified,
+    /// returning the account. `NotFound` if there's none.
+    fn verify_email(&self, token_hash: &str) -> Result<i32, DbError>;
+    fn get_totp(&self, account_id: i32) -> Result<Totp, DbError>;
+    /// Start enrolling with `secret`, or turn two-factor authentication off
+    /// with `None`. Either way it's disabled until `enable_totp`.
+    fn set_totp_secret(&self, account_id: i32, secret: Option<&[u8]>) -> Result<(), DbError>;
+    fn enable_totp(&self, account_id: i32) -> Result<(), DbError>;
+    /// Record that the code of `step` was used. `NotFound` if it, or a
+    /// later one, already was.
+    fn use_totp_step(&self, account_id: i32, step: i64) -> Result<(), DbError>;
+    /// Replace the recovery codes of an account. Should be run in a
+    /// transaction.
+    fn set_recovery_codes(&self, account_id: i32, code_hashes: &[String]) -> Result<(), DbError>;
+    /// Use up a recovery code, `NotFound` if the account doesn't have it
+    fn use_recovery_code(&self, account_id: i32, code_hash: &str) -> Result<(), DbError>;
+    fn create_mfa_challenge(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError>;
+    /// Count an attempt at answering an unexpired login challenge and
+    /// return its account. `NotFound` if there's none with attempts left.
+    fn attempt_mfa_challenge(&self, token_hash: &str, max_attempts: i32) -> Result<i32, DbError>;
+    fn delete_mfa_challenges(&self, account_id: i32) -> Result<(), DbError>;
+
+    fn create_thread(&self, account_id: i32, title: &str) -> Result<(), DbError>;
+    fn get_threads(&self, sort: ThreadSort, page: &PageRequest<ThreadKey>) -> Result<Page<Thread>, DbError>;
+    /// A thread with a page of its messages
+    fn get_thread(&self, id: i32, page: &PageRequest) -> Result<Thread, DbError>;
+    /// Threads created after the thread `id`, oldest first
+    fn get_threads_created_after(&self, id: i32, limit: i64) -> Result<Vec<Thread>, DbError>;
+    /// A thread without its messages
+    fn get_thread_summary(&self, id: i32) -> Result<Thread, DbError>;
+    fn get_thread_ownership(&self, id: i32) -> Result<Ownership, DbError>;
+    fn set_thread_locked(&self, id: i32, locked: bool) -> Result<(), DbError>;
+    fn update_thread(&self, id: i32, title: &str) -> Result<(), DbError>;
+    fn delete_thread(&self, id: i32) -> Result<(), DbError>;
+    /// Thread titles and messages matching `query`, best matches first
+    fn search(&self, query: &str, limit: i64) -> Result<Vec<SearchResult>, DbError>;
+
+    fn get_message(&self, thread_id: i32, id: i32) -> Result<Message, DbError>;
+    /// Post a message, bumping the thread's latest activity, and return its
+    /// id. `NotFound` if the message it replies to isn't in the thread.
+    fn create_message(&self, account_id: i32, thread_id: i32, content: &str, content_html: &str,
+                      parent_id: Option<i32>) -> Result<i32, DbError>;
+    fn get_message_ownership(&self, thread_id: i32, id: i32) -> Result<Ownership, DbError>;
+    /// Replace the content of a message, keeping the current version as a
+    /// revision. Should be run in a transaction.
+    fn update_message(&self, account_id: i32, thread_id: i32, id: i32, content: &str, content_html: &str)
+        -> Result<(), DbError>;
+    fn delete_message(&self, thread_id: i32, id: i32) -> Result<(), DbError>;
+    /// Prior versions of a message, oldest first
+    fn get_message_revisions(&self, thread_id: i32, id: i32) -> Result<Vec<MessageRevision>, DbError>;
+
+    fn create_attachment(&self, attachment: &NewAttachment) -> Result<Attachment, DbError>;
+    /// An attachment that isn't attached yet, or whose message is visible
+    fn get_attachment(&self, id: i32) -> Result<StoredAttachment, DbError>;
+    /// Attach uploads of `account_id` to a message. `NotFound` unless every
+    /// one of them exists and isn't attached to anything yet.
+    fn attach(&self, account_id: i32, message_id: i32, ids: &[i32]) -> Result<(), DbError>;
+    /// Delete the uploads that weren't attached to anything before
+    /// `created_before`, returning the blob keys of their content and
+    /// thumbnails
+    fn delete_unattached(&self, created_before: DateTime<Utc>) -> Result<Vec<String>, DbError>;
+
+    fn create_refresh_token(&self, token: &NewRefreshToken) -> Result<(), DbError>;
+    fn get_refresh_token(&self, token_hash: &str) -> Result<RefreshToken, DbError>;
+    /// Mark a refresh token as used, returning false if it already was
+    fn mark_refresh_token_used(&self, id: i32) -> Result<bool, DbError>;
+    /// Revoke every refresh token of a rotation family, along with the
+    /// access tokens that were issued with them
+    fn revoke_refresh_token_family(&self, family: &str) -> Result<(), DbError>;
+    /// Revoke every session of an account
+    fn revoke_account_tokens(&self, account_id: i32) -> Result<(), DbError>;
+    fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), DbError>;
+    fn is_token_revoked(&self, jti: &str) -> Result<bool, DbError>;
+}
+
+/// A store shared by every request, kept in `router::S`
+pub trait Backend: ForumStore + Send + Sync + fmt::Debug {
+    /// Start a transaction. Its changes are discarded unless it's committed.
+    fn begin(&self) -> Result<Box<dyn StoreTransaction + '_>, DbError>;
+}
+
+pub trait StoreTransaction: ForumStore {
+    fn commit(self: Box<Self>) -> Result<(), DbError>;
+}
+
+pub type Store = Arc<dyn Backend>;
+
+/// A connection `Postgres` runs its queries on: a fresh one from the pool
+/// per query, or the one a transaction runs on
+pub enum Conn<'a> {
+    Pooled(Connection),
+    Borrowed(&'a Connection),
+}
+
+impl Deref for Conn<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Conn::Pooled(connection) => connection,
+            Conn::Borrowed(connection) => connection,
+        }
+    }
+}
+
+pub trait Connect {
+    fn connection(&self) -> Result<Conn<'_>, DbError>;
+}
+
+impl Connect for Database {
+    fn connection(&self) -> Result<Conn<'_>, DbError> {
+        Ok(Conn::Pooled(self.get()?))
+    }
+}
+
+/// A connection with an open transaction, rolled back when dropped unless
+/// it was committed
+pub struct PgTransaction {
+    connection: Connection,
+    committed: bool,
+}
+
+impl Connect for PgTransaction {
+    fn connection(&self) -> Result<Conn<'_>, DbError> {
+        Ok(Conn::Borrowed(&self.connection))
+    }
+}
+
+impl Drop for PgTransaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            // Fails only if the connection is gone, taking the transaction along
+            let _ = self.connection.into_generic_connection().batch_execute("ROLLBACK");
+        }
+    }
+}
+
+/// The Postgres store, running the queries in `db`
+#[derive(Debug)]
+pub struct Postgres<C>(pub C);
+
+impl Backend for Postgres<Database> {
+    fn begin(&self) -> Result<Box<dyn StoreTransaction + '_>, DbError> {
+        let connection = self.0.get()?;
+        connection.into_generic_connection().batch_execute("BEGIN")?;
+        Ok(Box::new(Postgres(PgTransaction { connection, committed: false })))
+    }
+}
+
+impl StoreTransaction for Postgres<PgTransaction> {
+    fn commit(mut self: Box<Self>) -> Result<(), DbError> {
+        self.0.connection.into_generic_connection().batch_execute("COMMIT")?;
+        self.0.committed = true;
+        Ok(())
+    }
+}
+
+impl<C: Connect> ForumStore for Postgres<C> {
+    fn create_account(&self, username: &str, email: &str, password: &str) -> Result<i32, DbError> {
+        db::create_account(&*self.0.connection()?, username, email, password)
+    }
+
+    fn get_credentials(&self, username: &str) -> Result<Credentials, DbError> {
+        db::get_credentials(&*self.0.connection()?, username)
+    }
+
+    fn get_credentials_by_email(&self, email: &str) -> Result<Credentials, DbError> {
+        db::get_credentials_by_email(&*self.0.connection()?, email)
+    }
+
+    fn update_last_logged_in(&self, username: &str) -> Result<(), DbError> {
+        db::update_last_logged_in(&*self.0.connection()?, username)
+    }
+
+    fn get_account(&self, id: i32) -> Result<Account, DbError> {
+        db::get_account(&*self.0.connection()?, id)
+    }
+
+    fn get_role(&self, id: i32) -> Result<Role, DbError> {
+        db::get_role(&*self.0.connection()?, id)
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), DbError> {
+        db::set_role(&*self.0.connection()?, id, role)
+    }
+
+    fn set_banned(&self, id: i32, banned: bool, reason: Option<&str>) -> Result<(), DbError> {
+        db::set_banned(&*self.0.connection()?, id, banned, reason)
+    }
+
+    fn set_password(&self, id: i32, password: &str) -> Result<(), DbError> {
+        db::set_password(&*self.0.connection()?, id, password)
+    }
+
+    fn create_password_reset(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError> {
+        db::create_password_reset(&*self.0.connection()?, account_id, token_hash, expires_at)
+    }
+
+    fn use_password_reset(&self, token_hash: &str) -> Result<i32, DbError> {
+        db::use_password_reset(&*self.0.connection()?, token_hash)
+    }
+
+    fn get_email(&self, id: i32) -> Result<AccountEmail, DbError> {
+        db::get_email(&*self.0.connection()?, id)
+    }
+
+    fn create_email_verification(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError> {
+        db::create_email_verification(&*self.0.connection()?, account_id, token_hash, expires_at)
+    }
+
+    fn verify_email(&self, token_hash: &str) -> Result<i32, DbError> {
+        db::verify_email(&*self.0.connection()?, token_hash)
+    }
+
+    fn get_totp(&self, account_id: i32) -> Result<Totp, DbError> {
+        db::get_totp(&*self.0.connection()?, account_id)
+    }
+
+    fn set_totp_secret(&self, account_id: i32, secret: Option<&[u8]>) -> Result<(), DbError> {
+        db::set_totp_secret(&*self.0.connection()?, account_id, secret)
+    }
+
+    fn enable_totp(&self, account_id: i32) -> Result<(), DbError> {
+        db::enable_totp(&*self.0.connection()?, account_id)
+    }
+
+    fn use_totp_step(&self, account_id: i32, step: i64) -> Result<(), DbError> {
+        db::use_totp_step(&*self.0.connection()?, account_id, step)
+    }
+
+    fn set_recovery_codes(&self, account_id: i32, code_hashes: &[String]) -> Result<(), DbError> {
+        db::set_recovery_codes(&*self.0.connection()?, account_id, code_hashes)
+    }
+
+    fn use_recovery_code(&self, account_id: i32, code_hash: &str) -> Result<(), DbError> {
+        db::use_recovery_code(&*self.0.connection()?, account_id, code_hash)
+    }
+
+    fn create_mfa_challenge(&self, account_id: i32, token_hash: &str, expires_at: DateTime<Utc>)
+        -> Result<(), DbError> {
+        db::create_mfa_challenge(&*self.0.connection()?, account_id, token_hash, expires_at)
+    }
+
+    fn attempt_mfa_challenge(&self, token_hash: &str, max_attempts: i32) -> Result<i32, DbError> {
+        db::attempt_mfa_challenge(&*self.0.connection()?, token_hash, max_attempts)
+    }
+
+    fn delete_mfa_challenges(&self, account_id: i32) -> Result<(), DbError> {
+        db::delete_mfa_challenges(&*self.0.connection()?, account_id)
+    }
+
+    fn create_thread(&self, account_id: i32, title: &str) -> Result<(), DbError> {
+        db::create_thread(&*self.0.connection()?, account_id, title)
+    }
+
+    fn get_threads(&self, sort: ThreadSort, page: &PageRequest<ThreadKey>) -> Result<Page<Thread>, DbError> {
+        db::get_threads(&*self.0.connection()?, sort, page)
+    }
+
+    fn get_thread(&self, id: i32, page: &PageRequest) -> Result<Thread, DbError> {
+        db::get_thread(&*self.0.connection()?, id, page)
+    }
+
+    fn get_threads_created_after(&self, id: i32, limit: i64) -> Result<Vec<Thread>, DbError> {
+        db::get_threads_created_after(&*self.0.connection()?, id, limit)
+    }
+
+    fn get_thread_summary(&self, id: i32) -> Result<Thread, DbError> {
+        db::get_thread_summary(&*self.0.connection()?, id)
+    }
+
+    fn get_thread_ownership(&self, id: i32) -> Result<Ownership, DbError> {
+        db::get_thread_ownership(&*self.0.connection()?, id)
+    }
+
+    fn set_thread_locked(&self, id: i32, locked: bool) -> Result<(), DbError> {
+        db::set_thread_locked(&*self.0.connection()?, id, locked)
+    }
+
+    fn update_thread(&self, id: i32, title: &str) -> Result<(), DbError> {
+        db::update_thread(&*self.0.connection()?, id, title)
+    }
+
+    fn delete_thread(&self, id: i32) -> Result<(), DbError> {
+        db::delete_thread(&*self.0.connection()?, id)
+    }
+
+    fn search(&self, query: &str, limit: i64) -> Result<Vec<SearchResult>, DbError> {
+        db::search(&*self.0.connection()?, query, limit)
+    }
+
+    fn get_message(&self, thread_id: i32, id: i32) -> Result<Message, DbError> {
+        db::get_message(&*self.0.connection()?, thread_id, id)
+    }
+
+    fn create_message(&self, account_id: i32, thread_id: i32, content: &str, content_html: &str,
+                      parent_id: Option<i32>) -> Result<i32, DbError> {
+        db::create_message(&*self.0.connection()?, account_id, thread_id, content, content_html, parent_id)
+    }
+
+    fn get_message_ownership(&self, thread_id: i32, id: i32) -> Result<Ownership, DbError> {
+        db::get_message_ownership(&*self.0.connection()?, thread_id, id)
+    }
+
+    fn update_message(&self, account_id: i32, thread_id: i32, id: i32, content: &str, content_html: &str)
+        -> Result<(), DbError> {
+        db::update_message(&*self.0.connection()?, account_id, thread_id, id, content, content_html)
+    }
+
+    fn delete_message(&self, thread_id: i32, id: i32) -> Result<(), DbError> {
+        db::delete_message(&*self.0.connection()?, thread_id, id)
+    }
+
+    fn get_message_revisions(&self, thread_id: i32, id: i32) -> Result<Vec<MessageRevision>, DbError> {
+        db::get_message_revisions(&*self.0.connection()?, thread_id, id)
+    }
+
+    fn create_attachment(&self, attachment: &NewAttachment) -> Result<Attachment, DbError> {
+        db::create_attachment(&*self.0.connection()?, attachment)
+    }
+
+    fn get_attachment(&self, id: i32) -> Result<StoredAttachment, DbError> {
+        db::get_attachment(&*self.0.connection()?, id)
+    }
+
+    fn attach(&self, account_id: i32, message_id: i32, ids: &[i32]) -> Result<(), DbError> {
+        db::attach(&*self.0.connection()?, account_id, message_id, ids)
+    }
+
+    fn delete_unattached(&self, created_before: DateTime<Utc>) -> Result<Vec<String>, DbError> {
+        db::delete_unattached(&*self.0.connection()?, created_before)
+    }
+
+    fn create_refresh_token(&self, token: &NewRefreshToken) -> Result<(), DbError> {
+        db::create_refresh_token(&*self.0.connection()?, token)
+    }
+
+    fn get_refresh_token(&self, token_hash: &str) -> Result<RefreshToken, DbError> {
+        db::get_refresh_token(&*self.0.connection()?, token_hash)
+    }
+
+    fn mark_refresh_token_used(&self, id: i32) -> Result<bool, DbError> {
+        db::mark_refresh_token_used(&*self.0.connection()?, id)
+    }
+
+    fn revoke_refresh_token_family(&self, family: &str) -> Result<(), DbError> {
+        db::revoke_refresh_token_family(&*self.0.connection()?, family)
+    }
+
+    fn revoke_account_tokens(&self, account_id: i32) -> Result<(), DbError> {
+        db::revoke_account_tokens(&*self.0.connection()?, account_id)
+    }
+
+    fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), DbError> {
+        db::revoke_token(&*self.0.connection()?, jti, expires_at)
+    }
+
+    fn is_token_revoked(&self, jti: &str) -> Result<bool, DbError> {
+        db::is_token_revoked(&*self.0.connection()?, jti)
+    }
+}
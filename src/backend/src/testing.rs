@@ -0,0 +1,209 @@
+use gotham::test::{TestResponse, TestServer};
+use hyper::header::{AUTHORIZATION, HeaderValue};
+use hyper::{Method, StatusCode};
+use postgres::{Connection, TlsMode};
+use r2d2_postgres::PostgresConnectionManager;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::sync::Arc;
+use types::{ApiError, Attachment, Page, Thread, Token};
+use uuid::Uuid;
+
+use crate::blob_store::MemoryBlobStore;
+use crate::config::Config;
+use crate::db::Database;
+use crate::mailer::MemoryMailer;
+use crate::memory_store::MemoryStore;
+use crate::migrate;
+use crate::rate_limit::{Limits, MemoryLimits, PostgresLimits};
+use crate::router::{S, router};
+use crate::store::{Postgres, Store};
+
+/// Postgres to run the tests on. Without it they run on `MemoryStore`.
+const DATABASE_URL_VAR: &str = "FSTACK_TEST_DATABASE_URL";
+
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Points every pooled connection at the schema of the test
+#[derive(Debug)]
+struct SearchPath(String);
+
+impl r2d2::CustomizeConnection<Connection, postgres::Error> for SearchPath {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), postgres::Error> {
+        conn.batch_execute(&format!("SET search_path TO {}", self.0))
+    }
+}
+
+/// A schema of its own, dropped with it
+struct Schema {
+    database_url: String,
+    name: String,
+}
+
+impl Schema {
+    fn create(database_url: String) -> (Schema, Database) {
+        let name = format!("test_{}", Uuid::new_v4().to_simple());
+        let conn = Connection::connect(database_url.as_str(), TlsMode::None).unwrap();
+        conn.batch_execute(&format!("CREATE SCHEMA {}", name)).unwrap();
+        let schema = Schema { database_url, name };
+
+        let manager = PostgresConnectionManager::new(schema.database_url.as_str(), TlsMode::None).unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(4)
+            .connection_customizer(Box::new(SearchPath(schema.name.clone())))
+            .build(manager)
+            .unwrap();
+        migrate::up(&pool.get().unwrap()).unwrap();
+        (schema, Database::new(pool))
+    }
+}
+
+impl Drop for Schema {
+    fn drop(&mut self) {
+        let dropped = Connection::connect(self.database_url.as_str(), TlsMode::None)
+            .and_then(|conn| conn.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.name)));
+        if let Err(e) = dropped {
+            eprintln!("Could not drop test schema {}: {}", self.name, e);
+        }
+    }
+}
+
+/// The API served by a `TestServer`, on a throwaway schema of the database
+/// in `FSTACK_TEST_DATABASE_URL`, or in memory if that isn't set.
+pub struct TestApp {
+    pub server: TestServer,
+    pub store: Store,
+    pub config: Arc<Config>,
+    /// Everything the app has mailed
+    pub mail: Arc<MemoryMailer>,
+    _schema: Option<Schema>,
+}
+
+impl TestApp {
+    pub fn new() -> TestApp {
+        TestApp::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> TestApp {
+        let (schema, store, limits): (_, Store, Limits) = match env::var(DATABASE_URL_VAR) {
+            Ok(database_url) => {
+                let (schema, database) = Schema::create(database_url);
+                (Some(schema), Arc::new(Postgres(database.clone())), Arc::new(PostgresLimits(database)))
+            }
+            Err(_) => (None, Arc::new(MemoryStore::default()), Arc::new(MemoryLimits::default())),
+        };
+        let mail = Arc::new(MemoryMailer::default());
+        let state = S::new(config, store.clone(), Arc::new(MemoryBlobStore::default()), limits, mail.clone()).unwrap();
+        let config = state.config.clone();
+        TestApp { server: TestServer::new(router(state)).unwrap(), store, config, mail, _schema: schema }
+    }
+
+    /// Send a request, with a JSON body and a bearer token if given
+    pub fn request(&self, method: Method, path: &str, body: Option<&str>, token: Option<&str>) -> TestResponse {
+        let client = self.server.client();
+        let uri = format!("http://localhost{}", path);
+        let mut request = match body {
+            Some(body) => client.build_request_with_body(method, uri, body.to_string(), mime::APPLICATION_JSON),
+            None => client.build_request(method, uri),
+        };
+        if let Some(token) = token {
+            request = request.with_header(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+        }
+        request.perform().unwrap()
+    }
+
+    pub fn get(&self, path: &str, token: Option<&str>) -> TestResponse {
+        self.request(Method::GET, path, None, token)
+    }
+
+    pub fn post(&self, path: &str, body: &str, token: Option<&str>) -> TestResponse {
+        self.request(Method::POST, path, Some(body), token)
+    }
+
+    pub fn put(&self, path: &str, body: &str, token: Option<&str>) -> TestResponse {
+        self.request(Method::PUT, path, Some(body), token)
+    }
+
+    pub fn delete(&self, path: &str, token: Option<&str>) -> TestResponse {
+        self.request(Method::DELETE, path, None, token)
+    }
+
+    /// Sign up with `PASSWORD` and `<username>@example.com`, and verify
+    /// the address
+    pub fn create_account(&self, username: &str) -> Token {
+        let token = self.create_unverified_account(username);
+        let verification = self.mailed_token(&format!("{}@example.com", username), "#verify-email/");
+        let body = json!({"token": verification}).to_string();
+        expect(self.post("/account/verify", &body, None), StatusCode::NO_CONTENT);
+        token
+    }
+
+    pub fn create_unverified_account(&self, username: &str) -> Token {
+        let body = json!({
+            "username": username, "email": format!("{}@example.com", username), "password": PASSWORD,
+        }).to_string();
+        json(expect(self.post("/account", &body, None), StatusCode::CREATED))
+    }
+
+    /// The token at the end of the link to `path` in the latest mail to `to`
+    pub fn mailed_token(&self, to: &str, path: &str) -> String {
+        let mail = self.mail.sent().into_iter().rev().find(|mail| mail.to == to).unwrap();
+        let link = mail.body.lines().find(|line| line.contains(path)).unwrap();
+        link.rsplit('/').next().unwrap().to_string()
+    }
+
+    pub fn login(&self, username: &str) -> Token {
+        let body = json!({"email": format!("{}@example.com", username), "password": PASSWORD}).to_string();
+        json(expect(self.post("/login", &body, None), StatusCode::OK))
+    }
+
+    /// Create a thread, returning its id
+    pub fn create_thread(&self, token: &Token, title: &str) -> i32 {
+        let body = json!({"title": title}).to_string();
+        expect(self.post("/thread", &body, Some(&token.token)), StatusCode::CREATED);
+        let threads: Page<Thread> = json(expect(self.get("/thread?sort=created&limit=1", None), StatusCode::OK));
+        threads.items[0].id
+    }
+
+    pub fn create_message(&self, token: &Token, thread_id: i32, content: &str) {
+        let body = json!({"content": content}).to_string();
+        expect(self.post(&format!("/thread/{}", thread_id), &body, Some(&token.token)), StatusCode::CREATED);
+    }
+
+    /// Upload `data` as multipart/form-data, the way browsers do
+    pub fn upload(&self, token: &Token, filename: &str, data: &[u8]) -> TestResponse {
+        let boundary = "------------------------fstack";
+        let mut body = format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                                Content-Type: application/octet-stream\r\n\r\n", boundary, filename).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        let content_type = format!("multipart/form-data; boundary={}", boundary).parse().unwrap();
+        self.server.client()
+            .post("http://localhost/attachment", body, content_type)
+            .with_header(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token.token)).unwrap())
+            .perform()
+            .unwrap()
+    }
+
+    pub fn create_attachment(&self, token: &Token, filename: &str, data: &[u8]) -> Attachment {
+        json(expect(self.upload(token, filename, data), StatusCode::CREATED))
+    }
+}
+
+/// Fail the test, with the body of the response, unless it has `status`
+pub fn expect(response: TestResponse, status: StatusCode) -> TestResponse {
+    if response.status() != status {
+        let actual = response.status();
+        panic!("expected {}, got {}: {}", status, actual, response.read_utf8_body().unwrap_or_default());
+    }
+    response
+}
+
+pub fn json<T: DeserializeOwned>(response: TestResponse) -> T {
+    serde_json::from_slice(&response.read_body().unwrap()).unwrap()
+}
+
+/// The error of a failed request, checking its status
+pub fn error(response: TestResponse, status: StatusCode) -> ApiError {
+    json(expect(response, status))
+}
@@ -0,0 +1,178 @@
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use qrcode::QrCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// RFC 6238 defaults, the only parameters authenticator apps reliably support
+const DIGITS: u32 = 6;
+const STEP: u64 = 30;
+const SECRET_LENGTH: usize = 20;
+/// Codes of the neighbouring time steps are accepted too, for clock drift
+const SKEW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// Wrong codes a login challenge survives
+pub const MAX_ATTEMPTS: i32 = 5;
+const QR_CODE_SIZE: u32 = 200;
+
+const BASE32: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, the form authenticator apps take
+/// secrets in
+pub fn base32(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |bits, &byte| bits << 8 | u64::from(byte));
+        let chars = (chunk.len() * 8 + 4) / 5;
+        for i in 0..chars {
+            result.push(BASE32[(bits >> (35 - i * 5) & 31) as usize] as char);
+        }
+    }
+    result
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    openssl::rand::rand_bytes(&mut bytes).expect("the system random number generator failed");
+    bytes
+}
+
+pub fn new_secret() -> Vec<u8> {
+    random_bytes(SECRET_LENGTH)
+}
+
+/// The code of time step `step`, HOTP (RFC 4226) with the step as counter
+fn code(secret: &[u8], step: u64, digits: u32) -> Result<u32, openssl::error::ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&step.to_be_bytes())?;
+    let hmac = signer.sign_to_vec()?;
+    let offset = (hmac[hmac.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes([hmac[offset], hmac[offset + 1], hmac[offset + 2], hmac[offset + 3]]);
+    Ok((truncated & 0x7fff_ffff) % 10u32.pow(digits))
+}
+
+fn current_step() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    (now / STEP) as i64
+}
+
+/// The time step `code` belongs to, if it's a current code of `secret` from
+/// a later step than `last_step`
+pub fn verify(secret: &[u8], code: &str, last_step: Option<i64>) -> Option<i64> {
+    verify_at(secret, code, current_step(), last_step)
+}
+
+fn verify_at(secret: &[u8], code: &str, step: i64, last_step: Option<i64>) -> Option<i64> {
+    if !is_code(code) {
+        return None;
+    }
+    let code = code.trim();
+    (step - SKEW..=step + SKEW)
+        .filter(|&candidate| candidate >= 0 && last_step.map_or(true, |last| candidate > last))
+        .find(|&candidate| match self::code(secret, candidate as u64, DIGITS) {
+            Ok(expected) => {
+                let expected = format!("{:0width$}", expected, width = DIGITS as usize);
+                openssl::memcmp::eq(expected.as_bytes(), code.as_bytes())
+            }
+            Err(_) => false,
+        })
+}
+
+/// The code `offset` steps from now, as an authenticator app would show it
+#[cfg(test)]
+pub fn code_at(secret: &[u8], offset: i64) -> String {
+    format!("{:0width$}", code(secret, (current_step() + offset) as u64, DIGITS).unwrap(), width = DIGITS as usize)
+}
+
+/// Whether `code` is shaped like a TOTP code rather than a recovery code
+pub fn is_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The `otpauth://` URI authenticator apps are set up with, usually by
+/// scanning it as a QR code
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            percent_encode(issuer), percent_encode(account), base32(secret), percent_encode(issuer), DIGITS, STEP)
+}
+
+/// `uri` as a QR code, in a PNG `data:` URI for an `<img>`
+pub fn qr_code(uri: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let image = QrCode::new(uri.as_bytes())?
+        .render::<Luma<u8>>()
+        .min_dimensions(QR_CODE_SIZE, QR_CODE_SIZE)
+        .build();
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(image).write_to(&mut png, ImageOutputFormat::PNG)?;
+    Ok(format!("data:image/png;base64,{}", base64::encode(&png)))
+}
+
+/// New recovery codes, shown to the user once and only stored hashed. They
+/// are as long as they are because the hash isn't salted.
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = base32(&random_bytes(10)).to_lowercase();
+            let groups: Vec<&str> = (0..4).map(|i| &code[i * 4..i * 4 + 4]).collect();
+            groups.join("-")
+        })
+        .collect()
+}
+
+/// A recovery code the way it's hashed, however it was typed in
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_match_rfc_6238() {
+        let secret = b"12345678901234567890";
+        assert_eq!(code(secret, 59 / STEP, 8).unwrap(), 94_287_082);
+        assert_eq!(code(secret, 1_111_111_109 / STEP, 8).unwrap(), 7_081_804);
+        assert_eq!(code(secret, 20_000_000_000 / STEP, 8).unwrap(), 65_353_130);
+
+        let step = (1_111_111_109 / STEP) as i64;
+        assert_eq!(verify_at(secret, "081804", step, None), Some(step));
+        assert_eq!(verify_at(secret, " 081804 ", step + 1, None), Some(step));
+        assert_eq!(verify_at(secret, "081804", step + 2, None), None);
+        // Used codes don't work again
+        assert_eq!(verify_at(secret, "081804", step, Some(step)), None);
+        assert_eq!(verify_at(secret, "81804", step, None), None);
+    }
+
+    #[test]
+    fn secrets_are_base32_encoded() {
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        let uri = provisioning_uri("fstack", "alice smith", b"foobar");
+        assert_eq!(uri, "otpauth://totp/fstack:alice%20smith?secret=MZXW6YTBOI&issuer=fstack&algorithm=SHA1\
+                         &digits=6&period=30");
+    }
+
+    #[test]
+    fn recovery_codes_are_normalized() {
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(codes[0].len(), 19);
+        assert_eq!(normalize_recovery_code(&codes[0].to_uppercase()), normalize_recovery_code(&codes[0]));
+        assert_eq!(normalize_recovery_code("abcd-efgh "), "abcdefgh");
+    }
+}
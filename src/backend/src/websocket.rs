@@ -0,0 +1,165 @@
+use futures::{future, stream, Future, Sink, Stream};
+use gotham::handler::HandlerFuture;
+use gotham::state::{FromState, State};
+use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use serde::Deserialize;
+use sha1::Sha1;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::protocol::{Message as WsMessage, Role};
+use types::{ClientCommand, ErrorCode, Event};
+
+use crate::auth_middleware::{AuthenticatedAccount, authenticate};
+use crate::handler_utils::HttpResult;
+use crate::router::S;
+
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Browsers can't set headers on WebSocket requests, so the access token is
+/// passed in the query string instead.
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct SocketQuery {
+    access_token: Option<String>,
+}
+
+enum Incoming {
+    Client(WsMessage),
+    Event(Arc<Event>),
+    /// The client went away or the token expired
+    Closed,
+}
+
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::default();
+    sha1.update(key);
+    sha1.update(WEBSOCKET_GUID);
+    base64::encode(&sha1.digest().bytes())
+}
+
+fn upgrade_response(headers: &HeaderMap) -> Option<Response<Body>> {
+    let upgrade = headers.get(UPGRADE)?.to_str().ok()?;
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+    let key = headers.get(SEC_WEBSOCKET_KEY)?;
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, HeaderValue::from_static("websocket"))
+        .header(CONNECTION, HeaderValue::from_static("upgrade"))
+        .header(SEC_WEBSOCKET_ACCEPT, accept_key(key.as_bytes()))
+        .body(Body::empty())
+        .ok()
+}
+
+/// Message events are only sent for the threads the client subscribed to
+fn visible(event: &Event, subscriptions: &HashSet<i32>) -> bool {
+    !event.is_message_event() || subscriptions.contains(&event.thread_id())
+}
+
+fn serve<E>(socket: WebSocketStream<Upgraded>, account: AuthenticatedAccount, events: E)
+    -> impl Future<Item = (), Error = ()>
+where E: Stream<Item = Arc<Event>, Error = ()> {
+    let (sink, client) = socket.split();
+    let client = client
+        .map(Incoming::Client)
+        .map_err(|_| ())
+        .chain(stream::once(Ok(Incoming::Closed)));
+    let lifetime = (account.expires_at.timestamp() - chrono::Utc::now().timestamp()).max(0) as u64;
+    let expiry = Delay::new(Instant::now() + Duration::from_secs(lifetime))
+        .map(|_| Incoming::Closed)
+        .map_err(|_| ())
+        .into_stream();
+
+    let mut subscriptions = HashSet::new();
+    let outgoing = client
+        .select(events.map(Incoming::Event))
+        .select(expiry)
+        .take_while(|incoming| Ok(match incoming {
+            Incoming::Client(WsMessage::Close(_)) | Incoming::Closed => false,
+            _ => true,
+        }))
+        .filter_map(move |incoming| match incoming {
+            Incoming::Client(WsMessage::Text(text)) => {
+                match serde_json::from_str(&text) {
+                    Ok(ClientCommand::Subscribe { thread_id }) => { subscriptions.insert(thread_id); }
+                    Ok(ClientCommand::Unsubscribe { thread_id }) => { subscriptions.remove(&thread_id); }
+                    Err(_) => {}
+                }
+                None
+            }
+            Incoming::Event(ref event) if visible(event, &subscriptions) =>
+                serde_json::to_string(&**event).ok().map(WsMessage::Text),
+            _ => None,
+        });
+    outgoing
+        .forward(sink.sink_map_err(|_| ()))
+        .map(|_| ())
+}
+
+/// `GET /events/ws`: a WebSocket pushing `types::Event`s. Thread events are
+/// sent to everyone, message events for the threads subscribed to with
+/// `ClientCommand`s. The socket is closed when the access token expires.
+pub fn events_socket(mut state: State) -> Box<HandlerFuture> {
+    let headers = HeaderMap::take_from(&mut state);
+    let body = Body::take_from(&mut state);
+
+    let token = match SocketQuery::borrow_from(&state).access_token.clone() {
+        Some(token) => token,
+        None => {
+            let response = HttpResult::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Authentication required")
+                .into_response(&state);
+            return Box::new(future::ok((state, response)));
+        }
+    };
+
+    let response = match upgrade_response(&headers) {
+        Some(response) => response,
+        None => {
+            let response = HttpResult::new(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, "Expected a WebSocket upgrade")
+                .into_response(&state);
+            return Box::new(future::ok((state, response)));
+        }
+    };
+
+    let accepted = authenticate(&state, token).then(move |account| {
+        let account = match account {
+            Ok(account) => account,
+            Err(e) => {
+                let response = HttpResult::from(e).into_response(&state);
+                return Ok((state, response));
+            }
+        };
+        let events = S::borrow_from(&state).events.subscribe();
+        let connection = body.on_upgrade()
+            .map_err(|e| eprintln!("WebSocket upgrade failed: {}", e))
+            .and_then(move |upgraded| {
+                serve(WebSocketStream::from_raw_socket(upgraded, Role::Server, None), account, events)
+            });
+        tokio::spawn(connection);
+        Ok((state, response))
+    });
+    Box::new(accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc6455() {
+        assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn message_events_need_a_subscription() {
+        let subscriptions: HashSet<i32> = [1].iter().cloned().collect();
+        assert!(visible(&Event::ThreadDeleted { thread_id: 2 }, &subscriptions));
+        assert!(visible(&Event::MessageDeleted { thread_id: 1, message_id: 5 }, &subscriptions));
+        assert!(!visible(&Event::MessageDeleted { thread_id: 2, message_id: 5 }, &subscriptions));
+    }
+}
@@ -1,3 +1,9 @@
+use serde::de::DeserializeOwned;
+use stdweb::unstable::TryInto;
+use types::{ApiError, ThreadSort};
+use yew::format::{Binary, Text};
+use yew::services::fetch::Response;
+
 #[cfg(debug_assertions)]
 lazy_static! {
     pub static ref HOST: String = "http://localhost:80".to_string();
@@ -12,16 +18,73 @@ pub fn login() -> String {
     format!("{}/login", *HOST)
 }
 
+pub fn refresh_token() -> String {
+    format!("{}/token/refresh", *HOST)
+}
+
+pub fn logout() -> String {
+    format!("{}/logout", *HOST)
+}
+
 pub fn create_account() -> String {
     format!("{}/account", *HOST)
 }
 
-pub fn all_threads() -> String {
-    format!("{}/thread", *HOST)
+pub fn password_reset() -> String {
+    format!("{}/password-reset", *HOST)
 }
 
-pub fn thread(thread_id: i32) -> String {
-    format!("{}/thread/{}", *HOST, thread_id)
+pub fn confirm_password_reset() -> String {
+    format!("{}/password-reset/confirm", *HOST)
+}
+
+pub fn verify_email() -> String {
+    format!("{}/account/verify", *HOST)
+}
+
+pub fn resend_verification() -> String {
+    format!("{}/account/verify/resend", *HOST)
+}
+
+pub fn login_mfa() -> String {
+    format!("{}/login/mfa", *HOST)
+}
+
+pub fn totp() -> String {
+    format!("{}/account/totp", *HOST)
+}
+
+pub fn confirm_totp() -> String {
+    format!("{}/account/totp/confirm", *HOST)
+}
+
+pub fn recovery_codes() -> String {
+    format!("{}/account/totp/recovery-codes", *HOST)
+}
+
+pub fn disable_totp() -> String {
+    format!("{}/account/totp/disable", *HOST)
+}
+
+/// Build a query string out of the parameters that are set. The values
+/// (cursors and sort orders) are URL safe.
+fn query(params: &[(&str, Option<&str>)]) -> String {
+    let params: Vec<String> = params.iter()
+        .filter_map(|(name, value)| Some(format!("{}={}", name, (*value)?)))
+        .collect();
+    if params.is_empty() {
+        "".to_string()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+pub fn all_threads(sort: ThreadSort, after: Option<&str>) -> String {
+    format!("{}/thread{}", *HOST, query(&[("sort", Some(sort.as_str())), ("after", after)]))
+}
+
+pub fn thread(thread_id: i32, after: Option<&str>) -> String {
+    format!("{}/thread/{}{}", *HOST, thread_id, query(&[("after", after)]))
 }
 
 pub fn new_thread() -> String {
@@ -31,3 +94,119 @@ pub fn new_thread() -> String {
 pub fn new_message(thread_id: i32) -> String {
     format!("{}/thread/{}", *HOST, thread_id)
 }
+
+pub fn markdown_preview() -> String {
+    format!("{}/markdown/preview", *HOST)
+}
+
+/// The WebSocket event feed. Browsers can't send headers with WebSocket
+/// requests, so the token goes in the query string.
+pub fn events_socket(token: &str) -> String {
+    format!("{}/events/ws?access_token={}", HOST.replacen("http", "ws", 1), token)
+}
+
+/// Server-Sent Events feed of thread changes, for when WebSockets don't work
+pub fn events() -> String {
+    format!("{}/events", *HOST)
+}
+
+pub fn thread_events(thread_id: i32) -> String {
+    format!("{}/thread/{}/events", *HOST, thread_id)
+}
+
+/// Percent-encode a free-form query string value
+fn encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn search(q: &str) -> String {
+    format!("{}/search{}", *HOST, query(&[("q", Some(&encode(q)))]))
+}
+
+pub fn lock_thread(thread_id: i32) -> String {
+    format!("{}/admin/thread/{}/lock", *HOST, thread_id)
+}
+
+pub fn message(thread_id: i32, message_id: i32) -> String {
+    format!("{}/thread/{}/message/{}", *HOST, thread_id, message_id)
+}
+
+pub fn new_attachment() -> String {
+    format!("{}/attachment", *HOST)
+}
+
+pub fn attachment(id: i32) -> String {
+    format!("{}/attachment/{}", *HOST, id)
+}
+
+pub fn thumbnail(id: i32) -> String {
+    format!("{}/attachment/{}/thumbnail", *HOST, id)
+}
+
+/// A multipart/form-data body with `content` in the field `file`, along
+/// with its content type
+pub fn multipart_file(filename: &str, content: &[u8]) -> (Vec<u8>, String) {
+    let random: String = js! { return Math.random().toString(36).slice(2) + Math.random().toString(36).slice(2); }
+        .try_into()
+        .unwrap_or_default();
+    let boundary = format!("----fstack{}", random);
+    // Quotes and line breaks would end the header, browsers escape them the same way
+    let filename = filename.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A");
+    let mut body = format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                            Content-Type: application/octet-stream\r\n\r\n", boundary, filename).into_bytes();
+    body.extend_from_slice(content);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (body, format!("multipart/form-data; boundary={}", boundary))
+}
+
+/// The message to show the user for a failed request
+pub fn error_message(body: Text) -> String {
+    body.ok()
+        .and_then(|body| serde_json::from_str::<ApiError>(&body).ok())
+        .map(|error| error.to_string())
+        .unwrap_or_else(|| "Could not reach the server".to_string())
+}
+
+/// Parse a JSON response, or the `ApiError` of a failed one
+pub fn parse<T: DeserializeOwned>(response: Response<Text>) -> Result<T, String> {
+    let (meta, body) = response.into_parts();
+    if meta.status.is_success() {
+        body.ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .ok_or_else(|| "Unexpected response from the server".to_string())
+    } else {
+        Err(error_message(body))
+    }
+}
+
+/// Like `parse`, for requests made with `fetch_binary`
+pub fn parse_binary<T: DeserializeOwned>(response: Response<Binary>) -> Result<T, String> {
+    let (meta, body) = response.into_parts();
+    let body = body.map(|body| String::from_utf8_lossy(&body).into_owned());
+    parse(Response::from_parts(meta, body))
+}
+
+/// The body of a successful binary response, such as a file
+pub fn bytes(response: Response<Binary>) -> Result<Vec<u8>, String> {
+    let (meta, body) = response.into_parts();
+    if meta.status.is_success() {
+        body.map_err(|_| "Could not reach the server".to_string())
+    } else {
+        Err(error_message(body.map(|body| String::from_utf8_lossy(&body).into_owned())))
+    }
+}
+
+/// Like `parse`, for responses without a body
+pub fn check(response: Response<Text>) -> Result<(), String> {
+    let (meta, body) = response.into_parts();
+    if meta.status.is_success() {
+        Ok(())
+    } else {
+        Err(error_message(body))
+    }
+}
@@ -0,0 +1,33 @@
+use stdweb::Value;
+use yew::callback::Callback;
+
+/// An open `EventSource`, closed when dropped. The browser reconnects it on
+/// its own, sending the id of the last event it saw in `Last-Event-ID`.
+pub struct EventSourceTask {
+    source: Value,
+}
+
+impl EventSourceTask {
+    /// Open an event stream, passing the data of every event to `callback`
+    pub fn new(url: &str, callback: Callback<String>) -> Self {
+        let on_message = move |data: String| callback.emit(data);
+        let source = js! {
+            var on_message = @{on_message};
+            var source = new EventSource(@{url});
+            source.onmessage = function(event) { on_message(event.data); };
+            source.on_message = on_message;
+            return source;
+        };
+        EventSourceTask { source }
+    }
+}
+
+impl Drop for EventSourceTask {
+    fn drop(&mut self) {
+        js! { @(no_return)
+            var source = @{&self.source};
+            source.close();
+            source.on_message.drop();
+        }
+    }
+}
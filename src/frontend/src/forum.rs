@@ -1,47 +1,144 @@
-use failure::Error;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use stdweb::traits::{IEvent, IKeyboardEvent};
+use stdweb::unstable::TryInto;
+use stdweb::web::{Node, TypedArray, document};
+use stdweb::web::event::ScrollEvent;
 use yew::prelude::*;
-use yew::format::{Nothing, Json};
+use yew::format::{Binary, Json, Nothing, Text};
 use yew::services::fetch::{FetchService, FetchTask, Request, Response};
-use types::{CreateMessage, CreateThread, Message, Thread};
+use yew::services::reader::{File, FileData, ReaderService, ReaderTask};
+use yew::services::timeout::{TimeoutService, TimeoutTask};
+use yew::services::websocket::{WebSocketService, WebSocketStatus, WebSocketTask};
+use yew::virtual_dom::VNode;
+use types::{
+    Attachment, ClientCommand, CreateMessage, CreateThread, Event, Message, MessagePreview, Page, Role, SearchResult,
+    SetLocked, Thread, ThreadSort, UpdateMessage,
+};
 
 use crate::api;
+use crate::event_source::EventSourceTask;
+
+/// How long to wait before reconnecting a closed event socket
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 pub struct Forum {
     updating: bool,
+    error: Option<String>,
     threads: Option<Vec<Thread>>,
+    threads_next: Option<String>,
+    loading_more: bool,
     current_thread: Option<Thread>,
     show_create_thread: bool,
     create_thread_field: String,
     create_message_field: String,
+    /// The message the next one replies to
+    replying_to: Option<i32>,
+    /// Whether the message form shows the rendered message, and the
+    /// rendering once it has been fetched
+    previewing: bool,
+    preview: Option<String>,
+    /// Uploaded files to attach to the next message
+    attachments: Vec<Attachment>,
+    /// Files waiting to be uploaded, one at a time
+    upload_queue: VecDeque<File>,
+    uploading: bool,
+    /// Object URLs of fetched thumbnails, by attachment id
+    thumbnails: HashMap<i32, String>,
+    /// The message being edited, and its new content
+    editing: Option<(i32, String)>,
+    search_field: String,
+    /// Shown instead of the thread list while set
+    search_results: Option<Vec<SearchResult>>,
 
     token: String,
+    account_id: i32,
+    role: Role,
 
     fetch_service: FetchService,
     link: ComponentLink<Forum>,
     ft: Option<FetchTask>,
+    reader_service: ReaderService,
+    reader_task: Option<ReaderTask>,
+    upload_task: Option<FetchTask>,
+    download_task: Option<FetchTask>,
+    thumbnail_tasks: HashMap<i32, FetchTask>,
+
+    websocket_service: WebSocketService,
+    timeout_service: TimeoutService,
+    socket: Option<WebSocketTask>,
+    /// Whether the socket has ever connected. If it hasn't, WebSockets are
+    /// probably blocked and the event streams are used instead.
+    socket_opened: bool,
+    reconnect_task: Option<TimeoutTask>,
+    feed: Option<EventSourceTask>,
+    thread_feed: Option<EventSourceTask>,
 }
 
 pub enum Msg {
     FetchThreads,
-    FetchError,
+    FetchError(String),
+    DismissError,
 
     CreateThreadForm,
     CreateThread,
     UpdateCreateTitle(String),
 
     ChooseThread(i32),
+    LoadMoreThreads,
+    LoadMoreMessages,
+    Ignore,
 
-    ThreadsFetched(Result<Vec<Thread>, Error>),
-    ThreadFetched(Result<Thread, Error>),
+    /// A page of threads, and whether to append it to the current list
+    ThreadsFetched(Page<Thread>, bool),
+    /// A thread with a page of messages, and whether to append them
+    ThreadFetched(Thread, bool),
 
     UpdateMessageField(String),
     CreateMessage(i32),
+    MessageCreated(i32),
+    /// Reply to a message, and whether to quote it
+    Reply(i32, bool),
+    CancelReply,
+    TogglePreview,
+    PreviewFetched(MessagePreview),
+
+    AttachFiles(Vec<File>),
+    FileRead(FileData),
+    Uploaded(Result<Attachment, String>),
+    RemoveAttachment(i32),
+    ThumbnailFetched(i32, Vec<u8>),
+    Download(i32, String),
+    Downloaded(String, Vec<u8>),
+
+    EditMessage(i32),
+    UpdateEditField(String),
+    CancelEdit,
+    SaveEdit(i32),
+    DeleteMessage(i32, i32),
+    /// A message in the thread was edited or deleted
+    MessageChanged(i32),
+
+    SetLocked(i32, bool),
+
+    UpdateSearchField(String),
+    Search,
+    SearchFetched(Vec<SearchResult>),
+    ClearSearch,
+
+    ServerEvent(Event),
+    SocketStatus(WebSocketStatus),
+    Reconnect,
 }
 
 #[derive(PartialEq, Properties)]
 pub struct Props {
     #[props(required)]
     pub token: String,
+    #[props(required)]
+    pub account_id: i32,
+    #[props(required)]
+    pub role: Role,
 }
 
 impl Component for Forum {
@@ -51,28 +148,72 @@ impl Component for Forum {
     fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
         let mut this = Forum {
             updating: false,
+            error: None,
             threads: None,
+            threads_next: None,
+            loading_more: false,
             current_thread: None,
             show_create_thread: false,
             create_thread_field: "".to_string(),
             create_message_field: "".to_string(),
+            replying_to: None,
+            previewing: false,
+            preview: None,
+            attachments: Vec::new(),
+            upload_queue: VecDeque::new(),
+            uploading: false,
+            thumbnails: HashMap::new(),
+            editing: None,
+            search_field: "".to_string(),
+            search_results: None,
 
             token: props.token,
+            account_id: props.account_id,
+            role: props.role,
 
             fetch_service: FetchService::new(),
             link,
             ft: None,
+            reader_service: ReaderService::new(),
+            reader_task: None,
+            upload_task: None,
+            download_task: None,
+            thumbnail_tasks: HashMap::new(),
+
+            websocket_service: WebSocketService::new(),
+            timeout_service: TimeoutService::new(),
+            socket: None,
+            socket_opened: false,
+            reconnect_task: None,
+            feed: None,
+            thread_feed: None,
         };
-        this.ft = Some(this.fetch_threads());
+        this.ft = Some(this.fetch_threads(None));
+        this.connect();
         this
     }
 
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        // The token changes whenever it gets refreshed, possibly with a new role
+        self.token = props.token;
+        let changed = self.role != props.role;
+        self.role = props.role;
+        changed
+    }
+
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
-            Msg::FetchError => { }
+            Msg::FetchError(error) => {
+                self.updating = false;
+                self.loading_more = false;
+                self.error = Some(error);
+            }
+            Msg::DismissError => {
+                self.error = None;
+            }
             Msg::FetchThreads => {
                 self.updating = true;
-                self.ft = Some(self.fetch_threads());
+                self.ft = Some(self.fetch_threads(None));
             }
             Msg::CreateThreadForm => {
                 self.show_create_thread = true;
@@ -91,16 +232,224 @@ impl Component for Forum {
             Msg::CreateMessage(thread_id) => {
                 self.ft = Some(self.create_message(thread_id));
             }
+            Msg::MessageCreated(thread_id) => {
+                let messages = self.current_thread.as_ref()
+                    .filter(|thread| thread.id == thread_id)
+                    .and_then(|thread| thread.messages.as_ref())
+                    .map(|page| (page.next.is_some(), page.items.last().map(|m| m.id.to_string())));
+                match messages {
+                    // Everything is loaded, so fetch just the new message(s)
+                    Some((false, Some(last))) => {
+                        self.loading_more = true;
+                        self.ft = Some(self.fetch_thread(thread_id, Some(last), true));
+                    }
+                    // The new message shows up when scrolling down
+                    Some((true, _)) => {}
+                    _ => self.link.send_self(Msg::ChooseThread(thread_id)),
+                }
+            }
+            Msg::Reply(id, quote) => {
+                if quote {
+                    if let Some(message) = self.message(id) {
+                        let quoted = quote_message(message);
+                        self.create_message_field = format!("{}{}", quoted, self.create_message_field);
+                        self.previewing = false;
+                    }
+                }
+                self.replying_to = Some(id);
+            }
+            Msg::CancelReply => {
+                self.replying_to = None;
+            }
+            Msg::TogglePreview => {
+                self.previewing = !self.previewing;
+                self.preview = None;
+                if self.previewing {
+                    self.ft = Some(self.fetch_preview());
+                }
+            }
+            Msg::PreviewFetched(preview) => {
+                if self.previewing {
+                    self.preview = Some(preview.content_html);
+                }
+            }
+            Msg::AttachFiles(files) => {
+                self.upload_queue.extend(files);
+                self.upload_next();
+            }
+            Msg::FileRead(file) => {
+                self.reader_task = None;
+                self.upload_task = Some(self.upload(file));
+            }
+            Msg::Uploaded(result) => {
+                self.uploading = false;
+                self.upload_task = None;
+                match result {
+                    Ok(attachment) => {
+                        self.fetch_thumbnails(Some(&attachment));
+                        self.attachments.push(attachment);
+                    }
+                    Err(e) => self.error = Some(e),
+                }
+                self.upload_next();
+            }
+            Msg::RemoveAttachment(id) => {
+                // The upload stays on the server, unattached
+                self.attachments.retain(|attachment| attachment.id != id);
+            }
+            Msg::ThumbnailFetched(id, data) => {
+                self.thumbnail_tasks.remove(&id);
+                self.thumbnails.insert(id, object_url(&data, "image/png"));
+            }
+            Msg::Download(id, filename) => {
+                self.download_task = Some(self.download(id, filename));
+                return false;
+            }
+            Msg::Downloaded(filename, data) => {
+                self.download_task = None;
+                save_file(&filename, &data);
+                return false;
+            }
+            Msg::EditMessage(id) => {
+                self.editing = self.message(id).map(|message| (id, message.content.clone()));
+            }
+            Msg::UpdateEditField(s) => {
+                if let Some((_, content)) = &mut self.editing {
+                    *content = s;
+                }
+            }
+            Msg::CancelEdit => {
+                self.editing = None;
+            }
+            Msg::SaveEdit(thread_id) => {
+                if let Some((id, content)) = self.editing.take() {
+                    self.ft = Some(self.update_message(thread_id, id, content));
+                }
+            }
+            Msg::DeleteMessage(thread_id, id) => {
+                self.ft = Some(self.delete_message(thread_id, id));
+            }
+            Msg::SetLocked(thread_id, locked) => {
+                self.ft = Some(self.set_locked(thread_id, locked));
+            }
+            Msg::UpdateSearchField(s) => {
+                self.search_field = s;
+                return false;
+            }
+            Msg::Search => {
+                if self.search_field.trim().is_empty() {
+                    self.search_results = None;
+                } else {
+                    self.updating = true;
+                    self.ft = Some(self.search());
+                }
+            }
+            Msg::SearchFetched(results) => {
+                self.updating = false;
+                self.search_results = Some(results);
+            }
+            Msg::ClearSearch => {
+                self.search_field = "".to_string();
+                self.search_results = None;
+            }
+            Msg::MessageChanged(thread_id) => {
+                self.ft = Some(self.fetch_thread(thread_id, None, false));
+            }
             Msg::ChooseThread(id) => {
-                self.ft = Some(self.choose_thread(id));
+                self.editing = None;
+                self.replying_to = None;
+                if self.feed.is_some() {
+                    self.thread_feed = Some(self.open_feed(&api::thread_events(id)));
+                } else {
+                    match self.current_thread.as_ref().map(|thread| thread.id) {
+                        Some(previous) if previous != id =>
+                            self.send_command(ClientCommand::Unsubscribe { thread_id: previous }),
+                        _ => {}
+                    }
+                    self.send_command(ClientCommand::Subscribe { thread_id: id });
+                }
+                self.ft = Some(self.fetch_thread(id, None, false));
+            }
+            Msg::LoadMoreThreads => {
+                if self.loading_more || self.threads_next.is_none() {
+                    return false;
+                }
+                self.loading_more = true;
+                let next = self.threads_next.clone();
+                self.ft = Some(self.fetch_threads(next));
             }
-            Msg::ThreadsFetched(threads) => {
+            Msg::LoadMoreMessages => {
+                let next = self.current_thread.as_ref()
+                    .and_then(|thread| Some((thread.id, thread.messages.as_ref()?.next.clone()?)));
+                match next {
+                    Some((id, next)) if !self.loading_more => {
+                        self.loading_more = true;
+                        self.ft = Some(self.fetch_thread(id, Some(next), true));
+                    }
+                    _ => return false,
+                }
+            }
+            Msg::Ignore => return false,
+            Msg::ThreadsFetched(page, append) => {
                 self.updating = false;
-                self.threads = threads.ok();
+                self.loading_more = false;
+                self.threads_next = page.next;
+                match (&mut self.threads, append) {
+                    (Some(threads), true) => threads.extend(page.items),
+                    _ => self.threads = Some(page.items),
+                }
             }
-            Msg::ThreadFetched(thread) => {
+            Msg::ThreadFetched(thread, append) => {
                 self.updating = false;
-                self.current_thread = thread.ok();
+                self.fetch_thumbnails(thread.messages.iter().flat_map(|page| &page.items).flat_map(|m| &m.attachments));
+                self.loading_more = false;
+                self.update_listed_thread(&thread);
+                let current = self.current_thread.as_mut()
+                    .filter(|current| append && current.id == thread.id)
+                    .and_then(|current| current.messages.as_mut());
+                match (current, thread.messages) {
+                    (Some(messages), Some(page)) => {
+                        // Messages may have already arrived over the socket
+                        let new: Vec<Message> = page.items.into_iter()
+                            .filter(|message| !messages.items.iter().any(|m| m.id == message.id))
+                            .collect();
+                        messages.items.extend(new);
+                        messages.next = page.next;
+                    }
+                    (_, messages) => self.current_thread = Some(Thread { messages, ..thread }),
+                }
+            }
+            Msg::ServerEvent(event) => {
+                if let Event::MessageCreated { message, .. } | Event::MessageUpdated { message, .. } = &event {
+                    self.fetch_thumbnails(&message.attachments);
+                }
+                self.apply_event(event);
+            }
+            Msg::SocketStatus(WebSocketStatus::Opened) => {
+                self.socket_opened = true;
+                // Subscriptions don't survive reconnects
+                if let Some(id) = self.current_thread.as_ref().map(|thread| thread.id) {
+                    self.send_command(ClientCommand::Subscribe { thread_id: id });
+                }
+                return false;
+            }
+            Msg::SocketStatus(_) if !self.socket_opened => {
+                self.socket = None;
+                if self.feed.is_none() {
+                    self.fall_back_to_feeds();
+                }
+                return false;
+            }
+            Msg::SocketStatus(_) => {
+                // Closed when the token expires, or when the connection was lost
+                self.socket = None;
+                let callback = self.link.send_back(|_| Msg::Reconnect);
+                self.reconnect_task = Some(self.timeout_service.spawn(RECONNECT_DELAY, callback));
+                return false;
+            }
+            Msg::Reconnect => {
+                self.connect();
+                return false;
             }
         }
         true
@@ -111,8 +460,10 @@ impl Renderable<Forum> for Forum {
     fn view(&self) -> Html<Self> {
         html! {
             <div class="forum-view">
+                { self.render_error() }
                 <div class="row">
-                    <div class="thread-list">
+                    <div class="thread-list"
+                        onscroll=|e| if scrolled_to_bottom(&e) { Msg::LoadMoreThreads } else { Msg::Ignore }>
                         <div class="thread-list-header">
                             <h5>{ "Thread list" }</h5>
                             {
@@ -125,12 +476,19 @@ impl Renderable<Forum> for Forum {
                                 }
                             }
                         </div>
+                        { self.render_search_box() }
                         { self.create_thread_form() }
                         <div class="thread-list-content">
-                            { self.render_threads() }
+                            {
+                                match &self.search_results {
+                                    Some(results) => self.render_search_results(results),
+                                    None => self.render_threads(),
+                                }
+                            }
                         </div>
                     </div>
-                    <div class="thread-view">
+                    <div class="thread-view"
+                        onscroll=|e| if scrolled_to_bottom(&e) { Msg::LoadMoreMessages } else { Msg::Ignore }>
                         { self.render_current_thread() }
                     </div>
                 </div>
@@ -139,12 +497,88 @@ impl Renderable<Forum> for Forum {
     }
 }
 
+/// Whether the scrolled element is (almost) scrolled to the bottom
+fn scrolled_to_bottom(event: &ScrollEvent) -> bool {
+    let target = match event.target() {
+        Some(target) => target,
+        None => return false,
+    };
+    let at_bottom = js! {
+        var element = @{target};
+        return element.scrollTop + element.clientHeight >= element.scrollHeight - 50;
+    };
+    at_bottom.try_into().unwrap_or(false)
+}
+
+/// The start of a reply quoting `message`
+fn quote_message(message: &Message) -> String {
+    let lines: Vec<String> = message.content.lines().map(|line| format!("> {}", line)).collect();
+    format!("> {} wrote:\n{}\n\n", message.creator, lines.join("\n"))
+}
+
+/// A URL for showing or downloading `data` until it's revoked
+fn object_url(data: &[u8], content_type: &str) -> String {
+    let data = TypedArray::<u8>::from(data);
+    let url = js! {
+        return URL.createObjectURL(new Blob([@{data}], { type: @{content_type} }));
+    };
+    url.try_into().unwrap_or_default()
+}
+
+/// Have the browser save `data` as a file called `filename`
+fn save_file(filename: &str, data: &[u8]) {
+    let url = object_url(data, "application/octet-stream");
+    js! { @(no_return)
+        var link = document.createElement("a");
+        link.href = @{&url};
+        link.download = @{filename};
+        document.body.appendChild(link);
+        link.click();
+        document.body.removeChild(link);
+        URL.revokeObjectURL(link.href);
+    }
+}
+
+/// File sizes the way people read them
+fn format_size(size: i32) -> String {
+    match size {
+        size if size < 1024 => format!("{} B", size),
+        size if size < 1024 * 1024 => format!("{:.1} KiB", f64::from(size) / 1024.0),
+        size => format!("{:.1} MiB", f64::from(size) / (1024.0 * 1024.0)),
+    }
+}
+
+/// Message HTML as rendered and sanitized by the backend
+fn render_content(content_html: &str) -> Html<Forum> {
+    let element = document().create_element("div").unwrap();
+    js! { @(no_return)
+        var element = @{&element};
+        element.className = "message-content";
+        element.innerHTML = @{content_html};
+    }
+    VNode::VRef(Node::from(element))
+}
+
 impl Forum {
+    fn render_error(&self) -> Html<Self> {
+        if let Some(error) = &self.error {
+            html! {
+                <div class="alert alert-danger forum-error" role="alert">
+                    { error }
+                    <button type="button" class="close" onclick=|_| Msg::DismissError>{ "×" }</button>
+                </div>
+            }
+        } else {
+            html! {}
+        }
+    }
+
     fn render_threads(&self) -> Html<Self> {
         if let Some(threads) = &self.threads {
             html! {
                 <div class="list-group">
                     { for threads.iter().map(|t| self.render_thread(t)) }
+                    { if self.loading_more { html! { <p class="p-3">{ "Loading..." }</p> } } else { html! {} } }
                 </div>
             }
         } else {
@@ -154,14 +588,68 @@ impl Forum {
         }
     }
 
+    fn render_search_box(&self) -> Html<Self> {
+        html! {
+            <div class="search-box">
+                <input class="form-control" type="search" placeholder="Search" autocomplete="off"
+                    value=&self.search_field
+                    oninput=|e| Msg::UpdateSearchField(e.value)
+                    onkeypress=|e| if e.key() == "Enter" { Msg::Search } else { Msg::Ignore } />
+                {
+                    if self.search_results.is_some() {
+                        html! { <button class="btn btn-link" onclick=|_| Msg::ClearSearch>{ "Clear" }</button> }
+                    } else {
+                        html! {}
+                    }
+                }
+            </div>
+        }
+    }
+
+    fn render_search_results(&self, results: &[SearchResult]) -> Html<Self> {
+        if results.is_empty() {
+            return html! { <p class="p-3">{ "No matches" }</p> };
+        }
+        html! {
+            <div class="list-group">
+                { for results.iter().map(|result| {
+                    let thread_id = result.thread_id;
+                    html! {
+                        <button class="thread-list-item" onclick=|_| Msg::ChooseThread(thread_id)>
+                            <b>{ &result.thread_title }</b>
+                            <br />
+                            <small class="search-snippet">
+                                { format!("{}: ", result.creator) }
+                                { for result.snippet.iter().map(|part| if part.highlighted {
+                                    html! { <mark>{ &part.text }</mark> }
+                                } else {
+                                    html! { <span>{ &part.text }</span> }
+                                }) }
+                            </small>
+                        </button>
+                    }
+                }) }
+            </div>
+        }
+    }
+
     fn render_current_thread(&self) -> Html<Self> {
         if let Some(thread) = &self.current_thread {
             html! {
                 <div class="thread">
-                    <h4>{ &thread.title }</h4>
-                    { self.render_current_messages(&thread.messages.as_ref().unwrap_or(&vec![])) }
+                    <div class="thread-header">
+                        <h4>{ &thread.title }</h4>
+                        { self.render_lock_control(thread) }
+                    </div>
+                    { self.render_current_messages(thread.id, thread.messages.as_ref().map(|page| &page.items[..]).unwrap_or(&[])) }
                     <hr />
-                    { self.create_message_field() }
+                    {
+                        if thread.locked && !self.is_moderator() {
+                            html! { <p class="text-muted">{ "This thread is locked." }</p> }
+                        } else {
+                            self.create_message_field()
+                        }
+                    }
                 </div>
             }
         } else {
@@ -173,34 +661,233 @@ impl Forum {
         }
     }
 
-    fn render_current_messages(&self, messages: &[Message]) -> Html<Self> {
+    fn is_moderator(&self) -> bool {
+        self.role >= Role::Moderator
+    }
+
+    fn render_lock_control(&self, thread: &Thread) -> Html<Self> {
+        let id = thread.id;
+        let locked = thread.locked;
+        if self.is_moderator() {
+            html! {
+                <button class="btn btn-outline-secondary btn-sm" onclick=|_| Msg::SetLocked(id, !locked)>
+                    { if locked { "Unlock" } else { "Lock" } }
+                </button>
+            }
+        } else if locked {
+            html! { <span class="badge badge-secondary">{ "Locked" }</span> }
+        } else {
+            html! {}
+        }
+    }
+
+    /// A loaded message of the current thread
+    fn message(&self, id: i32) -> Option<&Message> {
+        self.current_thread.as_ref()
+            .and_then(|thread| thread.messages.as_ref())
+            .and_then(|page| page.items.iter().find(|m| m.id == id))
+    }
+
+    fn render_current_messages(&self, thread_id: i32, messages: &[Message]) -> Html<Self> {
         if messages.is_empty() {
             html! {
                 "No messages yet! Be the first one to post here ;)"
             }
         } else {
+            // Replies whose parent isn't loaded (or was deleted) go on the top level
+            let roots = messages.iter()
+                .filter(|msg| msg.parent_id.map_or(true, |parent| !messages.iter().any(|m| m.id == parent)));
             html! {
                 <ul class="list-group">
-                { for messages.iter().map(
-                        |msg| html! {
-                            <li class="list-group-item">{ format!("{} | {}", &msg.creator, &msg.content) }</li>
-                        })
-                }
+                { for roots.map(|msg| self.render_message_tree(thread_id, msg, messages)) }
                 </ul>
             }
         }
     }
 
+    /// A message with its replies nested below it
+    fn render_message_tree(&self, thread_id: i32, msg: &Message, messages: &[Message]) -> Html<Self> {
+        let replies: Vec<&Message> = messages.iter().filter(|m| m.parent_id == Some(msg.id)).collect();
+        html! {
+            <li class="list-group-item message">
+                { self.render_message(thread_id, msg) }
+                {
+                    if replies.is_empty() {
+                        html! {}
+                    } else {
+                        html! {
+                            <ul class="message-replies">
+                            { for replies.into_iter().map(|reply| self.render_message_tree(thread_id, reply, messages)) }
+                            </ul>
+                        }
+                    }
+                }
+            </li>
+        }
+    }
+
+    fn render_message(&self, thread_id: i32, msg: &Message) -> Html<Self> {
+        let id = msg.id;
+        let edited = if msg.edited_at.is_some() { " (edited)" } else { "" };
+        let content = match &self.editing {
+            Some((editing, content)) if *editing == id => html! {
+                <div class="message-edit">
+                    <textarea class="form-control" value=content oninput=|e| Msg::UpdateEditField(e.value) />
+                    <button class="btn btn-primary btn-sm" onclick=|_| Msg::SaveEdit(thread_id)>{ "Save" }</button>
+                    <button class="btn btn-link btn-sm" onclick=|_| Msg::CancelEdit>{ "Cancel" }</button>
+                </div>
+            },
+            _ => html! {
+                <div>
+                    <b>{ &msg.creator }</b>
+                    { render_content(&msg.content_html) }
+                    { self.render_attachments(&msg.attachments) }
+                </div>
+            },
+        };
+        let locked = self.current_thread.as_ref().map(|t| t.locked).unwrap_or(false);
+        let can_post = self.is_moderator() || !locked;
+        let can_modify = self.is_moderator() || (msg.creator_id == self.account_id && !locked);
+        let controls = if self.editing.is_none() {
+            html! {
+                <span class="message-controls">
+                    {
+                        if can_post {
+                            html! {
+                                <span>
+                                    <button class="btn btn-link btn-sm" onclick=|_| Msg::Reply(id, false)>{ "Reply" }</button>
+                                    <button class="btn btn-link btn-sm" onclick=|_| Msg::Reply(id, true)>{ "Quote" }</button>
+                                </span>
+                            }
+                        } else {
+                            html! {}
+                        }
+                    }
+                    {
+                        if can_modify {
+                            html! {
+                                <span>
+                                    <button class="btn btn-link btn-sm" onclick=|_| Msg::EditMessage(id)>{ "Edit" }</button>
+                                    <button class="btn btn-link btn-sm text-danger"
+                                            onclick=|_| Msg::DeleteMessage(thread_id, id)>{ "Delete" }</button>
+                                </span>
+                            }
+                        } else {
+                            html! {}
+                        }
+                    }
+                </span>
+            }
+        } else {
+            html! {}
+        };
+        html! {
+            <div class="message-body">
+                { content }
+                <small class="message-meta">
+                    { format!("{}{}", msg.created_at.format("%Y-%m-%d %H:%M"), edited) }
+                </small>
+                { controls }
+            </div>
+        }
+    }
+
+    fn render_attachment(&self, attachment: &Attachment) -> Html<Self> {
+        let id = attachment.id;
+        let filename = attachment.filename.clone();
+        match self.thumbnails.get(&id) {
+            Some(url) => html! {
+                <button type="button" class="btn btn-link attachment-image" title=&attachment.filename
+                        onclick=|_| Msg::Download(id, filename.clone())>
+                    <img src=url alt=&attachment.filename />
+                </button>
+            },
+            None => html! {
+                <button type="button" class="btn btn-link btn-sm attachment-file"
+                        onclick=|_| Msg::Download(id, filename.clone())>
+                    { format!("{} ({})", attachment.filename, format_size(attachment.size)) }
+                </button>
+            },
+        }
+    }
+
+    fn render_attachments(&self, attachments: &[Attachment]) -> Html<Self> {
+        if attachments.is_empty() {
+            return html! {};
+        }
+        html! {
+            <ul class="attachments">
+                { for attachments.iter().map(|a| html! { <li>{ self.render_attachment(a) }</li> }) }
+            </ul>
+        }
+    }
+
+    fn render_pending_attachments(&self) -> Html<Self> {
+        let pending = self.attachments.iter().map(|attachment| {
+            let id = attachment.id;
+            html! {
+                <li>
+                    { self.render_attachment(attachment) }
+                    <button type="button" class="btn btn-link btn-sm text-danger" title="Remove"
+                            onclick=|_| Msg::RemoveAttachment(id)>{ "×" }</button>
+                </li>
+            }
+        });
+        let uploading = if self.uploading || !self.upload_queue.is_empty() {
+            html! { <li class="text-muted">{ format!("Uploading {} file(s)...", self.upload_queue.len() + 1) }</li> }
+        } else {
+            html! {}
+        };
+        html! {
+            <ul class="attachments pending-attachments">
+                { for pending }
+                { uploading }
+            </ul>
+        }
+    }
+
+    fn render_replying_to(&self) -> Html<Self> {
+        match self.replying_to {
+            Some(id) => {
+                let creator = self.message(id).map(|m| m.creator.as_str()).unwrap_or("a message");
+                html! {
+                    <div class="replying-to">
+                        <small>{ format!("Replying to {}", creator) }</small>
+                        <button type="button" class="btn btn-link btn-sm" onclick=|_| Msg::CancelReply>{ "Cancel" }</button>
+                    </div>
+                }
+            }
+            None => html! {},
+        }
+    }
+
     fn create_message_field(&self) -> Html<Self> {
         if let Some(thread) = &self.current_thread {
             let id = thread.id;
             html! {
                 <form>
                     <div class="form-group">
-                        <label for="inputMessage">{ "Message" }</label>
-                        <input id="inputMessage" class="form-control" placeholder="Create new message"
-                        autofocus="" autocomplete="off"
-                        value=&self.create_message_field oninput=|e| Msg::UpdateMessageField(e.value) />
+                        <div class="message-form-header">
+                            <label for="inputMessage">{ "Message" }</label>
+                            <button type="button" class="btn btn-link btn-sm" onclick=|_| Msg::TogglePreview>
+                                { if self.previewing { "Write" } else { "Preview" } }
+                            </button>
+                        </div>
+                        { self.render_replying_to() }
+                        { self.render_message_input() }
+                        <small class="form-text text-muted">{ "Formatted with Markdown" }</small>
+                    </div>
+                    <div class="form-group">
+                        <label for="inputAttachments">{ "Attachments" }</label>
+                        <input id="inputAttachments" type="file" class="form-control-file" multiple=true
+                               onchange=|value| {
+                                   let mut files = Vec::new();
+                                   if let ChangeData::Files(list) = value {
+                                       files.extend(list);
+                                   }
+                                   Msg::AttachFiles(files)
+                               } />
+                        { self.render_pending_attachments() }
                     </div>
 
                     <button class="btn btn-primary" onclick=|_| Msg::CreateMessage(id)>{ "Send message" }</button>
@@ -211,51 +898,74 @@ impl Forum {
         }
     }
 
-    fn fetch_threads(&mut self) -> FetchTask {
+    fn render_message_input(&self) -> Html<Self> {
+        match (self.previewing, &self.preview) {
+            (false, _) => html! {
+                <textarea id="inputMessage" class="form-control" placeholder="Create new message" rows=3
+                autofocus=""
+                value=&self.create_message_field oninput=|e| Msg::UpdateMessageField(e.value) />
+            },
+            (true, Some(preview)) => html! {
+                <div class="message-preview">{ render_content(preview) }</div>
+            },
+            (true, None) => html! {
+                <div class="message-preview text-muted">{ "Loading preview..." }</div>
+            },
+        }
+    }
+
+    /// Fetch the first page of threads, or the page after `after`
+    fn fetch_threads(&mut self, after: Option<String>) -> FetchTask {
+        let append = after.is_some();
         let callback = self.link.send_back(
-            move |response: Response<Json<Result<Vec<Thread>, Error>>>| {
-                let (meta, Json(data)) = response.into_parts();
-                if meta.status.is_success() {
-                    Msg::ThreadsFetched(data)
-                } else {
-                    Msg::FetchError
-                }
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(threads) => Msg::ThreadsFetched(threads, append),
+                Err(e) => Msg::FetchError(e),
             },
         );
-        let request = Request::get(api::all_threads()).body(Nothing).unwrap();
+        let request = Request::get(api::all_threads(ThreadSort::RecentActivity, after.as_ref().map(|s| s.as_str())))
+            .body(Nothing)
+            .unwrap();
         self.fetch_service.fetch(request, callback)
     }
 
-    fn choose_thread(&mut self, id: i32) -> FetchTask {
+    fn search(&mut self) -> FetchTask {
         let callback = self.link.send_back(
-            move |response: Response<Json<Result<Thread, Error>>>| {
-                let (meta, Json(data)) = response.into_parts();
-                if meta.status.is_success() {
-                    Msg::ThreadFetched(data)
-                } else {
-                    Msg::FetchError
-                }
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(results) => Msg::SearchFetched(results),
+                Err(e) => Msg::FetchError(e),
             },
         );
-        let request = Request::get(api::thread(id)).body(Nothing).unwrap();
+        let request = Request::get(api::search(self.search_field.trim()))
+            .body(Nothing)
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn fetch_thread(&mut self, id: i32, after: Option<String>, append: bool) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(thread) => Msg::ThreadFetched(thread, append),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let request = Request::get(api::thread(id, after.as_ref().map(|s| s.as_str())))
+            .body(Nothing)
+            .unwrap();
         self.fetch_service.fetch(request, callback)
     }
 
     fn create_thread(&mut self) -> FetchTask {
         let callback = self.link.send_back(
-            move |response: Response<Json<Result<Thread, Error>>>| {
-                let (meta, Json(_)) = response.into_parts();
-                if meta.status.is_success() {
-                    Msg::FetchThreads
-                } else {
-                    Msg::FetchError
-                }
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::FetchThreads,
+                Err(e) => Msg::FetchError(e),
             },
         );
         let body = CreateThread { title: self.create_thread_field.to_string() };
 
         let request = Request::post(api::new_thread())
-            .header("token", &self.token)
+            .header("Authorization", format!("Bearer {}", self.token))
             .body(Ok(serde_json::to_string(&body).unwrap()))
             .unwrap();
         self.fetch_service.fetch(request, callback)
@@ -263,21 +973,144 @@ impl Forum {
 
     fn create_message(&mut self, thread_id: i32) -> FetchTask {
         let callback = self.link.send_back(
-            move |response: Response<Json<Result<(), Error>>>| {
-                let (meta, Json(_)) = response.into_parts();
-                if meta.status.is_success() {
-                    Msg::ChooseThread(thread_id)
-                } else {
-                    Msg::FetchError
-                }
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::MessageCreated(thread_id),
+                Err(e) => Msg::FetchError(e),
             },
         );
-        let body = CreateMessage { content: self.create_message_field.to_string() };
+        let body = CreateMessage {
+            content: self.create_message_field.to_string(),
+            parent_id: self.replying_to.take(),
+            attachment_ids: self.attachments.drain(..).map(|attachment| attachment.id).collect(),
+        };
         let request = Request::post(api::new_message(thread_id))
-            .header("token", &self.token)
+            .header("Authorization", format!("Bearer {}", self.token))
             .body(Ok(serde_json::to_string(&body).unwrap()))
             .unwrap();
         self.create_message_field = "".to_string();
+        self.previewing = false;
+        self.preview = None;
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn fetch_preview(&mut self) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(preview) => Msg::PreviewFetched(preview),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let body = UpdateMessage { content: self.create_message_field.to_string() };
+        let request = Request::post(api::markdown_preview())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    /// Start reading the next queued file, unless an upload is under way
+    fn upload_next(&mut self) {
+        if self.uploading {
+            return;
+        }
+        if let Some(file) = self.upload_queue.pop_front() {
+            self.uploading = true;
+            let callback = self.link.send_back(Msg::FileRead);
+            self.reader_task = Some(self.reader_service.read_file(file, callback));
+        }
+    }
+
+    fn upload(&mut self, file: FileData) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Binary>| Msg::Uploaded(api::parse_binary(response)),
+        );
+        let (body, content_type) = api::multipart_file(&file.name, &file.content);
+        let request = Request::post(api::new_attachment())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .header("Content-Type", content_type)
+            .body(Ok(body))
+            .unwrap();
+        self.fetch_service.fetch_binary(request, callback)
+    }
+
+    /// Fetch the thumbnails that aren't loaded or being loaded yet. They
+    /// need the token, so they can't just be linked to.
+    fn fetch_thumbnails<'a, I: IntoIterator<Item = &'a Attachment>>(&mut self, attachments: I) {
+        for attachment in attachments {
+            let id = attachment.id;
+            if !attachment.thumbnail || self.thumbnails.contains_key(&id) || self.thumbnail_tasks.contains_key(&id) {
+                continue;
+            }
+            let callback = self.link.send_back(
+                move |response: Response<Binary>| match api::bytes(response) {
+                    Ok(data) => Msg::ThumbnailFetched(id, data),
+                    Err(e) => Msg::FetchError(e),
+                },
+            );
+            let request = Request::get(api::thumbnail(id))
+                .header("Authorization", format!("Bearer {}", self.token))
+                .body(Nothing)
+                .unwrap();
+            let task = self.fetch_service.fetch_binary(request, callback);
+            self.thumbnail_tasks.insert(id, task);
+        }
+    }
+
+    fn download(&mut self, id: i32, filename: String) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Binary>| match api::bytes(response) {
+                Ok(data) => Msg::Downloaded(filename.clone(), data),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let request = Request::get(api::attachment(id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Nothing)
+            .unwrap();
+        self.fetch_service.fetch_binary(request, callback)
+    }
+
+    fn update_message(&mut self, thread_id: i32, message_id: i32, content: String) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::MessageChanged(thread_id),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let body = UpdateMessage { content };
+        let request = Request::put(api::message(thread_id, message_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn delete_message(&mut self, thread_id: i32, message_id: i32) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::MessageChanged(thread_id),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let request = Request::delete(api::message(thread_id, message_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Nothing)
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn set_locked(&mut self, thread_id: i32, locked: bool) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::MessageChanged(thread_id),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let body = SetLocked { locked };
+        let request = Request::put(api::lock_thread(thread_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
         self.fetch_service.fetch(request, callback)
     }
 
@@ -303,18 +1136,126 @@ impl Forum {
 
     fn render_thread(&self, thread: &Thread) -> Html<Forum> {
         let id = thread.id;
-        if Some(id) == self.current_thread.as_ref().map(|t| t.id) {
-            html! { <button class="thread-list-item active disabled">
-                <b>{ &thread.title }</b>
-                <br />
-                <small>{ &thread.creator }</small>
-            </button> }
-        } else {
-            html! { <button class="thread-list-item" onclick=|_| Msg::ChooseThread(id)>
+        let active = Some(id) == self.current_thread.as_ref().map(|t| t.id);
+        let preview = match &thread.latest_message {
+            Some(message) => format!("{}: {}", message.creator, message.content),
+            None => "No messages yet".to_string(),
+        };
+        html! {
+            <button class=if active { "thread-list-item active disabled" } else { "thread-list-item" }
+                    disabled=active
+                    onclick=|_| Msg::ChooseThread(id)>
                 <b>{ &thread.title }</b>
                 <br />
-                <small>{ &thread.creator }</small>
-            </button> }
+                <small>{ format!("{} | {} messages", thread.creator, thread.message_count) }</small>
+                <small class="thread-list-preview">{ preview }</small>
+            </button>
+        }
+    }
+
+    /// Keep the thread list in sync with a freshly fetched thread, moving it
+    /// to the top if there has been new activity.
+    fn update_listed_thread(&mut self, thread: &Thread) {
+        let threads = match self.threads.as_mut() {
+            Some(threads) => threads,
+            None => return,
+        };
+        if let Some(position) = threads.iter().position(|t| t.id == thread.id) {
+            let mut listed = threads.remove(position);
+            let position = if thread.updated_at > listed.updated_at { 0 } else { position };
+            listed.title = thread.title.clone();
+            listed.locked = thread.locked;
+            listed.updated_at = thread.updated_at;
+            listed.message_count = thread.message_count;
+            listed.latest_message = thread.latest_message.clone();
+            threads.insert(position, listed);
+        }
+    }
+
+    fn connect(&mut self) {
+        let callback = self.link.send_back(|Json(event): Json<Result<Event, failure::Error>>| match event {
+            Ok(event) => Msg::ServerEvent(event),
+            Err(_) => Msg::Ignore,
+        });
+        let notification = self.link.send_back(Msg::SocketStatus);
+        self.socket = Some(self.websocket_service.connect(&api::events_socket(&self.token), callback, notification));
+    }
+
+    fn open_feed(&mut self, url: &str) -> EventSourceTask {
+        let callback = self.link.send_back(|data: String| match serde_json::from_str(&data) {
+            Ok(event) => Msg::ServerEvent(event),
+            Err(_) => Msg::Ignore,
+        });
+        EventSourceTask::new(url, callback)
+    }
+
+    /// Receive events over Server-Sent Events, which gets through proxies
+    /// that break WebSockets
+    fn fall_back_to_feeds(&mut self) {
+        info!("WebSocket unavailable, falling back to event streams");
+        self.feed = Some(self.open_feed(&api::events()));
+        if let Some(id) = self.current_thread.as_ref().map(|thread| thread.id) {
+            self.thread_feed = Some(self.open_feed(&api::thread_events(id)));
+        }
+    }
+
+    fn send_command(&mut self, command: ClientCommand) {
+        if let Some(socket) = self.socket.as_mut() {
+            socket.send(Json(&command));
+        }
+    }
+
+    fn current_messages(&mut self, thread_id: i32) -> Option<&mut Page<Message>> {
+        self.current_thread.as_mut()
+            .filter(|thread| thread.id == thread_id)
+            .and_then(|thread| thread.messages.as_mut())
+    }
+
+    /// Apply a change pushed by the backend
+    fn apply_event(&mut self, event: Event) {
+        match event {
+            Event::ThreadCreated { thread } => {
+                if let Some(threads) = self.threads.as_mut() {
+                    if !threads.iter().any(|t| t.id == thread.id) {
+                        threads.insert(0, thread);
+                    }
+                }
+            }
+            Event::ThreadUpdated { thread } => {
+                self.update_listed_thread(&thread);
+                if let Some(current) = self.current_thread.as_mut().filter(|t| t.id == thread.id) {
+                    *current = Thread { messages: current.messages.take(), ..thread };
+                }
+            }
+            Event::ThreadDeleted { thread_id } => {
+                if let Some(threads) = self.threads.as_mut() {
+                    threads.retain(|t| t.id != thread_id);
+                }
+                if self.current_thread.as_ref().map(|t| t.id) == Some(thread_id) {
+                    self.current_thread = None;
+                }
+            }
+            Event::MessageCreated { thread_id, message } => {
+                // Only append to a fully loaded thread, otherwise the message
+                // shows up when scrolling down
+                if let Some(page) = self.current_messages(thread_id).filter(|page| page.next.is_none()) {
+                    if !page.items.iter().any(|m| m.id == message.id) {
+                        page.items.push(message);
+                    }
+                }
+            }
+            Event::MessageUpdated { thread_id, message } => {
+                if let Some(page) = self.current_messages(thread_id) {
+                    if let Some(existing) = page.items.iter_mut().find(|m| m.id == message.id) {
+                        *existing = message;
+                    }
+                }
+            }
+            Event::MessageDeleted { thread_id, message_id } => {
+                if let Some(page) = self.current_messages(thread_id) {
+                    page.items.retain(|m| m.id != message_id);
+                }
+            }
         }
     }
 }
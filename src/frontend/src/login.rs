@@ -1,20 +1,32 @@
-use failure::Error;
 use yew::prelude::*;
-use yew::format::Json;
+use yew::format::Text;
 use yew::services::fetch::{FetchService, FetchTask, Request, Response};
 use stdweb::traits::IEvent;
 
-use types::{CreateAccount, Token};
+use types::{
+    CreateAccount, LoginResult, MfaChallenge, MfaLogin, RequestPasswordReset, ResetPassword, Token, VerifyEmail,
+};
 
 use crate::api;
 
 pub struct Login {
+    username: String,
     email: String,
     password: String,
-    error: Option<LoginError>,
+    /// From the authenticator app, or a recovery code
+    code: String,
+    /// Challenge of a password login that still needs the second factor
+    mfa_token: Option<String>,
+    /// Showing the sign up form instead of the login one
+    creating: bool,
+    error: Option<String>,
+    /// Something that went right, such as a mailed reset link
+    notice: Option<String>,
     loading: bool,
+    /// Token of the password reset link the page was opened with
+    reset_token: Option<String>,
 
-    onlogin: Callback<String>,
+    onlogin: Callback<Token>,
 
     fetch_service: FetchService,
     link: ComponentLink<Login>,
@@ -22,23 +34,32 @@ pub struct Login {
 }
 
 pub enum Msg {
+    UpdateUsername(String),
     UpdateEmail(String),
     UpdatePassword(String),
+    UpdateCode(String),
     Login,
+    MfaRequired(MfaChallenge),
+    SubmitCode,
+    CancelCode,
+    ToggleCreating,
     CreateAccount,
-    FetchError(LoginError),
-    LoginSuccess(String),
+    ForgotPassword,
+    ResetRequested,
+    ResetPassword,
+    PasswordReset,
+    EmailVerified,
+    FetchError(String),
+    LoginSuccess(Token),
 }
 
 #[derive(PartialEq, Properties)]
 pub struct Props {
     #[props(required)]
-    pub onlogin: Callback<String>,
-}
-
-pub enum LoginError {
-    Login,
-    CreateAccount,
+    pub onlogin: Callback<Token>,
+    pub reset_token: Option<String>,
+    /// Token of the verification link the page was opened with
+    pub verify_token: Option<String>,
 }
 
 
@@ -47,34 +68,95 @@ impl Component for Login {
     type Properties = Props;
 
     fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
-        Login {
+        let mut login = Login {
+            username: "".to_string(),
             email: "".to_string(),
             password: "".to_string(),
+            code: "".to_string(),
+            mfa_token: None,
+            creating: false,
             error: None,
+            notice: None,
             loading: false,
+            reset_token: props.reset_token,
 
             onlogin: props.onlogin,
 
             fetch_service: FetchService::new(),
             link,
             ft: None,
+        };
+        if let Some(token) = props.verify_token {
+            login.ft = Some(login.verify_email(token));
+            login.loading = true;
         }
+        login
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
+            Msg::UpdateUsername(username) => self.username = username,
             Msg::UpdateEmail(email) => self.email = email,
             Msg::UpdatePassword(pw) => self.password = pw,
+            Msg::UpdateCode(code) => self.code = code,
             Msg::Login => {
                 self.error = None;
                 self.ft = Some(self.login());
                 self.loading = true;
             }
+            Msg::MfaRequired(challenge) => {
+                self.loading = false;
+                self.password = "".to_string();
+                self.code = "".to_string();
+                self.mfa_token = Some(challenge.mfa_token);
+            }
+            Msg::SubmitCode => {
+                self.error = None;
+                self.ft = self.login_mfa();
+                self.loading = true;
+            }
+            Msg::CancelCode => {
+                self.mfa_token = None;
+                self.error = None;
+            }
+            Msg::ToggleCreating => {
+                self.creating = !self.creating;
+                self.error = None;
+            }
             Msg::CreateAccount => {
                 self.error = None;
                 self.ft = Some(self.create_account());
                 self.loading = true;
             }
+            Msg::ForgotPassword => {
+                if self.email.trim().is_empty() {
+                    self.error = Some("Enter the email address of your account first".to_string());
+                    return true;
+                }
+                self.error = None;
+                self.ft = Some(self.request_reset());
+                self.loading = true;
+            }
+            Msg::ResetRequested => {
+                self.loading = false;
+                self.notice = Some(format!("If there's an account for {}, a link to reset its password is on \
+                                            its way", self.email));
+            }
+            Msg::ResetPassword => {
+                self.error = None;
+                self.ft = self.reset_password();
+                self.loading = true;
+            }
+            Msg::PasswordReset => {
+                self.loading = false;
+                self.reset_token = None;
+                self.password = "".to_string();
+                self.notice = Some("Your password has been changed, log in with the new one".to_string());
+            }
+            Msg::EmailVerified => {
+                self.loading = false;
+                self.notice = Some("Your email address has been verified, log in to start posting".to_string());
+            }
             Msg::LoginSuccess(token) => {
                 self.loading = false;
                 self.onlogin.emit(token);
@@ -98,6 +180,15 @@ fn spinner() -> Html<Login> {
 
 impl Renderable<Login> for Login {
     fn view(&self) -> Html<Self> {
+        if self.reset_token.is_some() {
+            return self.reset_form();
+        }
+        if self.creating {
+            return self.create_form();
+        }
+        if self.mfa_token.is_some() {
+            return self.code_form();
+        }
         html! {
             <div class="login-form-container">
                 <div class="login-form-content">
@@ -122,9 +213,11 @@ impl Renderable<Login> for Login {
                                 <button type="submit" class="btn btn-primary" onclick=|e| { e.prevent_default(); Msg::Login }>{ "Log in "}</button>
                                 { if self.loading { spinner() } else { html! {} } }
 
-                                <button type="submit" class="btn btn-link" onclick=|e| { e.prevent_default(); Msg::CreateAccount }>{ "Create account"}</button>
+                                <button type="button" class="btn btn-link" onclick=|_| Msg::ToggleCreating>{ "Create account" }</button>
+                                <button type="button" class="btn btn-link" onclick=|_| Msg::ForgotPassword>{ "Forgot password?" }</button>
                             </div>
 
+                            { self.login_notice() }
                             { self.login_error() }
                         </form>
                     </div>
@@ -137,24 +230,17 @@ impl Renderable<Login> for Login {
 impl Login {
     fn create_account(&mut self) -> FetchTask {
         let callback = self.link.send_back(
-            move |response: Response<Json<Result<Token, Error>>>| {
-                let (meta, Json(data)) = response.into_parts();
-                if meta.status.is_success() {
-                    if let Ok(token) = data {
-                        Msg::LoginSuccess(token.token)
-                    } else {
-                        Msg::FetchError(LoginError::CreateAccount)
-                    }
-                } else {
-                    Msg::FetchError(LoginError::CreateAccount)
-                }
+            move |response: Response<Text>| match api::parse::<Token>(response) {
+                Ok(token) => Msg::LoginSuccess(token),
+                Err(e) => Msg::FetchError(format!("Account creation failed: {}", e)),
             },
         );
 
-        let username = self.email.to_string();
+        let username = self.username.trim().to_string();
+        let email = self.email.trim().to_string();
         let password = self.password.to_string();
 
-        let body = CreateAccount { username, password };
+        let body = CreateAccount { username, email, password };
 
         let request = Request::post(api::create_account())
             .body(Ok(serde_json::to_string(&body).unwrap()))
@@ -164,24 +250,17 @@ impl Login {
 
     fn login(&mut self) -> FetchTask {
         let callback = self.link.send_back(
-            move |response: Response<Json<Result<Token, Error>>>| {
-                let (meta, Json(data)) = response.into_parts();
-                if meta.status.is_success() {
-                    if let Ok(token) = data {
-                        Msg::LoginSuccess(token.token)
-                    } else {
-                        Msg::FetchError(LoginError::Login)
-                    }
-                } else {
-                    Msg::FetchError(LoginError::Login)
-                }
+            move |response: Response<Text>| match api::parse::<LoginResult>(response) {
+                Ok(LoginResult::Token(token)) => Msg::LoginSuccess(token),
+                Ok(LoginResult::MfaRequired(challenge)) => Msg::MfaRequired(challenge),
+                Err(e) => Msg::FetchError(format!("Login failed: {}", e)),
             },
         );
 
-        let username = self.email.to_string();
+        let email = self.email.trim().to_string();
         let password = self.password.to_string();
 
-        let body = types::Login { username, password };
+        let body = types::Login { email, password };
 
         let request = Request::post(api::login())
             .body(Ok(serde_json::to_string(&body).unwrap()))
@@ -189,13 +268,178 @@ impl Login {
         self.fetch_service.fetch(request, callback)
     }
 
-    fn login_error(&self) -> Html<Self> {
-        match self.error {
-            Some(LoginError::Login) => html! {
-                <div class="login-error">{ "Login failed, check username and password" }</div>
+    fn login_mfa(&mut self) -> Option<FetchTask> {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse::<Token>(response) {
+                Ok(token) => Msg::LoginSuccess(token),
+                Err(e) => Msg::FetchError(format!("Login failed: {}", e)),
+            },
+        );
+        let body = MfaLogin { mfa_token: self.mfa_token.clone()?, code: self.code.trim().to_string() };
+        let request = Request::post(api::login_mfa())
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        Some(self.fetch_service.fetch(request, callback))
+    }
+
+    /// The second step of logging in to accounts with two-factor
+    /// authentication
+    fn code_form(&self) -> Html<Self> {
+        html! {
+            <div class="login-form-container">
+                <div class="login-form-content">
+                    <div class="login-form-header">
+                        <h5>{ "Two-factor authentication" }</h5>
+                    </div>
+                    <div class="login-form">
+                        <form>
+                            <div class="form-group">
+                                <label for="inputCode">{ "Code" }</label>
+                                <input type="text" id="inputCode" class="form-control" placeholder="From your authenticator app" required="" autofocus=""
+                                autocomplete="one-time-code" value=&self.code oninput=|e| Msg::UpdateCode(e.value) />
+                                <small class="form-text text-muted">{ "Lost your device? Enter one of your recovery codes instead." }</small>
+                            </div>
+
+                            <div class="login-buttons">
+                                <button type="submit" class="btn btn-primary" onclick=|e| { e.prevent_default(); Msg::SubmitCode }>{ "Log in" }</button>
+                                { if self.loading { spinner() } else { html! {} } }
+
+                                <button type="button" class="btn btn-link" onclick=|_| Msg::CancelCode>{ "Start over" }</button>
+                            </div>
+
+                            { self.login_error() }
+                        </form>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn create_form(&self) -> Html<Self> {
+        html! {
+            <div class="login-form-container">
+                <div class="login-form-content">
+                    <div class="login-form-header">
+                        <h5>{ "Create account" }</h5>
+                    </div>
+                    <div class="login-form">
+                        <form>
+                            <div class="form-group">
+                                <label for="inputUsername">{ "Username" }</label>
+                                <input type="text" id="inputUsername" class="form-control" placeholder="Shown with your posts" required="" autofocus=""
+                                value=&self.username oninput=|e| Msg::UpdateUsername(e.value) />
+                            </div>
+
+                            <div class="form-group">
+                                <label for="inputEmail">{ "Email address" }</label>
+                                <input type="email" id="inputEmail" class="form-control" placeholder="Email address" required=""
+                                value=&self.email oninput=|e| Msg::UpdateEmail(e.value) />
+                            </div>
+
+                            <div class="form-group">
+                                <label for="inputPassword">{ "Password" }</label>
+                                <input type="password" id="inputPassword" class="form-control" placeholder="Password" required=""
+                                value=&self.password  oninput=|e| Msg::UpdatePassword(e.value) />
+                            </div>
+
+                            <div class="login-buttons">
+                                <button type="submit" class="btn btn-primary" onclick=|e| { e.prevent_default(); Msg::CreateAccount }>{ "Create account" }</button>
+                                { if self.loading { spinner() } else { html! {} } }
+
+                                <button type="button" class="btn btn-link" onclick=|_| Msg::ToggleCreating>{ "Log in instead" }</button>
+                            </div>
+
+                            { self.login_error() }
+                        </form>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn verify_email(&mut self, token: String) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::EmailVerified,
+                Err(e) => Msg::FetchError(format!("Could not verify the email address: {}", e)),
             },
-            Some(LoginError::CreateAccount) => html! {
-                <div class="login-error">{ "Account creation failed" }</div>
+        );
+        let body = VerifyEmail { token };
+        let request = Request::post(api::verify_email())
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn reset_form(&self) -> Html<Self> {
+        html! {
+            <div class="login-form-container">
+                <div class="login-form-content">
+                    <div class="login-form-header">
+                        <h5>{ "Choose a new password" }</h5>
+                    </div>
+                    <div class="login-form">
+                        <form>
+                            <div class="form-group">
+                                <label for="inputNewPassword">{ "New password" }</label>
+                                <input type="password" id="inputNewPassword" class="form-control" placeholder="New password" required="" autofocus=""
+                                value=&self.password oninput=|e| Msg::UpdatePassword(e.value) />
+                            </div>
+
+                            <div class="login-buttons">
+                                <button type="submit" class="btn btn-primary" onclick=|e| { e.prevent_default(); Msg::ResetPassword }>{ "Change password" }</button>
+                                { if self.loading { spinner() } else { html! {} } }
+                            </div>
+
+                            { self.login_error() }
+                        </form>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn request_reset(&mut self) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::ResetRequested,
+                Err(e) => Msg::FetchError(format!("Could not reset the password: {}", e)),
+            },
+        );
+        let body = RequestPasswordReset { email: self.email.trim().to_string() };
+        let request = Request::post(api::password_reset())
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn reset_password(&mut self) -> Option<FetchTask> {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::PasswordReset,
+                Err(e) => Msg::FetchError(format!("Could not change the password: {}", e)),
+            },
+        );
+        let body = ResetPassword { token: self.reset_token.clone()?, new_password: self.password.to_string() };
+        let request = Request::post(api::confirm_password_reset())
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        Some(self.fetch_service.fetch(request, callback))
+    }
+
+    fn login_notice(&self) -> Html<Self> {
+        match &self.notice {
+            Some(notice) => html! {
+                <div class="login-notice">{ notice }</div>
+            },
+            None => html! {}
+        }
+    }
+
+    fn login_error(&self) -> Html<Self> {
+        match &self.error {
+            Some(error) => html! {
+                <div class="login-error">{ error }</div>
             },
             None => html! {}
         }
@@ -1,6 +1,6 @@
 #![recursion_limit="1024"]
 
-#[cfg_attr(test, macro_use)]
+#[macro_use]
 extern crate stdweb;
 #[macro_use]
 extern crate log;
@@ -9,9 +9,11 @@ extern crate lazy_static;
 extern crate web_logger;
 
 mod api;
+mod event_source;
 mod login;
 mod router;
 mod forum;
+mod two_factor;
 
 pub fn main() {
     web_logger::init();
@@ -1,15 +1,33 @@
+use std::time::Duration;
 use yew::prelude::*;
+use yew::format::Text;
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+use yew::services::timeout::{TimeoutService, TimeoutTask};
+use types::{RefreshToken, Token};
+
+use crate::api;
 use crate::login::Login;
 use crate::forum::Forum;
+use crate::two_factor::TwoFactor;
 
 use yew::virtual_dom::VNode;
 use yew_router::{route::Route, service::RouteService, Switch};
 
+/// Refresh the access token this many seconds before it expires
+const REFRESH_MARGIN: u64 = 60;
 
 #[derive(Clone, Switch, Debug)]
 pub enum AppRoute {
+    /// The link of a password reset mail
+    #[to = "/#reset-password/{token}"]
+    ResetPassword(String),
+    /// The link of a verification mail
+    #[to = "/#verify-email/{token}"]
+    VerifyEmail(String),
     #[to = "/#forum"]
     Forum,
+    #[to = "/#two-factor"]
+    TwoFactor,
     #[to = "/"]
     Login,
 }
@@ -17,7 +35,15 @@ pub enum AppRoute {
 pub struct Model {
     route_service: RouteService<()>,
     route: Route<()>,
-    token: Option<String>,
+    token: Option<Token>,
+
+    fetch_service: FetchService,
+    timeout_service: TimeoutService,
+    ft: Option<FetchTask>,
+    refresh_task: Option<TimeoutTask>,
+    verification_task: Option<FetchTask>,
+    /// Outcome of sending the verification mail again
+    verification_notice: Option<String>,
 
     link: ComponentLink<Self>
 }
@@ -25,7 +51,13 @@ pub struct Model {
 pub enum Msg {
     RouteChanged(Route<()>),
     ChangeRoute(AppRoute),
-    Login(String),
+    Login(Token),
+    RefreshToken,
+    TokenRefreshed(Token),
+    Logout,
+    LoggedOut,
+    ResendVerification,
+    VerificationSent(String),
 }
 
 impl Component for Model {
@@ -48,6 +80,13 @@ impl Component for Model {
             route,
             link,
             token: None,
+
+            fetch_service: FetchService::new(),
+            timeout_service: TimeoutService::new(),
+            ft: None,
+            refresh_task: None,
+            verification_task: None,
+            verification_notice: None,
         }
     }
 
@@ -55,18 +94,50 @@ impl Component for Model {
         match msg {
             Msg::RouteChanged(route) => self.route = route,
             Msg::Login(token) => {
+                self.schedule_refresh(&token);
                 self.token = Some(token);
                 self.link.send_self(Msg::ChangeRoute(AppRoute::Forum));
             }
+            Msg::RefreshToken => {
+                self.ft = self.refresh_token();
+                return false;
+            }
+            Msg::TokenRefreshed(token) => {
+                self.schedule_refresh(&token);
+                self.token = Some(token);
+            }
+            Msg::Logout => {
+                self.ft = self.logout();
+                if self.ft.is_none() {
+                    self.link.send_self(Msg::LoggedOut);
+                }
+            }
+            Msg::ResendVerification => {
+                self.verification_task = self.resend_verification();
+                self.verification_notice = Some("Sending...".to_string());
+            }
+            Msg::VerificationSent(notice) => {
+                self.verification_task = None;
+                self.verification_notice = Some(notice);
+            }
+            Msg::LoggedOut => {
+                self.token = None;
+                self.refresh_task = None;
+                self.verification_notice = None;
+                self.link.send_self(Msg::ChangeRoute(AppRoute::Login));
+            }
             Msg::ChangeRoute(route) => {
                 // This might be derived in the future
                 let route_string = match route {
-                    AppRoute::Login => "/",
-                    AppRoute::Forum => "/#forum"
+                    AppRoute::Login => "/".to_string(),
+                    AppRoute::Forum => "/#forum".to_string(),
+                    AppRoute::TwoFactor => "/#two-factor".to_string(),
+                    AppRoute::ResetPassword(token) => format!("/#reset-password/{}", token),
+                    AppRoute::VerifyEmail(token) => format!("/#verify-email/{}", token),
                 };
                 self.route_service.set_route(&route_string, ());
                 self.route = Route {
-                    route: route_string.to_string(),
+                    route: route_string,
                     state: None,
                 };
             }
@@ -79,16 +150,104 @@ impl Renderable<Model> for Model {
     fn view(&self) -> VNode<Self> {
         html! {
             match (AppRoute::switch(self.route.clone()), &self.token) {
+                (Some(AppRoute::ResetPassword(reset_token)), _) =>
+                    html!{<Login onlogin=|token| Msg::Login(token) reset_token=Some(reset_token)/>},
+                (Some(AppRoute::VerifyEmail(verify_token)), _) =>
+                    html!{<Login onlogin=|token| Msg::Login(token) verify_token=Some(verify_token)/>},
                 (Some(AppRoute::Login), _) | (_, None) => html!{<Login onlogin=|token| Msg::Login(token)/>},
-                (Some(AppRoute::Forum), Some(token)) => {
-                    if let Some(token) = &self.token {
-                        html!{<Forum token=token.to_string()/>}
-                    } else {
-                        unreachable!()
-                    }
-                }
+                (Some(AppRoute::Forum), Some(token)) => html!{
+                    <div class="forum-container">
+                        <div class="app-header">
+                            <button class="btn btn-link" onclick=|_| Msg::ChangeRoute(AppRoute::TwoFactor)>
+                                { "Two-factor authentication" }
+                            </button>
+                            <button class="btn btn-link" onclick=|_| Msg::Logout>{ "Log out" }</button>
+                        </div>
+                        { self.verify_banner(token) }
+                        <Forum token=token.token.to_string() account_id=token.account_id role=token.role/>
+                    </div>
+                },
+                (Some(AppRoute::TwoFactor), Some(token)) => html!{
+                    <div class="forum-container">
+                        <div class="app-header">
+                            <button class="btn btn-link" onclick=|_| Msg::ChangeRoute(AppRoute::Forum)>
+                                { "Back to the forum" }
+                            </button>
+                            <button class="btn btn-link" onclick=|_| Msg::Logout>{ "Log out" }</button>
+                        </div>
+                        <TwoFactor token=token.token.to_string()/>
+                    </div>
+                },
                 (None, _) => html!{"404"}
             }
         }
     }
 }
+
+impl Model {
+    /// Asks accounts to verify their address, which they need to post
+    fn verify_banner(&self, token: &Token) -> Html<Self> {
+        if token.email_verified {
+            return html! {};
+        }
+        html! {
+            <div class="verify-banner">
+                <span>{ "Follow the link mailed to you to verify your email address, then you can post." }</span>
+                {
+                    match &self.verification_notice {
+                        Some(notice) => html! { <span>{ notice }</span> },
+                        None => html! {
+                            <button class="btn btn-link" onclick=|_| Msg::ResendVerification>{ "Send it again" }</button>
+                        },
+                    }
+                }
+            </div>
+        }
+    }
+
+    fn schedule_refresh(&mut self, token: &Token) {
+        let delay = token.expires_in.saturating_sub(REFRESH_MARGIN).max(1);
+        let callback = self.link.send_back(|_| Msg::RefreshToken);
+        self.refresh_task = Some(self.timeout_service.spawn(Duration::from_secs(delay), callback));
+    }
+
+    fn refresh_token(&mut self) -> Option<FetchTask> {
+        let refresh_token = self.token.as_ref()?.refresh_token.to_string();
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse::<Token>(response) {
+                Ok(token) => Msg::TokenRefreshed(token),
+                // The session is gone (expired or revoked), back to the login screen
+                Err(_) => Msg::LoggedOut,
+            },
+        );
+        let body = RefreshToken { refresh_token };
+        let request = Request::post(api::refresh_token())
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        Some(self.fetch_service.fetch(request, callback))
+    }
+
+    fn resend_verification(&mut self) -> Option<FetchTask> {
+        let token = self.token.as_ref()?;
+        let callback = self.link.send_back(|response: Response<Text>| match api::check(response) {
+            Ok(()) => Msg::VerificationSent("Sent, check your mail".to_string()),
+            Err(e) => Msg::VerificationSent(format!("Could not send it: {}", e)),
+        });
+        let request = Request::post(api::resend_verification())
+            .header("Authorization", format!("Bearer {}", token.token))
+            .body(Ok("".to_string()))
+            .unwrap();
+        Some(self.fetch_service.fetch(request, callback))
+    }
+
+    fn logout(&mut self) -> Option<FetchTask> {
+        let token = self.token.as_ref()?;
+        let callback = self.link.send_back(|_: Response<Text>| Msg::LoggedOut);
+        let body = RefreshToken { refresh_token: token.refresh_token.to_string() };
+        let request = Request::post(api::logout())
+            .header("Authorization", format!("Bearer {}", token.token))
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        Some(self.fetch_service.fetch(request, callback))
+    }
+}
@@ -0,0 +1,308 @@
+use yew::prelude::*;
+use yew::format::{Nothing, Text};
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+use stdweb::traits::IEvent;
+
+use types::{DisableTotp, RecoveryCodes, TotpCode, TotpEnrollment, TotpStatus};
+
+use crate::api;
+
+/// Turning two-factor authentication of the signed in account on and off
+pub struct TwoFactor {
+    token: String,
+    status: Option<TotpStatus>,
+    /// A secret waiting for its first code
+    enrollment: Option<TotpEnrollment>,
+    /// Recovery codes, shown once right after they were made
+    recovery_codes: Option<Vec<String>>,
+    code: String,
+    password: String,
+    error: Option<String>,
+    loading: bool,
+
+    fetch_service: FetchService,
+    link: ComponentLink<TwoFactor>,
+    ft: Option<FetchTask>,
+}
+
+pub enum Msg {
+    FetchStatus,
+    StatusFetched(TotpStatus),
+    UpdateCode(String),
+    UpdatePassword(String),
+    Enroll,
+    Enrolled(TotpEnrollment),
+    Confirm,
+    NewRecoveryCodes,
+    RecoveryCodes(RecoveryCodes),
+    Disable,
+    FetchError(String),
+}
+
+#[derive(PartialEq, Properties)]
+pub struct Props {
+    #[props(required)]
+    pub token: String,
+}
+
+impl Component for TwoFactor {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let mut two_factor = TwoFactor {
+            token: props.token,
+            status: None,
+            enrollment: None,
+            recovery_codes: None,
+            code: "".to_string(),
+            password: "".to_string(),
+            error: None,
+            loading: true,
+
+            fetch_service: FetchService::new(),
+            link,
+            ft: None,
+        };
+        two_factor.ft = Some(two_factor.fetch_status());
+        two_factor
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::FetchStatus => {
+                self.recovery_codes = None;
+                self.ft = Some(self.fetch_status());
+                self.loading = true;
+            }
+            Msg::StatusFetched(status) => {
+                self.loading = false;
+                self.status = Some(status);
+            }
+            Msg::UpdateCode(code) => self.code = code,
+            Msg::UpdatePassword(pw) => self.password = pw,
+            Msg::Enroll => {
+                self.error = None;
+                self.ft = Some(self.enroll());
+                self.loading = true;
+            }
+            Msg::Enrolled(enrollment) => {
+                self.loading = false;
+                self.code = "".to_string();
+                self.enrollment = Some(enrollment);
+            }
+            Msg::Confirm => {
+                self.error = None;
+                self.ft = Some(self.send_code(api::confirm_totp()));
+                self.loading = true;
+            }
+            Msg::NewRecoveryCodes => {
+                self.error = None;
+                self.ft = Some(self.send_code(api::recovery_codes()));
+                self.loading = true;
+            }
+            Msg::RecoveryCodes(codes) => {
+                self.loading = false;
+                self.enrollment = None;
+                self.code = "".to_string();
+                self.recovery_codes = Some(codes.codes);
+            }
+            Msg::Disable => {
+                self.error = None;
+                self.ft = Some(self.disable());
+                self.loading = true;
+            }
+            Msg::FetchError(error) => {
+                self.loading = false;
+                self.error = Some(error);
+            }
+        }
+        true
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        // A refreshed access token for the same account
+        self.token = props.token;
+        false
+    }
+}
+
+fn spinner() -> Html<TwoFactor> {
+    html! {
+        <div class="spinner-border spinner-border-sm" role="status">
+            <span class="sr-only">{ "Loading..." }</span>
+        </div>
+    }
+}
+
+impl Renderable<TwoFactor> for TwoFactor {
+    fn view(&self) -> Html<Self> {
+        let content = if let Some(codes) = &self.recovery_codes {
+            self.recovery_codes_view(codes)
+        } else if let Some(enrollment) = &self.enrollment {
+            self.enrollment_view(enrollment)
+        } else {
+            match &self.status {
+                Some(status) if status.enabled => self.enabled_view(status),
+                Some(_) => html! {
+                    <div>
+                        <p>{ "Two-factor authentication is off. Turn it on to be asked for a code from an \
+                              authenticator app whenever you log in." }</p>
+                        <div class="login-buttons">
+                            <button type="button" class="btn btn-primary" onclick=|_| Msg::Enroll>{ "Turn on" }</button>
+                            { if self.loading { spinner() } else { html! {} } }
+                        </div>
+                    </div>
+                },
+                None => if self.loading { spinner() } else { html! {} },
+            }
+        };
+        html! {
+            <div class="login-form-container">
+                <div class="login-form-content">
+                    <div class="login-form-header">
+                        <h5>{ "Two-factor authentication" }</h5>
+                    </div>
+                    <div class="login-form two-factor">
+                        { content }
+                        { self.error() }
+                    </div>
+                </div>
+            </div>
+        }
+    }
+}
+
+impl TwoFactor {
+    fn fetch_status(&mut self) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(status) => Msg::StatusFetched(status),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let request = Request::get(api::totp())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Nothing)
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn enroll(&mut self) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(enrollment) => Msg::Enrolled(enrollment),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let request = Request::post(api::totp())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Nothing)
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    /// Confirming the enrollment and replacing the recovery codes both take
+    /// a code and return new recovery codes
+    fn send_code(&mut self, url: String) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::parse(response) {
+                Ok(codes) => Msg::RecoveryCodes(codes),
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let body = TotpCode { code: self.code.trim().to_string() };
+        let request = Request::post(url)
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn disable(&mut self) -> FetchTask {
+        let callback = self.link.send_back(
+            move |response: Response<Text>| match api::check(response) {
+                Ok(()) => Msg::FetchStatus,
+                Err(e) => Msg::FetchError(e),
+            },
+        );
+        let body = DisableTotp { password: self.password.to_string(), code: self.code.trim().to_string() };
+        self.password = "".to_string();
+        self.code = "".to_string();
+        let request = Request::post(api::disable_totp())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .body(Ok(serde_json::to_string(&body).unwrap()))
+            .unwrap();
+        self.fetch_service.fetch(request, callback)
+    }
+
+    fn code_input(&self) -> Html<Self> {
+        html! {
+            <div class="form-group">
+                <label for="inputCode">{ "Code" }</label>
+                <input type="text" id="inputCode" class="form-control" placeholder="From your authenticator app"
+                autocomplete="one-time-code" value=&self.code oninput=|e| Msg::UpdateCode(e.value) />
+            </div>
+        }
+    }
+
+    fn enrollment_view(&self, enrollment: &TotpEnrollment) -> Html<Self> {
+        html! {
+            <form>
+                <p>{ "Scan the code with your authenticator app, or enter the key by hand, then enter the code the \
+                      app shows." }</p>
+                <img class="qr-code" src=&enrollment.qr_code alt="QR code" />
+                <p class="totp-secret"><code>{ &enrollment.secret }</code></p>
+                { self.code_input() }
+                <div class="login-buttons">
+                    <button type="submit" class="btn btn-primary" onclick=|e| { e.prevent_default(); Msg::Confirm }>{ "Turn on" }</button>
+                    { if self.loading { spinner() } else { html! {} } }
+                </div>
+            </form>
+        }
+    }
+
+    fn recovery_codes_view(&self, codes: &[String]) -> Html<Self> {
+        html! {
+            <div>
+                <p>{ "Keep these recovery codes somewhere safe. Each of them logs you in once in place of a code \
+                      from the app, and they won't be shown again." }</p>
+                <ul class="recovery-codes">
+                    { for codes.iter().map(|code| html! { <li><code>{ code }</code></li> }) }
+                </ul>
+                <div class="login-buttons">
+                    <button type="button" class="btn btn-primary" onclick=|_| Msg::FetchStatus>{ "Done" }</button>
+                </div>
+            </div>
+        }
+    }
+
+    fn enabled_view(&self, status: &TotpStatus) -> Html<Self> {
+        html! {
+            <form>
+                <p>{ format!("Two-factor authentication is on. You have {} recovery codes left.",
+                             status.recovery_codes_left) }</p>
+                { self.code_input() }
+                <div class="form-group">
+                    <label for="inputPassword">{ "Password" }</label>
+                    <input type="password" id="inputPassword" class="form-control" placeholder="Only needed to turn it off"
+                    value=&self.password oninput=|e| Msg::UpdatePassword(e.value) />
+                </div>
+                <div class="login-buttons">
+                    <button type="button" class="btn btn-primary" onclick=|_| Msg::NewRecoveryCodes>{ "New recovery codes" }</button>
+                    <button type="submit" class="btn btn-danger" onclick=|e| { e.prevent_default(); Msg::Disable }>{ "Turn off" }</button>
+                    { if self.loading { spinner() } else { html! {} } }
+                </div>
+            </form>
+        }
+    }
+
+    fn error(&self) -> Html<Self> {
+        match &self.error {
+            Some(error) => html! {
+                <div class="login-error">{ error }</div>
+            },
+            None => html! {}
+        }
+    }
+}
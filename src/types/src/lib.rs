@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[cfg(not(cargo_web))]
 #[macro_use]
@@ -8,26 +10,181 @@ extern crate gotham_derive;
 #[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
 pub struct Token {
     pub token: String,
+    /// Id of the account the token was issued to
+    pub account_id: i32,
+    pub role: Role,
+    pub refresh_token: String,
+    /// Seconds until `token` expires
+    pub expires_in: u64,
+    /// Whether the account has followed the link mailed to its address
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
+pub struct RefreshToken {
+    pub refresh_token: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
 pub struct CreateAccount {
+    /// Shown with everything the account posts
     pub username: String,
+    /// Logged in with, and sent a verification link
+    pub email: String,
     pub password: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Mail a password reset link to the account, if there is one
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RequestPasswordReset {
+    #[serde(alias = "username")]
+    pub email: String,
+}
+
+/// Follow the link of a verification mail
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ResetPassword {
+    /// From the reset link
+    pub token: String,
+    pub new_password: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Account {
     pub id: i32,
     pub username: String,
+    pub role: Role,
+    pub banned: bool,
+}
+
+/// Roles are ordered, each one having the permissions of the ones before it
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    /// May lock threads, ban users and edit or delete anything
+    Moderator,
+    /// May additionally change the roles of other accounts
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        [Role::User, Role::Moderator, Role::Admin].iter().cloned().find(|role| role.as_str() == s)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
+pub struct SetRole {
+    pub role: Role,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
+pub struct SetBanned {
+    pub banned: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
+pub struct SetLocked {
+    pub locked: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
 pub struct Login {
-    pub username: String,
+    /// The email address, or the username of accounts from before they
+    /// had separate addresses
+    #[serde(alias = "username")]
+    pub email: String,
+    pub password: String,
+}
+
+/// What `POST /login` answers with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LoginResult {
+    Token(Token),
+    /// The account has two-factor authentication, exchange the challenge
+    /// for a token with `POST /login/mfa`
+    MfaRequired(MfaChallenge),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MfaChallenge {
+    pub mfa_token: String,
+    /// Seconds left to answer the challenge
+    pub expires_in: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MfaLogin {
+    pub mfa_token: String,
+    /// From the authenticator app, or one of the recovery codes
+    pub code: String,
+}
+
+/// Two-factor authentication of the signed in account
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+}
+
+/// A new TOTP secret, enabled by confirming a code of it with
+/// `POST /account/totp/confirm`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TotpEnrollment {
+    /// Base32, for typing into the authenticator app
+    pub secret: String,
+    /// The `otpauth://` provisioning URI
+    pub uri: String,
+    /// `uri` as a QR code, in a PNG `data:` URI
+    pub qr_code: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DisableTotp {
     pub password: String,
+    /// From the authenticator app, or one of the recovery codes
+    pub code: String,
+}
+
+/// Single-use codes that stand in for the authenticator app. They are only
+/// ever shown this once.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -40,21 +197,246 @@ pub struct CreateThread {
 pub struct Thread {
     pub id: i32,
     pub creator: String,
+    pub creator_id: i32,
     pub title: String,
-    pub messages: Option<Vec<Message>>,
+    pub created_at: DateTime<Utc>,
+    /// Time of the latest activity in the thread
+    pub updated_at: DateTime<Utc>,
+    pub message_count: i64,
+    /// Locked threads only accept messages and edits from moderators
+    pub locked: bool,
+    pub messages: Option<Page<Message>>,
     pub latest_message: Option<Message>,
 }
 
+/// Order of the thread listing
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadSort {
+    /// Most recently active first
+    RecentActivity,
+    /// Newest first
+    Created,
+    /// Alphabetically by title
+    Title,
+}
+
+impl Default for ThreadSort {
+    fn default() -> Self {
+        ThreadSort::RecentActivity
+    }
+}
+
+impl ThreadSort {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThreadSort::RecentActivity => "recent_activity",
+            ThreadSort::Created => "created",
+            ThreadSort::Title => "title",
+        }
+    }
+}
+
+/// One page of a keyset paginated list. The cursors are opaque and only
+/// present if there are more items in that direction; pass them back as
+/// `after=<next>` or `before=<prev>`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
 pub struct CreateMessage {
     pub content: String,
+    /// The message this one replies to, in the same thread
+    #[serde(default)]
+    pub parent_id: Option<i32>,
+    /// Uploads of the author to attach, content may be empty if there are any
+    #[serde(default)]
+    pub attachment_ids: Vec<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
+pub struct UpdateThread {
+    pub title: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(cargo_web), derive(StateData, StaticResponseExtender))]
+pub struct UpdateMessage {
+    pub content: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Message {
     pub id: i32,
     pub creator: String,
+    pub creator_id: i32,
+    /// Markdown source
     pub content: String,
+    /// `content` rendered to sanitized HTML
+    pub content_html: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Time of the latest edit, if the message has been edited
+    pub edited_at: Option<DateTime<Utc>>,
+    /// The message this one replies to
+    pub parent_id: Option<i32>,
+    /// How many replies deep the message is, 0 if it isn't a reply
+    pub depth: i32,
+    pub attachments: Vec<Attachment>,
 }
 
+/// A file uploaded with `POST /attachment`, downloaded from
+/// `GET /attachment/:id`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Attachment {
+    pub id: i32,
+    pub filename: String,
+    /// Detected from the content, not taken from the upload
+    pub content_type: String,
+    pub size: i32,
+    /// Whether there's a preview image at `GET /attachment/:id/thumbnail`
+    pub thumbnail: bool,
+}
+
+/// Message content rendered with `POST /markdown/preview`, which takes an
+/// `UpdateMessage`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessagePreview {
+    pub content_html: String,
+}
+
+/// A prior version of an edited message
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageRevision {
+    pub content: String,
+    /// When this version was written
+    pub created_at: DateTime<Utc>,
+    /// When this version was replaced by an edit, and by whom
+    pub replaced_at: DateTime<Utc>,
+    pub replaced_by: String,
+}
+
+/// A thread title or message matching a search
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub thread_id: i32,
+    pub thread_title: String,
+    /// `None` when the thread title itself matched
+    pub message_id: Option<i32>,
+    pub creator: String,
+    pub created_at: DateTime<Utc>,
+    pub rank: f32,
+    /// An excerpt of the matching text, split around the matched words
+    pub snippet: Vec<SnippetPart>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlighted: bool,
+}
+
+
+/// A change to the forum, pushed to clients as it happens. Thread events are
+/// sent to every client, message events only to those watching the thread.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ThreadCreated { thread: Thread },
+    /// The thread itself changed, or it has new activity
+    ThreadUpdated { thread: Thread },
+    ThreadDeleted { thread_id: i32 },
+    MessageCreated { thread_id: i32, message: Message },
+    MessageUpdated { thread_id: i32, message: Message },
+    MessageDeleted { thread_id: i32, message_id: i32 },
+}
+
+impl Event {
+    pub fn thread_id(&self) -> i32 {
+        match self {
+            Event::ThreadCreated { thread } | Event::ThreadUpdated { thread } => thread.id,
+            Event::ThreadDeleted { thread_id }
+            | Event::MessageCreated { thread_id, .. }
+            | Event::MessageUpdated { thread_id, .. }
+            | Event::MessageDeleted { thread_id, .. } => *thread_id,
+        }
+    }
+
+    pub fn is_message_event(&self) -> bool {
+        match self {
+            Event::MessageCreated { .. } | Event::MessageUpdated { .. } | Event::MessageDeleted { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Sent by WebSocket clients to choose the threads they get message events for
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Subscribe { thread_id: i32 },
+    Unsubscribe { thread_id: i32 },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    ValidationFailed,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    Internal,
+    Unavailable,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    TooManyRequests,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// The body of every non-2xx response from the backend
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Seconds to wait before trying again, also sent as `Retry-After`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError { code, message: message.into(), details: Vec::new(), request_id: None, retry_after: None }
+    }
+
+    pub fn with_detail(mut self, field: impl Into<String>, message: impl Into<String>) -> Self {
+        self.details.push(FieldError { field: field.into(), message: message.into() });
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for detail in &self.details {
+            write!(f, "; {}: {}", detail.field, detail.message)?;
+        }
+        Ok(())
+    }
+}